@@ -0,0 +1,8 @@
+#![no_main]
+
+use clipper::network::Package;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Package::from_bytes(data);
+});
@@ -0,0 +1,570 @@
+//! Sending large files in pieces. A file item bigger than [`CHUNK_LEN`] goes out as a series of
+//! [`Package::FileChunk`], each its own frame, so neither end handles one huge frame. The
+//! chunks are sent back to back, other packages on the same connection wait for the last one.
+//!
+//! The chunks carry the bincode encoding of the whole item package, which the receiver decodes
+//! once every chunk arrived, so the item keeps its time, source app and label.
+//!
+//! A transfer is named by a hash of that encoding, the same every time the package is sent. A
+//! receiver keeping chunks in `.part` files, see [`Reassembler::with_part_dir`], can then ask
+//! for the rest of a transfer a dropped connection cut short with a [`Package::Resume`], which
+//! the sender answers with [`send_chunked_from`].
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::item::TransferableItem;
+use crate::network::{
+    check_frame_len, send_package, try_reserve_memory, Encoding, NetworkError, Package,
+    Reservation, SharedKey, TransferStats, MAX_PACKAGE_LEN,
+};
+
+/// Bytes carried by each chunk
+pub const CHUNK_LEN: usize = 1024 * 1024;
+
+/// How long a partly received file is kept waiting for its missing chunks.
+pub const CHUNK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Files received at the same time on one connection, unless changed with
+/// [`Reassembler::with_limits`]
+pub const MAX_PENDING_FILES: usize = 4;
+
+/// The chunks of a package, made one at a time as they are sent.
+pub struct Chunks {
+    /// Transfer id, see [`transfer_id`]
+    id: u64,
+    encoded: Vec<u8>,
+    next: u32,
+    total: u32,
+}
+
+impl Iterator for Chunks {
+    type Item = Package;
+
+    fn next(&mut self) -> Option<Package> {
+        if self.next == self.total {
+            return None;
+        }
+        let start = self.next as usize * CHUNK_LEN;
+        let end = (start + CHUNK_LEN).min(self.encoded.len());
+        let chunk = Package::FileChunk {
+            id: self.id,
+            seq: self.next,
+            total: self.total,
+            bytes: self.encoded[start..end].to_vec(),
+        };
+        self.next += 1;
+        Some(chunk)
+    }
+}
+
+impl Chunks {
+    /// Transfer id the chunks are sent under.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Skips the chunks before the one holding byte `offset` of the encoded package.
+    fn skip_to(&mut self, offset: u64) {
+        self.next = (offset / CHUNK_LEN as u64).min(self.total as u64) as u32;
+    }
+}
+
+/// Names the transfer of the package encoded as `encoded`, the same whenever it is sent.
+fn transfer_id(encoded: &[u8]) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    encoded.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The chunks to send instead of `package`, `None` unless it is a file larger than
+/// [`CHUNK_LEN`].
+pub fn split(package: &Package) -> Result<Option<Chunks>, NetworkError> {
+    let Package::Item {
+        item: TransferableItem::File { data, .. },
+        ..
+    } = package
+    else {
+        return Ok(None);
+    };
+    if data.len() <= CHUNK_LEN {
+        return Ok(None);
+    }
+    let encoded = bincode::serialize(package).map_err(NetworkError::Serialize)?;
+    Ok(Some(Chunks {
+        id: transfer_id(&encoded),
+        total: encoded.len().div_ceil(CHUNK_LEN) as u32,
+        encoded,
+        next: 0,
+    }))
+}
+
+/// Sends `package` like [`send_package`], in chunks when [`split`] says so. Returns what sending
+/// each frame took.
+pub fn send_chunked<W: Write>(
+    package: &Package,
+    stream: &mut W,
+    shared_key: &Option<SharedKey>,
+    encoding: Encoding,
+) -> Result<Vec<TransferStats>, NetworkError> {
+    send_chunked_from(package, 0, stream, shared_key, encoding)
+}
+
+/// Like [`send_chunked`], but leaves out the chunks before byte `offset` for a receiver that
+/// has them, see [`Package::Resume`]. Packages that aren't chunked are sent whole.
+pub fn send_chunked_from<W: Write>(
+    package: &Package,
+    offset: u64,
+    stream: &mut W,
+    shared_key: &Option<SharedKey>,
+    encoding: Encoding,
+) -> Result<Vec<TransferStats>, NetworkError> {
+    match split(package)? {
+        Some(mut chunks) => {
+            chunks.skip_to(offset);
+            debug!(
+                "Sending {} in {} chunks, from chunk {}",
+                package.redacted(),
+                chunks.total,
+                chunks.next
+            );
+            chunks
+                .map(|chunk| send_package(&chunk, stream, shared_key, encoding))
+                .collect()
+        }
+        None => Ok(vec![send_package(package, stream, shared_key, encoding)?]),
+    }
+}
+
+/// A file whose chunks are still arriving.
+struct Partial {
+    total: u32,
+    /// Chunks received so far, by `seq`
+    chunks: BTreeMap<u32, Vec<u8>>,
+    started: Instant,
+    /// Memory budget taken by the chunks, given back with them
+    reserved: Vec<Reservation<'static>>,
+}
+
+impl Partial {
+    fn len(&self) -> usize {
+        self.chunks.values().map(Vec::len).sum()
+    }
+}
+
+/// Puts chunked packages back together, for one connection.
+pub struct Reassembler {
+    partial: HashMap<u64, Partial>,
+    timeout: Duration,
+    max_files: usize,
+    /// Chunk bytes held over all files at once
+    max_buffered: usize,
+    /// Where chunks are kept instead of in memory, see [`Reassembler::with_part_dir`]
+    part_dir: Option<PathBuf>,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self {
+            partial: HashMap::new(),
+            timeout: CHUNK_TIMEOUT,
+            max_files: MAX_PENDING_FILES,
+            max_buffered: MAX_PACKAGE_LEN as usize,
+            part_dir: None,
+        }
+    }
+}
+
+impl Reassembler {
+    /// Gives up on files whose chunks stop arriving for `timeout` instead of [`CHUNK_TIMEOUT`].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Holds chunks of at most `max_files` files and `max_buffered` bytes at once, instead of
+    /// [`MAX_PENDING_FILES`] and [`MAX_PACKAGE_LEN`]. Chunks beyond either are dropped.
+    pub fn with_limits(mut self, max_files: usize, max_buffered: usize) -> Self {
+        self.max_files = max_files;
+        self.max_buffered = max_buffered;
+        self
+    }
+
+    /// Keeps the chunks in a `.part` file per transfer in `dir` instead of in memory, so a
+    /// transfer a dropped connection cut short can be resumed, see [`resume_requests`].
+    ///
+    /// Chunks then have to arrive in order, as [`send_chunked`] sends them, and only one
+    /// transfer goes on at a time: starting one deletes the parts of any other.
+    pub fn with_part_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.part_dir = Some(dir.into());
+        self
+    }
+
+    /// Takes in a received package. Chunks are kept until the last one of their file arrives,
+    /// in any order, which returns the whole package. Other packages are returned unchanged.
+    ///
+    /// The chunks held count against the memory budget, if one is set. A chunk that doesn't fit
+    /// in it or in the limits is dropped, and with it the rest of its file.
+    pub fn push(&mut self, package: Package) -> Option<Package> {
+        self.discard_expired();
+        let Package::FileChunk {
+            id,
+            seq,
+            total,
+            bytes,
+        } = package
+        else {
+            return Some(package);
+        };
+        if seq >= total
+            || bytes.len() > CHUNK_LEN
+            || check_frame_len(total as u64 * CHUNK_LEN as u64).is_err()
+        {
+            warn!("Dropping chunk {seq} of {total}");
+            return None;
+        }
+        if let Some(dir) = &self.part_dir {
+            return push_to_part(dir, id, seq, total, &bytes);
+        }
+        if !self.partial.contains_key(&id) && self.partial.len() >= self.max_files {
+            warn!(
+                "Dropping file {id}, already receiving {} files",
+                self.partial.len()
+            );
+            return None;
+        }
+        let buffered = self.buffered();
+        let partial = self.partial.entry(id).or_insert_with(|| Partial {
+            total,
+            chunks: BTreeMap::new(),
+            started: Instant::now(),
+            reserved: Vec::new(),
+        });
+        if partial.total != total {
+            warn!(
+                "Chunk {seq} doesn't match the other {} chunks",
+                partial.total
+            );
+            return None;
+        }
+        if partial.chunks.contains_key(&seq) {
+            debug!("Ignoring chunk {seq} of file {id}, which arrived already");
+            return None;
+        }
+        let reserved = if buffered + bytes.len() > self.max_buffered {
+            Err(format!("{buffered} bytes of chunks are held already"))
+        } else {
+            try_reserve_memory(bytes.len()).map_err(|err| err.to_string())
+        };
+        match reserved {
+            Ok(reservation) => partial.reserved.extend(reservation),
+            Err(err) => {
+                warn!("Dropping file {id}: {err}");
+                self.partial.remove(&id);
+                return None;
+            }
+        }
+        partial.chunks.insert(seq, bytes);
+        if partial.chunks.len() < total as usize {
+            return None;
+        }
+
+        let partial = self.partial.remove(&id)?;
+        let encoded: Vec<u8> = partial.chunks.into_values().flatten().collect();
+        match Package::from_bytes(&encoded) {
+            Ok(package) => Some(package),
+            Err(err) => {
+                warn!("Dropping chunked package: {err}");
+                None
+            }
+        }
+    }
+
+    /// Files still waiting for chunks.
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+
+    /// Bytes of the chunks held.
+    fn buffered(&self) -> usize {
+        self.partial.values().map(Partial::len).sum()
+    }
+
+    fn discard_expired(&mut self) {
+        let timeout = self.timeout;
+        self.partial.retain(|id, partial| {
+            let expired = partial.started.elapsed() >= timeout;
+            if expired {
+                warn!(
+                    "Discarding file {id}, {} of {} chunks arrived",
+                    partial.chunks.len(),
+                    partial.total
+                );
+            }
+            !expired
+        });
+    }
+}
+
+/// Path of the `.part` file of transfer `id` in `dir`.
+fn part_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("clipper-{id:016x}.part"))
+}
+
+/// Transfer id of a `.part` file called `name`.
+fn part_id(name: &str) -> Option<u64> {
+    let hex = name.strip_prefix("clipper-")?.strip_suffix(".part")?;
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// Bytes of whole chunks in the `.part` file at `path`. Only the last chunk is shorter and it
+/// completes the transfer, so anything past the last whole chunk was torn by a crash and is
+/// cut off.
+fn held_len(path: &Path) -> std::io::Result<u64> {
+    let len = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let held = len - len % CHUNK_LEN as u64;
+    if held != len {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(held)?;
+    }
+    Ok(held)
+}
+
+/// Appends chunk `seq` of transfer `id` to its `.part` file in `dir`, and decodes the package
+/// once the last chunk is there.
+fn push_to_part(dir: &Path, id: u64, seq: u32, total: u32, bytes: &[u8]) -> Option<Package> {
+    let path = part_path(dir, id);
+    let offset = seq as u64 * CHUNK_LEN as u64;
+    let held = match held_len(&path) {
+        Ok(held) => held,
+        Err(err) => {
+            warn!("Dropping file {id}: {err}");
+            return None;
+        }
+    };
+    if offset < held {
+        debug!("Ignoring chunk {seq} of file {id}, which arrived already");
+        return None;
+    }
+    if offset > held {
+        warn!("Dropping chunk {seq} of file {id}, only {held} bytes of it arrived");
+        return None;
+    }
+    if offset == 0 {
+        discard_parts(dir, id);
+    }
+    let appended = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(bytes));
+    if let Err(err) = appended {
+        warn!("Dropping file {id}: {err}");
+        let _ = std::fs::remove_file(&path);
+        return None;
+    }
+    if seq + 1 < total {
+        return None;
+    }
+
+    let encoded = std::fs::read(&path);
+    let _ = std::fs::remove_file(&path);
+    match encoded
+        .map_err(NetworkError::from)
+        .and_then(|encoded| Package::from_bytes(&encoded))
+    {
+        Ok(package) => Some(package),
+        Err(err) => {
+            warn!("Dropping chunked package: {err}");
+            None
+        }
+    }
+}
+
+/// Deletes the `.part` files in `dir` of every transfer but `id`.
+fn discard_parts(dir: &Path, id: u64) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        match name.to_str().and_then(part_id) {
+            Some(other) if other != id => {
+                debug!("Discarding the part of file {other}");
+                let _ = std::fs::remove_file(entry.path());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A [`Package::Resume`] for every transfer with a `.part` file in `dir`, asking the sender for
+/// the rest after a reconnect.
+pub fn resume_requests(dir: &Path) -> std::io::Result<Vec<Package>> {
+    let mut requests = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let Some(id) = entry.file_name().to_str().and_then(part_id) else {
+            continue;
+        };
+        let offset = held_len(&entry.path())?;
+        requests.push(Package::Resume { id, offset });
+    }
+    Ok(requests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_package(len: usize) -> Package {
+        let bytes = (0..len).map(|i| i as u8).collect();
+        Package::from(TransferableItem::file("big.bin", bytes)).with_label(Some("work".into()))
+    }
+
+    #[test]
+    fn small_file_is_not_split_test() {
+        assert!(split(&file_package(CHUNK_LEN)).unwrap().is_none());
+        assert!(split(&Package::text("clip".to_string())).unwrap().is_none());
+    }
+
+    #[test]
+    fn reassemble_out_of_order_test() {
+        let package = file_package(2 * CHUNK_LEN + 1);
+        let mut chunks: Vec<Package> = split(&package).unwrap().unwrap().collect();
+        assert_eq!(chunks.len(), 3);
+
+        let mut reassembler = Reassembler::default();
+        let middle = chunks.remove(1);
+        for chunk in chunks {
+            assert_eq!(reassembler.push(chunk), None);
+        }
+        assert_eq!(
+            reassembler.push(Package::Heartbeat),
+            Some(Package::Heartbeat)
+        );
+        assert_eq!(reassembler.push(middle), Some(package));
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn missing_chunk_test() {
+        let package = file_package(2 * CHUNK_LEN + 1);
+        let mut chunks: Vec<Package> = split(&package).unwrap().unwrap().collect();
+        let last = chunks.pop().unwrap();
+
+        let mut reassembler = Reassembler::default().with_timeout(Duration::ZERO);
+        for chunk in chunks {
+            assert_eq!(reassembler.push(chunk), None);
+        }
+        // The earlier chunks expired, so the final one alone isn't enough
+        assert_eq!(reassembler.push(last), None);
+        assert_eq!(reassembler.pending(), 1);
+    }
+
+    #[test]
+    fn bad_chunk_test() {
+        let mut reassembler = Reassembler::default();
+        let chunk = |seq, total| Package::FileChunk {
+            id: 1,
+            seq,
+            total,
+            bytes: vec![1],
+        };
+        assert_eq!(reassembler.push(chunk(2, 2)), None);
+        assert_eq!(reassembler.push(chunk(0, u32::MAX)), None);
+        assert_eq!(reassembler.pending(), 0);
+        // Both chunks arrived, but they aren't a package
+        assert_eq!(reassembler.push(chunk(0, 2)), None);
+        assert_eq!(reassembler.push(chunk(1, 2)), None);
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn chunk_limits_test() {
+        let chunk = |id, seq, len| Package::FileChunk {
+            id,
+            seq,
+            total: 3,
+            bytes: vec![1; len],
+        };
+        let mut reassembler = Reassembler::default().with_limits(1, 10);
+        assert_eq!(reassembler.push(chunk(1, 0, CHUNK_LEN + 1)), None);
+        assert_eq!(reassembler.pending(), 0);
+
+        assert_eq!(reassembler.push(chunk(1, 0, 4)), None);
+        // A second file at once is one too many
+        assert_eq!(reassembler.push(chunk(2, 0, 4)), None);
+        assert_eq!(reassembler.pending(), 1);
+        // Would hold 11 bytes, so the whole file goes
+        assert_eq!(reassembler.push(chunk(1, 1, 7)), None);
+        assert_eq!(reassembler.pending(), 0);
+        assert_eq!(reassembler.push(chunk(2, 0, 10)), None);
+        assert_eq!(reassembler.pending(), 1);
+    }
+
+    #[test]
+    fn part_dir_resume_test() {
+        let dir = std::env::temp_dir().join(format!("clipper-parts-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let stale = part_path(&dir, 0xff);
+        std::fs::write(&stale, [1, 2, 3]).unwrap();
+
+        let package = file_package(3 * CHUNK_LEN + 1);
+        let chunks = split(&package).unwrap().unwrap();
+        let id = chunks.id();
+        let chunks: Vec<Package> = chunks.collect();
+        assert_eq!(chunks.len(), 4);
+
+        let mut reassembler = Reassembler::default().with_part_dir(&dir);
+        // Only in order
+        assert_eq!(reassembler.push(chunks[1].clone()), None);
+        assert!(!part_path(&dir, id).exists());
+        assert_eq!(reassembler.push(chunks[0].clone()), None);
+        assert_eq!(reassembler.push(chunks[1].clone()), None);
+        assert!(!stale.exists());
+        drop(reassembler); // connection lost
+
+        // A chunk torn by a crash mid-write is cut off
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(part_path(&dir, id))
+            .unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        drop(file);
+        let offset = 2 * CHUNK_LEN as u64;
+        assert_eq!(
+            resume_requests(&dir).unwrap(),
+            [Package::Resume { id, offset }]
+        );
+
+        let mut sent = Vec::new();
+        let stats = send_chunked_from(&package, offset, &mut sent, &None, Encoding::default());
+        assert_eq!(stats.unwrap().len(), 2);
+        let mut rest = split(&package).unwrap().unwrap();
+        rest.skip_to(offset);
+        let rest: Vec<Package> = rest.collect();
+        assert_eq!(rest, chunks[2..]);
+
+        let mut reassembler = Reassembler::default().with_part_dir(&dir);
+        // Already there
+        assert_eq!(reassembler.push(chunks[0].clone()), None);
+        assert_eq!(reassembler.push(rest[0].clone()), None);
+        assert_eq!(reassembler.push(rest[1].clone()), Some(package));
+        assert!(resume_requests(&dir).unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,205 +1,1385 @@
 use clipper::network::Package;
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
-use std::net::{SocketAddr, TcpStream};
-use std::sync::Arc;
-use std::time::Duration;
+use std::io::{BufReader, BufWriter};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use clipper::chunk::{resume_requests, send_chunked, Reassembler};
+use clipper::clipboard::{Backend, Clipboard};
+use clipper::item::{ContentFilter, LineEndings, TransferableItem};
 use clipper::network::*;
+use clipper::outbox::Outbox;
+use clipper::proxy::Socks5Proxy;
+use clipper::schedule::{LocalTime, Schedule};
+use clipper::tls::{Connector, ServerName};
 
+mod cue;
 mod parser;
 
 const POOLING_TIME: Duration = Duration::from_millis(200);
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How long the connection may stall in each direction before it is considered dead.
+#[derive(Debug, Clone, Copy)]
+struct Timeouts {
+    /// The server sends heartbeats, so a read waiting this long means the server is gone
+    read: Duration,
+    /// A server accepting nothing for this long has stalled
+    write: Duration,
+}
 
 struct Server {
-    stream: TcpStream,
-    listen_stream: TcpStream,
-    shared_key: Arc<Option<SharedKey>>,
+    addr: SocketAddr,
+    nodelay: bool,
+    timeouts: Timeouts,
+    proxy: Option<Socks5Proxy>,
+    /// Run the connection over TLS instead of plain TCP
+    tls: Option<Connector>,
+    /// Replaced on every reconnect
+    connection: Connection,
+    /// Clone of the current connection, replaced on every reconnect, so other threads can shut
+    /// it down
+    current: Arc<Mutex<TcpStream>>,
+    psk: Option<PreSharedKey>,
+    stop: Arc<AtomicBool>,
+}
+
+/// Which way clips flow between this device and the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Mode {
+    #[default]
+    Bidirectional,
+    /// Only send what is copied here, like a source machine, ignoring what others copy
+    SendOnly,
+    /// Only apply what others copy, like a display machine, without watching the clipboard
+    RecvOnly,
+}
+
+impl Mode {
+    /// Whether the clipboard is watched and its changes sent.
+    fn sends(self) -> bool {
+        self != Self::RecvOnly
+    }
+
+    /// Whether received clips are written to the clipboard.
+    fn receives(self) -> bool {
+        self != Self::SendOnly
+    }
+}
+
+/// Behaviour switches taken from the command line.
+#[derive(Debug, Clone)]
+struct Options {
+    mode: Mode,
+    backend: Backend,
+    line_endings: LineEndings,
+    propagate_clear: bool,
+    schedule: Schedule,
+    dry_run_send: bool,
+    label: Option<String>,
+    label_filter: LabelFilter,
+    receive_cue: cue::ReceiveCue,
+    /// Where received files are saved
+    download_dir: PathBuf,
+    /// Clips that aren't sent
+    content_filter: ContentFilter,
+    /// How often a polled clipboard is checked for changes
+    poll_interval: Duration,
+    /// Set through the control socket to stop sending without disconnecting
+    paused: Arc<AtomicBool>,
+    /// What the listener last wrote to the clipboard, so the sender doesn't send it back
+    applied: Applied,
+    /// Clips sent but not acknowledged by the server yet, kept on disk with --outbox
+    outbox: Option<Arc<Mutex<Outbox>>>,
+}
+
+/// Drops clip `id` from the outbox, which only fails when the file can't be written.
+fn forget(outbox: &mut Outbox, id: u64) {
+    if let Err(err) = outbox.ack(id) {
+        warn!("Unable to update the outbox: {err}");
+    }
 }
 
-#[allow(unused)]
 fn calculate_hash<T: Hash>(t: &T) -> u64 {
     let mut hasher = DefaultHasher::new();
     t.hash(&mut hasher);
     hasher.finish()
 }
 
+/// Hash of the item the listener last wrote to the clipboard, `Some(None)` when it cleared it.
+#[derive(Debug, Clone, Default)]
+struct Applied(Arc<Mutex<Option<Option<u64>>>>);
+
+impl Applied {
+    /// Called right before writing `item`, so the change it causes is never seen without it.
+    fn record(&self, item: &TransferableItem) {
+        *self.0.lock().unwrap() = Some(Some(item.content_hash()));
+    }
+
+    /// Called right before clearing the clipboard, like [`Applied::record`].
+    fn record_clear(&self) {
+        *self.0.lock().unwrap() = Some(None);
+    }
+
+    /// Whether the clipboard changing to `item` is the listener's own write. Every change
+    /// clears the record, so copying the same content again later is sent like any other copy.
+    fn is_echo(&self, item: &Option<TransferableItem>) -> bool {
+        let applied = self.0.lock().unwrap().take();
+        applied.is_some_and(|applied| applied == item.as_ref().map(TransferableItem::content_hash))
+    }
+}
+
 impl Server {
-    fn connect(addr: SocketAddr) -> Self {
-        info!("Connecting to {addr}");
-        let stream = TcpStream::connect(addr).expect("Unable to connect to server");
-        info!("Connected to {addr}");
-        let stream_clone = stream.try_clone().expect("Unable to clone TcpStream");
+    /// Connects to the server, retrying with the same backoff as [`Server::reconnect`] while it
+    /// can't be reached, e.g. because it isn't up yet.
+    fn connect(
+        addr: SocketAddr,
+        nodelay: bool,
+        timeouts: Timeouts,
+        proxy: Option<Socks5Proxy>,
+        tls: Option<Connector>,
+    ) -> Self {
+        let mut backoff = Backoff::new(RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY);
+        let (current, connection) = loop {
+            match Server::open(addr, nodelay, timeouts, proxy.as_ref(), tls.as_ref()) {
+                Ok(opened) => break opened,
+                Err(err) => {
+                    let delay = backoff.next_delay();
+                    warn!("Unable to connect to {addr}: {err}, retrying in {delay:?}");
+                    std::thread::sleep(delay);
+                }
+            }
+        };
         Self {
-            stream,
-            listen_stream: stream_clone,
-            shared_key: Arc::new(None),
+            addr,
+            nodelay,
+            timeouts,
+            proxy,
+            tls,
+            connection,
+            current: Arc::new(Mutex::new(current)),
+            psk: None,
+            stop: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    fn start(&mut self, session: SessionInfo) {
-        use std::thread;
+    fn with_psk(mut self, psk: Option<PreSharedKey>) -> Self {
+        self.psk = psk;
+        self
+    }
+
+    /// Connects to the server, returning the connection along with a clone of its socket.
+    fn open(
+        addr: SocketAddr,
+        nodelay: bool,
+        timeouts: Timeouts,
+        proxy: Option<&Socks5Proxy>,
+        tls: Option<&Connector>,
+    ) -> std::io::Result<(TcpStream, Connection)> {
+        info!("Connecting to {addr}");
+        let stream = match proxy {
+            Some(proxy) => {
+                debug!("Connecting through {proxy:?}");
+                proxy.connect(addr)
+            }
+            None => TcpStream::connect(addr),
+        }?;
+        info!("Connected to {addr}");
+        stream.set_nodelay(nodelay)?;
+        // A stalled read or write then fails like a dropped connection, which reconnects
+        stream.set_read_timeout(Some(timeouts.read))?;
+        stream.set_write_timeout(Some(timeouts.write))?;
+        let current = stream.try_clone()?;
+        let stream = match tls {
+            Some(tls) => {
+                let stream = tls.connect(stream)?;
+                debug!("TLS handshake done");
+                Stream::from(stream)
+            }
+            None => Stream::from(stream),
+        };
+        Ok((current, Connection::new(stream)?))
+    }
+
+    /// Connects to the server again after the connection dropped, waiting longer after every
+    /// failed attempt. Returns `false` if the client was stopped in the meantime.
+    fn reconnect(&mut self, backoff: &mut Backoff) -> bool {
+        loop {
+            let delay = backoff.next_delay();
+            info!("Reconnecting in {delay:?}");
+            // In steps, so stopping doesn't wait out the whole delay
+            let deadline = std::time::Instant::now() + delay;
+            while std::time::Instant::now() < deadline {
+                if self.stop.load(Ordering::SeqCst) {
+                    return false;
+                }
+                std::thread::sleep(POOLING_TIME.min(delay));
+            }
+            if self.stop.load(Ordering::SeqCst) {
+                return false;
+            }
+            let connection = Server::open(
+                self.addr,
+                self.nodelay,
+                self.timeouts,
+                self.proxy.as_ref(),
+                self.tls.as_ref(),
+            );
+            match connection {
+                Ok((current, connection)) => {
+                    self.connection = connection;
+                    *self.current.lock().unwrap() = current;
+                    return true;
+                }
+                Err(err) => warn!("Unable to reconnect to {}: {err}", self.addr),
+            }
+        }
+    }
 
-        // handshake
-        send_session(&mut self.stream, &session).unwrap();
+    /// Shuts the client down once stdin reaches EOF, for supervisors that signal termination by
+    /// closing it.
+    fn exit_on_stdin_close(&self) {
+        let stop = Arc::clone(&self.stop);
+        let current = Arc::clone(&self.current);
+        std::thread::spawn(move || {
+            let _ = std::io::copy(&mut std::io::stdin().lock(), &mut std::io::sink());
+            info!("stdin closed, shutting down");
+            Server::shutdown(&current.lock().unwrap(), &stop);
+        });
+    }
 
-        if session.use_encryption {
-            use rand_core::OsRng;
-            use std::io::Read;
-            use std::io::Write;
-            use x25519_dalek::{EphemeralSecret, PublicKey};
-            let client_private = EphemeralSecret::random_from_rng(OsRng);
-            let client_public = PublicKey::from(&client_private);
+    /// Shuts the client down on Ctrl-C the same way, so no write is cut off halfway and the
+    /// threads return instead of being killed.
+    fn stop_on_ctrl_c(&self) {
+        let stop = Arc::clone(&self.stop);
+        let current = Arc::clone(&self.current);
+        ctrlc::set_handler(move || {
+            info!("Interrupted, shutting down");
+            Server::shutdown(&current.lock().unwrap(), &stop);
+        })
+        .expect("Unable to install the Ctrl-C handler");
+    }
 
-            let mut server_public_key = [0u8; 32];
-            self.stream.read_exact(&mut server_public_key).unwrap();
+    /// Raises the stop flag checked by the sender loop and closes the connection, which unblocks
+    /// the listener.
+    fn shutdown(stream: &TcpStream, stop: &AtomicBool) {
+        stop.store(true, Ordering::SeqCst);
+        let _ = stream.shutdown(Shutdown::Both);
+    }
 
-            self.stream.write_all(client_public.as_bytes()).unwrap();
+    /// Runs until the client is stopped, reconnecting whenever the connection drops.
+    fn start(&mut self, session: SessionInfo, options: Options) {
+        let mut backoff = Backoff::new(RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY);
+        loop {
+            match self.handshake(&session) {
+                Ok(()) => {
+                    backoff.reset();
+                    self.run(&session, &options);
+                }
+                Err(err) => warn!("Handshake with {} failed: {err}", self.addr),
+            }
+            if self.stop.load(Ordering::SeqCst) || !self.reconnect(&mut backoff) {
+                return;
+            }
+        }
+    }
 
-            let server_public = PublicKey::from(server_public_key);
-            let shared_secret = client_private.diffie_hellman(&server_public);
-            self.shared_key = Arc::new(Some(SharedKey::from(shared_secret.as_bytes())));
+    /// Runs the handshake on the current connection. Exits if the server refuses the session,
+    /// as reconnecting wouldn't change its mind.
+    fn handshake(&mut self, session: &SessionInfo) -> std::io::Result<()> {
+        match self.connection.handshake(session, self.psk.as_ref()) {
+            Ok(()) => Ok(()),
+            Err(HandshakeError::Io(err)) => Err(err),
+            Err(err) => {
+                error!("{err}");
+                std::process::exit(1);
+            }
+        }
+    }
 
-            debug!("Shared key: {:?}", self.shared_key);
+    /// Syncs over the current connection until it drops or the client is stopped.
+    fn run(&mut self, session: &SessionInfo, options: &Options) {
+        let shared_key = self.connection.shared_key();
+        let encoding = self.connection.encoding();
+        let (listen_stream, stream) = self.connection.halves();
+        if session.observer {
+            Server::start_observer(listen_stream, &shared_key, encoding, &self.stop);
+            return;
+        }
+        if let Err(err) = Server::request_resume(stream, &shared_key, encoding, options) {
+            warn!("Unable to reach the server: {err}");
+            return;
         }
 
-        thread::scope(|s| {
-            s.spawn(|| Server::start_sender(&mut self.stream, &self.shared_key));
-            s.spawn(|| Server::start_listener(&mut self.listen_stream, &self.shared_key));
+        // Raised by whichever thread notices the connection is gone first, so the other one
+        // stops too and both are restarted on the new connection
+        let disconnected = AtomicBool::new(false);
+        let stop = &self.stop;
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                if options.mode.sends() {
+                    Server::start_sender(
+                        stream,
+                        &shared_key,
+                        encoding,
+                        options,
+                        stop,
+                        &disconnected,
+                    )
+                } else {
+                    // The server still has to hear from us to keep the connection open
+                    Server::start_heartbeat(stream, &shared_key, encoding, stop, &disconnected)
+                }
+            });
+            s.spawn(|| {
+                if options.mode.receives() {
+                    Server::start_listener(
+                        listen_stream,
+                        &shared_key,
+                        encoding,
+                        options,
+                        stop,
+                        &disconnected,
+                    )
+                } else {
+                    // Reading on keeps the server's writes from backing up
+                    Server::discard_incoming(
+                        listen_stream,
+                        &shared_key,
+                        encoding,
+                        options,
+                        stop,
+                        &disconnected,
+                    )
+                }
+            });
         });
     }
 
-    #[cfg(target_os = "macos")]
-    fn start_sender(stream: &mut TcpStream, shared_key: &Option<SharedKey>) {
-        let mut current_count = mac::get_count();
-        loop {
-            // This is ugly but appkit doesn't provide proper API for monitoring clipboard change
-            let t = mac::get_count();
-            if current_count < t {
-                send_package(&generate_package(), stream, shared_key).unwrap();
+    /// Marks the connection as lost and closes it, which unblocks a listener waiting on it.
+    fn disconnect(stream: &TcpStream, disconnected: &AtomicBool) {
+        disconnected.store(true, Ordering::SeqCst);
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    fn send_clipboard(
+        package: Package,
+        stream: &mut BufWriter<Stream>,
+        shared_key: &Option<SharedKey>,
+        encoding: Encoding,
+        options: &Options,
+    ) -> Result<(), NetworkError> {
+        if !options.schedule.is_active(LocalTime::now()) {
+            debug!("Outside of the active hours, not sending");
+            return Ok(());
+        }
+        // A clipboard that briefly goes empty mid-copy would otherwise wipe the other devices
+        if matches!(package, Package::Clear { .. }) && !options.propagate_clear {
+            debug!("Clipboard is empty, not propagating");
+            return Ok(());
+        }
+        if options.paused.load(Ordering::SeqCst) {
+            debug!("Sending is paused");
+            return Ok(());
+        }
+        if options.dry_run_send {
+            info!("Dry run, would send {package}");
+            return Ok(());
+        }
+        // Stored first, so a clip lost with the connection or a crash is sent again
+        let id = options.outbox.as_ref().and_then(|outbox| {
+            outbox
+                .lock()
+                .unwrap()
+                .push(package.clone())
+                .inspect_err(|err| warn!("Unable to store the clip in the outbox: {err}"))
+                .ok()
+        });
+        match send_chunked(&package, stream, shared_key, encoding) {
+            Err(
+                err @ (NetworkError::Serialize(_)
+                | NetworkError::SerializeJson(_)
+                | NetworkError::EncryptionFailed(_)),
+            ) => {
+                warn!("Skipping clipboard item: {err}");
+                // Resending it would fail the same way
+                if let (Some(outbox), Some(id)) = (&options.outbox, id) {
+                    forget(&mut outbox.lock().unwrap(), id);
+                }
+                Ok(())
+            }
+            result => result.map(drop),
+        }
+    }
+
+    /// Asks the server for the rest of the large file a dropped connection cut short, if any,
+    /// then sends a heartbeat: the server holds a large current clip back until the first
+    /// package of a new connection, in case it asks to resume it.
+    fn request_resume<W: std::io::Write>(
+        stream: &mut W,
+        shared_key: &Option<SharedKey>,
+        encoding: Encoding,
+        options: &Options,
+    ) -> Result<(), NetworkError> {
+        if options.mode.receives() {
+            match resume_requests(&options.download_dir) {
+                Ok(requests) => {
+                    for request in requests {
+                        debug!("Resuming: {request}");
+                        send_package(&request, stream, shared_key, encoding)?;
+                    }
+                }
+                Err(err) => warn!("Unable to look for interrupted transfers: {err}"),
+            }
+        }
+        send_package(&Package::Heartbeat, stream, shared_key, encoding)?;
+        Ok(())
+    }
+
+    /// Sends the clips the outbox still holds again, oldest first, for a new connection.
+    fn replay_outbox<W: std::io::Write>(
+        outbox: &Mutex<Outbox>,
+        stream: &mut W,
+        shared_key: &Option<SharedKey>,
+        encoding: Encoding,
+    ) -> Result<(), NetworkError> {
+        let pending = outbox.lock().unwrap().pending().to_vec();
+        if !pending.is_empty() {
+            info!(
+                "Resending {} clips the server didn't acknowledge",
+                pending.len()
+            );
+        }
+        for (_, package) in pending {
+            send_chunked(&package, stream, shared_key, encoding)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the clips `ack` confirms the server received from the outbox.
+    fn acknowledge(outbox: &Mutex<Outbox>, ack: &Package) {
+        let mut outbox = outbox.lock().unwrap();
+        let acked: Vec<u64> = outbox
+            .pending()
+            .iter()
+            .filter(|(_, package)| package.ack().as_ref() == Some(ack))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in acked {
+            forget(&mut outbox, id);
+        }
+    }
+
+    /// Sends a heartbeat once [`HEARTBEAT_INTERVAL`] has passed since `last`, so the server
+    /// can tell an idle client from a dead one.
+    fn send_heartbeat(
+        stream: &mut BufWriter<Stream>,
+        shared_key: &Option<SharedKey>,
+        encoding: Encoding,
+        last: &mut Instant,
+    ) -> Result<(), NetworkError> {
+        if last.elapsed() < HEARTBEAT_INTERVAL {
+            return Ok(());
+        }
+        *last = Instant::now();
+        send_package(&Package::Heartbeat, stream, shared_key, encoding)?;
+        Ok(())
+    }
+
+    /// Only sends heartbeats, for a client that doesn't send its clipboard.
+    fn start_heartbeat(
+        stream: &mut BufWriter<Stream>,
+        shared_key: &Option<SharedKey>,
+        encoding: Encoding,
+        stop: &AtomicBool,
+        disconnected: &AtomicBool,
+    ) {
+        let mut last_heartbeat = Instant::now();
+        while !stop.load(Ordering::SeqCst) && !disconnected.load(Ordering::SeqCst) {
+            if let Err(err) =
+                Server::send_heartbeat(stream, shared_key, encoding, &mut last_heartbeat)
+            {
+                warn!("Unable to send heartbeat: {err}");
+                Server::disconnect(stream.get_ref().tcp(), disconnected);
+                return;
             }
-            current_count = t;
             std::thread::sleep(POOLING_TIME);
         }
     }
 
-    #[cfg(target_os = "macos")]
-    fn start_listener(stream: &mut TcpStream, shared_key: &Option<SharedKey>) {
-        use clipper::item::TransferableItem;
+    /// Reads and drops every package, for a client that doesn't apply received clips. Still
+    /// notices the connection dropping and takes acknowledgements like the listener.
+    fn discard_incoming(
+        stream: &mut BufReader<Stream>,
+        shared_key: &Option<SharedKey>,
+        encoding: Encoding,
+        options: &Options,
+        stop: &AtomicBool,
+        disconnected: &AtomicBool,
+    ) {
         loop {
-            match receive_package(stream, shared_key) {
-                Ok(package) => {
-                    if let Package::Item { item, .. } = package {
-                        println!("writing text");
-                        match item {
-                            TransferableItem::File { .. } => unimplemented!(),
-                            TransferableItem::Folder { .. } => unimplemented!(),
-                            TransferableItem::Text { text } => mac::write_text(text),
-                        }
+            match receive_package(stream, shared_key, encoding) {
+                Ok(ack @ Package::Ack { .. }) => {
+                    if let Some(outbox) = &options.outbox {
+                        Server::acknowledge(outbox, &ack);
                     }
                 }
-                Err(err) => panic!("Unable to connect to server: {err}"),
+                Ok(package) => debug!("Send only, dropping {}", package.redacted()),
+                Err(_) if stop.load(Ordering::SeqCst) || disconnected.load(Ordering::SeqCst) => {
+                    return
+                }
+                Err(
+                    err @ (NetworkError::DecryptionFailed(_)
+                    | NetworkError::Replayed(_)
+                    | NetworkError::DecompressionFailed(_)
+                    | NetworkError::Deserialize(_)
+                    | NetworkError::DeserializeJson(_)),
+                ) => debug!("Dropping received package: {err}"),
+                Err(err) => {
+                    warn!("Lost connection to the server: {err}");
+                    Server::disconnect(stream.get_ref().tcp(), disconnected);
+                    return;
+                }
             }
         }
     }
 
-    #[cfg(target_os = "windows")]
-    fn start_sender(stream: &mut TcpStream, shared_key: &Option<SharedKey>) {
-        let mut current_item = get_current_item();
+    fn start_observer(
+        stream: &mut BufReader<Stream>,
+        shared_key: &Option<SharedKey>,
+        encoding: Encoding,
+        stop: &AtomicBool,
+    ) {
+        let mut reassembler = Reassembler::default();
         loop {
-            let t = get_current_item();
-            if calculate_hash(&t) != calculate_hash(&current_item) {
-                send_package(&generate_package(), stream, shared_key).unwrap();
+            match receive_package(stream, shared_key, encoding) {
+                Ok(Package::Heartbeat) => {}
+                Ok(package) => {
+                    if let Some(package) = reassembler.push(package) {
+                        println!("{package}");
+                    }
+                }
+                Err(_) if stop.load(Ordering::SeqCst) => return,
+                Err(err) => {
+                    warn!("Lost connection to the server: {err}");
+                    return;
+                }
             }
-            current_item = t;
-            std::thread::sleep(POOLING_TIME);
         }
     }
 
-    #[cfg(target_os = "windows")]
-    fn start_listener(stream: &mut TcpStream, shared_key: &Option<SharedKey>) {
+    /// Opens the clipboard for the sender or listener, stopping the client when it can't be,
+    /// as reconnecting wouldn't bring the missing tools back.
+    fn open_clipboard(backend: Backend, stop: &AtomicBool) -> Option<Box<dyn Clipboard + Send>> {
+        match backend.open() {
+            Ok(clipboard) => Some(clipboard),
+            Err(err) => {
+                error!("{err}");
+                stop.store(true, Ordering::SeqCst);
+                None
+            }
+        }
+    }
+
+    fn start_sender(
+        stream: &mut BufWriter<Stream>,
+        shared_key: &Option<SharedKey>,
+        encoding: Encoding,
+        options: &Options,
+        stop: &AtomicBool,
+        disconnected: &AtomicBool,
+    ) {
+        let Some(mut clipboard) = Self::open_clipboard(options.backend, stop) else {
+            return;
+        };
+        if let Some(outbox) = &options.outbox {
+            if let Err(err) = Server::replay_outbox(outbox, stream, shared_key, encoding) {
+                warn!("Unable to resend the outbox: {err}");
+                Server::disconnect(stream.get_ref().tcp(), disconnected);
+                return;
+            }
+        }
+        if let Some(changes) = clipboard.watch() {
+            Server::send_changes(
+                changes,
+                stream,
+                shared_key,
+                encoding,
+                options,
+                stop,
+                disconnected,
+            );
+            return;
+        }
+        let mut current_count = clipboard.change_count();
+        let mut current_item =
+            get_current_item(clipboard.as_mut(), &options.content_filter).unwrap_or_default();
+        let mut last_heartbeat = Instant::now();
+        let mut last_sent = None;
+        while !stop.load(Ordering::SeqCst) && !disconnected.load(Ordering::SeqCst) {
+            if let Err(err) =
+                Server::send_heartbeat(stream, shared_key, encoding, &mut last_heartbeat)
+            {
+                warn!("Unable to send heartbeat: {err}");
+                Server::disconnect(stream.get_ref().tcp(), disconnected);
+                return;
+            }
+            let changed = detect_change(
+                clipboard.as_mut(),
+                &options.content_filter,
+                &mut current_count,
+                &mut current_item,
+            );
+            let package = if changed {
+                generate_package(
+                    current_item.clone(),
+                    &options.content_filter,
+                    &options.applied,
+                    &mut last_sent,
+                )
+            } else {
+                None
+            };
+            if let Some(package) = package {
+                let package = package.with_label(options.label.clone());
+                if let Err(err) =
+                    Server::send_clipboard(package, stream, shared_key, encoding, options)
+                {
+                    warn!("Unable to send package: {err}");
+                    Server::disconnect(stream.get_ref().tcp(), disconnected);
+                    return;
+                }
+            }
+            std::thread::sleep(options.poll_interval);
+        }
+    }
+
+    /// Sends every change reported by a backend that watches the clipboard itself.
+    fn send_changes(
+        changes: Receiver<Option<TransferableItem>>,
+        stream: &mut BufWriter<Stream>,
+        shared_key: &Option<SharedKey>,
+        encoding: Encoding,
+        options: &Options,
+        stop: &AtomicBool,
+        disconnected: &AtomicBool,
+    ) {
+        let mut last_heartbeat = Instant::now();
+        let mut last_sent = None;
+        while !stop.load(Ordering::SeqCst) && !disconnected.load(Ordering::SeqCst) {
+            if let Err(err) =
+                Server::send_heartbeat(stream, shared_key, encoding, &mut last_heartbeat)
+            {
+                warn!("Unable to send heartbeat: {err}");
+                Server::disconnect(stream.get_ref().tcp(), disconnected);
+                return;
+            }
+            // The timeout serves to notice the client stopping and to send heartbeats
+            let item = match changes.recv_timeout(POOLING_TIME) {
+                Ok(item) => item,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    warn!("Stopped watching the clipboard");
+                    return;
+                }
+            };
+            let Some(package) = generate_package(
+                item,
+                &options.content_filter,
+                &options.applied,
+                &mut last_sent,
+            ) else {
+                continue;
+            };
+            let package = package.with_label(options.label.clone());
+            if let Err(err) = Server::send_clipboard(package, stream, shared_key, encoding, options)
+            {
+                warn!("Unable to send package: {err}");
+                Server::disconnect(stream.get_ref().tcp(), disconnected);
+                return;
+            }
+        }
+    }
+
+    fn start_listener(
+        stream: &mut BufReader<Stream>,
+        shared_key: &Option<SharedKey>,
+        encoding: Encoding,
+        options: &Options,
+        stop: &AtomicBool,
+        disconnected: &AtomicBool,
+    ) {
         use clipper::item::TransferableItem;
-        use clipper::windows;
+
+        let Some(mut clipboard) = Self::open_clipboard(options.backend, stop) else {
+            return;
+        };
+        let mut reassembler = Reassembler::default().with_part_dir(&options.download_dir);
         loop {
-            match receive_package(stream, shared_key) {
+            match receive_package(stream, shared_key, encoding) {
+                Ok(Package::Heartbeat) => {}
+                Ok(ack @ Package::Ack { .. }) => {
+                    if let Some(outbox) = &options.outbox {
+                        Server::acknowledge(outbox, &ack);
+                    }
+                }
                 Ok(package) => {
-                    if let Package::Item { item, .. } = package {
+                    // Chunks are only seen once their file is complete
+                    let Some(package) = reassembler.push(package) else {
+                        continue;
+                    };
+                    if !options.schedule.is_active(LocalTime::now()) {
+                        debug!(
+                            "Outside of the active hours, dropping {}",
+                            package.redacted()
+                        );
+                    } else if !options.label_filter.allows(&package) {
+                        debug!("Label not accepted, dropping {}", package.redacted());
+                    } else if let Package::Item {
+                        item,
+                        source_app,
+                        sensitive,
+                        ..
+                    } = package
+                    {
+                        match source_app {
+                            Some(app) => info!("Received {} from {app}", item.redacted()),
+                            None => debug!("Writing {}", item.redacted()),
+                        }
+                        clipboard.set_concealed(sensitive);
                         match item {
-                            TransferableItem::File { .. } => unimplemented!(),
-                            TransferableItem::Folder { .. } => unimplemented!(),
-                            TransferableItem::Text { text } => windows::write_text(text),
+                            TransferableItem::File { .. }
+                            | TransferableItem::Folder { .. }
+                            | TransferableItem::Multiple { .. } => {
+                                match item.write_to_dir(&options.download_dir, None) {
+                                    Ok(path) => {
+                                        info!("Saved to {}", path.display());
+                                        options.receive_cue.play();
+                                    }
+                                    Err(err) => {
+                                        warn!("Dropping received {}: {err}", item.redacted())
+                                    }
+                                }
+                            }
+                            TransferableItem::Text { text } => {
+                                let text = options.line_endings.apply(text);
+                                options
+                                    .applied
+                                    .record(&TransferableItem::Text { text: text.clone() });
+                                match clipboard.write_text(text) {
+                                    Ok(()) => options.receive_cue.play(),
+                                    Err(err) => warn!("Dropping received text: {err}"),
+                                }
+                            }
+                            TransferableItem::RichText { html, rtf, plain } => {
+                                let plain = options.line_endings.apply(plain);
+                                options.applied.record(&TransferableItem::RichText {
+                                    html: html.clone(),
+                                    rtf: rtf.clone(),
+                                    plain: plain.clone(),
+                                });
+                                match clipboard.write_rich_text(
+                                    html.as_deref(),
+                                    rtf.as_deref(),
+                                    plain,
+                                ) {
+                                    Ok(()) => options.receive_cue.play(),
+                                    Err(err) => warn!("Dropping received rich text: {err}"),
+                                }
+                            }
+                            TransferableItem::Url { url } => {
+                                // What the clipboard reads back, for the echo check
+                                let written = if clipboard.writes_urls() {
+                                    TransferableItem::Url { url: url.clone() }
+                                } else {
+                                    TransferableItem::Text { text: url.clone() }
+                                };
+                                options.applied.record(&written);
+                                match clipboard.write_url(url) {
+                                    Ok(()) => options.receive_cue.play(),
+                                    Err(err) => warn!("Dropping received URL: {err}"),
+                                }
+                            }
+                            TransferableItem::Image {
+                                width,
+                                height,
+                                ref bytes,
+                            } => {
+                                options.applied.record(&item);
+                                match clipboard.write_image(width, height, bytes) {
+                                    Ok(()) => options.receive_cue.play(),
+                                    Err(err) => warn!("Dropping received image: {err}"),
+                                }
+                            }
+                            TransferableItem::Raw {
+                                ref type_id,
+                                ref data,
+                            } => {
+                                options.applied.record(&item);
+                                if clipboard.write_raw(type_id, data) {
+                                    options.receive_cue.play();
+                                } else {
+                                    debug!("Backend can't write {type_id}, skipping");
+                                }
+                            }
+                            TransferableItem::Unknown { .. } => {
+                                info!("Skipping an item sent by a newer version of clipper")
+                            }
+                        }
+                    } else if let Package::Clear { .. } = package {
+                        options.applied.record_clear();
+                        match clipboard.clear() {
+                            Ok(()) => info!("Clipboard cleared by another device"),
+                            Err(err) => warn!("Unable to clear the clipboard: {err}"),
                         }
+                    } else if let Package::Presence { peers } = package {
+                        info!("Connected devices: {}", peers.join(", "));
                     }
                 }
-                Err(err) => panic!("Unable to connect to server: {err}"),
+                Err(_) if stop.load(Ordering::SeqCst) || disconnected.load(Ordering::SeqCst) => {
+                    return
+                }
+                // The whole frame was read, so the connection is still in sync
+                Err(
+                    err @ (NetworkError::DecryptionFailed(_)
+                    | NetworkError::Replayed(_)
+                    | NetworkError::DecompressionFailed(_)
+                    | NetworkError::Deserialize(_)
+                    | NetworkError::DeserializeJson(_)),
+                ) => warn!("Dropping received package: {err}"),
+                Err(err) => {
+                    warn!("Lost connection to the server: {err}");
+                    Server::disconnect(stream.get_ref().tcp(), disconnected);
+                    return;
+                }
             }
         }
     }
 }
 
-#[cfg(target_os = "macos")]
-use clipper::mac;
+/// How long `--discover` waits for servers to answer.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
 
-#[cfg(target_os = "windows")]
-fn get_current_item() -> Result<clipper::item::TransferableItem, clipper::windows::Error> {
-    use clipper::{item::TransferableItem, windows};
-
-    let text = windows::read_text();
-    match text {
-        Ok(t) => Ok(TransferableItem::from(t)),
-        Err(err) => Err(err),
+/// Address of the server advertised as `name`, or of the only one advertised. Exits listing
+/// the servers found when that doesn't pick exactly one.
+fn discover_server(name: Option<&str>) -> SocketAddr {
+    info!("Looking for servers on the local network");
+    let found = match clipper::discovery::discover(DISCOVERY_TIMEOUT) {
+        Ok(found) => found,
+        Err(err) => {
+            error!("Unable to browse the local network: {err}");
+            std::process::exit(1);
+        }
+    };
+    let mut candidates: Vec<_> = found
+        .iter()
+        .filter(|server| name.is_none_or(|name| server.name == name))
+        .collect();
+    let server = match (candidates.len(), name) {
+        (1, _) => candidates.remove(0),
+        (0, Some(name)) if !found.is_empty() => {
+            error!("No server named {name:?}, found:");
+            found.iter().for_each(|server| error!("  {}", server.name));
+            std::process::exit(1);
+        }
+        (0, _) => {
+            error!("No server found, is it running with --advertise?");
+            std::process::exit(1);
+        }
+        _ => {
+            error!("Found several servers, pick one with --discover <NAME>:");
+            found.iter().for_each(|server| error!("  {}", server.name));
+            std::process::exit(1);
+        }
+    };
+    if server.protocol_version != Some(PROTOCOL_VERSION) {
+        warn!(
+            "{} speaks protocol version {:?}, expected {PROTOCOL_VERSION}",
+            server.name, server.protocol_version
+        );
     }
+    let Some(addr) = server.addrs.first() else {
+        error!("{} advertised no address", server.name);
+        std::process::exit(1);
+    };
+    info!("Found {} at {addr}", server.name);
+    *addr
 }
 
-#[cfg(target_os = "windows")]
-fn generate_package() -> Package {
-    use clipper::item::TransferableItem;
+/// Creates `dir` if missing and checks that files can be created in it.
+fn prepare_download_dir(dir: &std::path::Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".clipper-probe-{}", std::process::id()));
+    std::fs::write(&probe, [])?;
+    std::fs::remove_file(probe)
+}
 
-    match get_current_item() {
-        Ok(item) => match &item {
-            TransferableItem::Text { .. } => Package::from(item),
-            _ => Package::Empty,
-        },
-        Err(err) => panic!("Unsupported type"),
+/// What the clipboard holds, or why it isn't read: files copied in Explorer that `filter`
+/// rejects are told from their metadata, so a large copy isn't read only to be dropped.
+fn get_current_item(
+    clipboard: &mut dyn Clipboard,
+    filter: &ContentFilter,
+) -> Result<Option<TransferableItem>, String> {
+    // Files copied in Explorer are a separate clipboard format that neither backend reads
+    #[cfg(target_os = "windows")]
+    if let Some(item) = clipper::windows::read_file(filter) {
+        return item.map(Some);
     }
+    #[cfg(not(target_os = "windows"))]
+    let _ = filter;
+    Ok(clipboard.read())
 }
 
-#[cfg(target_os = "macos")]
-fn get_current_item() -> Result<clipper::item::TransferableItem, mac::Error> {
-    use clipper::item::TransferableItem;
-
-    TransferableItem::try_from(mac::read().unwrap())
+/// Whether the clipboard changed since `count` and `item` were taken, which are updated to its
+/// current state. A change to content `filter` rejects before reading it counts as none.
+fn detect_change(
+    clipboard: &mut dyn Clipboard,
+    filter: &ContentFilter,
+    count: &mut Option<isize>,
+    item: &mut Option<TransferableItem>,
+) -> bool {
+    // This is ugly but the clipboard APIs don't provide a proper way to monitor changes
+    match clipboard.change_count() {
+        Some(current) => {
+            let changed = *count != Some(current);
+            *count = Some(current);
+            if changed {
+                match get_current_item(clipboard, filter) {
+                    Ok(current) => *item = current,
+                    Err(reason) => {
+                        info!("Not sending the copied files: {reason}");
+                        return false;
+                    }
+                }
+            }
+            changed
+        }
+        None => {
+            // Only Windows rejects before reading, and it always has a change count
+            let Ok(current) = get_current_item(clipboard, filter) else {
+                return false;
+            };
+            let changed = calculate_hash(&current) != calculate_hash(&*item);
+            *item = current;
+            changed
+        }
+    }
 }
 
-#[cfg(target_os = "macos")]
-fn generate_package() -> Package {
-    use clipper::item::TransferableItem;
+/// Prints every change of the clipboard as the sender would see it, without connecting
+/// anywhere, for `--monitor`. Runs until the process is stopped.
+fn monitor(backend: Backend, poll_interval: Duration) {
+    let print = |item: Option<TransferableItem>| match item {
+        Some(item) => println!("{item}"),
+        None => println!("Clipboard empty"),
+    };
+    let mut clipboard = match backend.open() {
+        Ok(clipboard) => clipboard,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    };
+    if let Some(changes) = clipboard.watch() {
+        changes.into_iter().for_each(print);
+        return;
+    }
+    info!("Monitoring the clipboard, stop with Ctrl-C");
+    // Shows every copy, whatever the sender would filter out
+    let filter = ContentFilter::default();
+    let mut count = clipboard.change_count();
+    let mut item = get_current_item(clipboard.as_mut(), &filter).unwrap_or_default();
+    loop {
+        if detect_change(clipboard.as_mut(), &filter, &mut count, &mut item) {
+            print(item.clone());
+        }
+        std::thread::sleep(poll_interval);
+    }
+}
 
-    match get_current_item() {
-        Ok(item) => match &item {
-            TransferableItem::Text { .. } => Package::from(item),
-            _ => Package::Empty,
-        },
-        Err(err) => panic!("Unsupported type: {err:?}"),
+/// The package announcing `item`, or `None` when it shouldn't be sent at all: `filter` rejects
+/// it, the listener just wrote it, or it is the same as the item sent before. `last_sent` holds
+/// the hash of that item and is updated here.
+fn generate_package(
+    item: Option<TransferableItem>,
+    filter: &ContentFilter,
+    applied: &Applied,
+    last_sent: &mut Option<u64>,
+) -> Option<Package> {
+    if applied.is_echo(&item) {
+        debug!("Clipboard changed to the item just received, not sending it back");
+        // The other devices now hold something else, so copying the last item again is news
+        *last_sent = None;
+        return None;
     }
+    let hash = item.as_ref().map(TransferableItem::content_hash);
+    if hash.is_some() && hash == *last_sent {
+        debug!("Clipboard changed to the item sent last, not sending it again");
+        return None;
+    }
+    *last_sent = hash;
+    match item {
+        Some(item) => {
+            if let Some(reason) = filter.rejects(&item) {
+                info!("Not sending {}: {reason}", item.redacted());
+                return None;
+            }
+            let package = Package::from(item);
+            #[cfg(target_os = "windows")]
+            let package = package.with_source_app(clipper::windows::foreground_app());
+            // Only read at all with --send-concealed
+            #[cfg(target_os = "macos")]
+            let package = package.with_sensitive(clipper::mac::is_concealed());
+            Some(package)
+        }
+        None => Some(Package::clear()),
+    }
+}
+
+/// Accepts `status`, `pause` and `resume` on the control socket at `path`.
+#[cfg(unix)]
+fn serve_control(path: &std::path::Path, paused: Arc<AtomicBool>) {
+    let listener = clipper::control::bind(path).expect("Unable to bind the control socket");
+    info!("Listening for commands on {}", path.display());
+    clipper::control::serve(listener, move |command| match command {
+        "status" if paused.load(Ordering::SeqCst) => "paused".to_string(),
+        "status" => "syncing".to_string(),
+        "pause" => {
+            paused.store(true, Ordering::SeqCst);
+            "ok".to_string()
+        }
+        "resume" => {
+            paused.store(false, Ordering::SeqCst);
+            "ok".to_string()
+        }
+        _ => format!("unknown command {command:?}"),
+    });
+}
+
+#[cfg(not(unix))]
+fn serve_control(_path: &std::path::Path, _paused: Arc<AtomicBool>) {
+    panic!("--control-socket is only supported on unix");
 }
 
 fn main() {
     use parser::*;
     let args = Args::parse();
-    let addr = args.socket;
 
     let log_level = if args.verbose { "debug" } else { "info" };
 
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_level)).init();
+    clipper::item::set_log_content(args.log_content);
+    if args.monitor {
+        monitor(
+            args.clipboard_backend,
+            Duration::from_millis(args.poll_interval_ms),
+        );
+        return;
+    }
+    let addr = match (args.socket, &args.discover) {
+        (Some(addr), _) => addr,
+        (None, Some(name)) => discover_server(name.as_deref()),
+        (None, None) => unreachable!("clap requires --socket or --discover"),
+    };
+    set_max_packet_log_bytes(args.max_packet_log_bytes);
+    set_max_package_len(args.max_package_len);
+    #[cfg(target_os = "macos")]
+    clipper::mac::set_read_concealed(args.send_concealed);
+    #[cfg(not(target_os = "macos"))]
+    if args.send_concealed {
+        warn!("--send-concealed only has an effect on macOS");
+    }
 
-    let mut server = Server::connect(addr);
+    // Better to find out now than when the first file arrives
+    if let Err(err) = prepare_download_dir(&args.download_dir) {
+        error!(
+            "Unable to save received files to {}: {err}",
+            args.download_dir.display()
+        );
+        std::process::exit(1);
+    }
+
+    let tls = args.tls.then(|| {
+        // Without a name the certificate has to be issued for the address connected to
+        let name = match args.tls_server_name {
+            Some(name) => ServerName::try_from(name).unwrap_or_else(|err| {
+                error!("Invalid --tls-server-name: {err}");
+                std::process::exit(1);
+            }),
+            None => ServerName::from(addr.ip()),
+        };
+        Connector::new(name, args.cert.as_deref()).unwrap_or_else(|err| {
+            error!("Unable to set up TLS: {err}");
+            std::process::exit(1);
+        })
+    });
+    let timeouts = Timeouts {
+        read: Duration::from_secs(args.idle_timeout),
+        write: Duration::from_secs(args.write_timeout),
+    };
+    let mut server =
+        Server::connect(addr, !args.no_tcp_nodelay, timeouts, args.proxy, tls).with_psk(args.psk);
+    server.stop_on_ctrl_c();
+    if args.exit_on_stdin_close {
+        server.exit_on_stdin_close();
+    }
 
     let session = SessionInfo {
+        device_name: args.name,
         os: std::env::consts::OS.to_string(),
         use_encryption: args.encrypted,
+        observer: args.observer,
+        ciphers: args.cipher,
+        protocol_version: PROTOCOL_VERSION,
+        compression: args.compression,
+        format: args.format,
+    };
+
+    if args.receive_cue != cue::ReceiveCue::None && !cue::ReceiveCue::available() {
+        warn!("stderr isn't a terminal, --receive-cue has nothing to beep or flash");
+    }
+
+    let options = Options {
+        mode: args.mode,
+        backend: args.clipboard_backend,
+        line_endings: args.line_endings,
+        propagate_clear: args.propagate_clear,
+        schedule: Schedule {
+            hours: args.active_hours,
+            days: args.active_days,
+        },
+        dry_run_send: args.dry_run_send,
+        label: args.label,
+        label_filter: LabelFilter {
+            accept: args.accept_labels,
+            deny: args.deny_labels,
+        },
+        receive_cue: args.receive_cue,
+        download_dir: args.download_dir,
+        content_filter: ContentFilter {
+            no_files: args.no_files,
+            no_images: args.no_images,
+            text_only: args.text_only,
+            max_size: args.max_size,
+        },
+        poll_interval: Duration::from_millis(args.poll_interval_ms),
+        paused: Arc::new(AtomicBool::new(false)),
+        applied: Applied::default(),
+        outbox: args.outbox.map(|path| match Outbox::open(&path) {
+            Ok(outbox) => Arc::new(Mutex::new(outbox)),
+            Err(err) => {
+                error!("Unable to open the outbox {}: {err}", path.display());
+                std::process::exit(1);
+            }
+        }),
     };
 
-    server.start(session);
+    if let Some(path) = args.control_socket {
+        serve_control(&path, Arc::clone(&options.paused));
+    }
+
+    server.start(session, options);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn received_item_is_not_sent_back_test() {
+        let filter = ContentFilter::default();
+        let applied = Applied::default();
+        let mut last_sent = None;
+        let item = TransferableItem::from("Hello".to_string());
+
+        // What the listener does before writing a received item to the clipboard
+        applied.record(&item);
+        assert!(generate_package(Some(item.clone()), &filter, &applied, &mut last_sent).is_none());
+
+        // Copying the same text again afterwards is a genuine copy
+        assert!(generate_package(Some(item.clone()), &filter, &applied, &mut last_sent).is_some());
+
+        // A different copy in between clears the record too
+        applied.record(&item);
+        let other = TransferableItem::from("World".to_string());
+        assert!(generate_package(Some(other), &filter, &applied, &mut last_sent).is_some());
+        assert!(generate_package(Some(item), &filter, &applied, &mut last_sent).is_some());
+    }
+
+    #[test]
+    fn identical_copy_is_sent_once_test() {
+        let filter = ContentFilter::default();
+        let applied = Applied::default();
+        let mut last_sent = None;
+        let item = TransferableItem::from("Hello".to_string());
+
+        let sent: Vec<_> = [&item, &item]
+            .into_iter()
+            .filter_map(|item| {
+                generate_package(Some(item.clone()), &filter, &applied, &mut last_sent)
+            })
+            .collect();
+        assert_eq!(sent.len(), 1);
+
+        // With another copy in between it's sent again
+        let other = TransferableItem::from("World".to_string());
+        assert!(generate_package(Some(other), &filter, &applied, &mut last_sent).is_some());
+        assert!(generate_package(Some(item), &filter, &applied, &mut last_sent).is_some());
+    }
+
+    #[test]
+    fn empty_clipboard_test() {
+        // What an empty pasteboard reads as, e.g. right after another app cleared it
+        let filter = ContentFilter::default();
+        let mut last_sent = None;
+        let package = generate_package(None, &filter, &Applied::default(), &mut last_sent);
+        assert!(matches!(package, Some(Package::Clear { .. })));
+
+        // Emptied by the listener applying a clear from another device
+        let applied = Applied::default();
+        applied.record_clear();
+        assert_eq!(
+            generate_package(None, &filter, &applied, &mut last_sent),
+            None
+        );
+        applied.record_clear();
+        let item = TransferableItem::from("Hello".to_string());
+        assert!(generate_package(Some(item), &filter, &applied, &mut last_sent).is_some());
+    }
+
+    #[test]
+    fn mode_test() {
+        use parser::{Args, Parser};
+
+        // Send only never reaches the listener, so the clipboard is never written
+        assert!(Mode::SendOnly.sends() && !Mode::SendOnly.receives());
+        // Receive only never opens the clipboard to watch it
+        assert!(!Mode::RecvOnly.sends() && Mode::RecvOnly.receives());
+        assert!(Mode::Bidirectional.sends() && Mode::Bidirectional.receives());
+
+        let args = Args::try_parse_from(["client", "-s", "127.0.0.1:4000"]).unwrap();
+        assert_eq!(args.mode, Mode::Bidirectional);
+        let args = Args::try_parse_from(["client", "-s", "127.0.0.1:4000", "--mode", "recv-only"])
+            .unwrap();
+        assert_eq!(args.mode, Mode::RecvOnly);
+    }
+
+    #[test]
+    fn monitor_without_socket_test() {
+        use parser::{Args, Parser};
+
+        let args = Args::try_parse_from(["client", "--monitor"]).unwrap();
+        assert!(args.monitor);
+        assert_eq!(args.socket, None);
+
+        // Without it a server is still required, and with it none is contacted
+        assert!(Args::try_parse_from(["client"]).is_err());
+        assert!(Args::try_parse_from(["client", "--monitor", "-s", "127.0.0.1:4000"]).is_err());
+        assert!(Args::try_parse_from(["client", "--monitor", "--discover"]).is_err());
+    }
+
+    #[test]
+    fn filtered_item_is_not_sent_test() {
+        let filter = ContentFilter {
+            text_only: true,
+            ..ContentFilter::default()
+        };
+        let mut last_sent = None;
+        let image = TransferableItem::Image {
+            width: 1,
+            height: 1,
+            bytes: vec![0; 4],
+        };
+        let applied = Applied::default();
+        assert!(generate_package(Some(image), &filter, &applied, &mut last_sent).is_none());
+        let text = TransferableItem::from("Hello".to_string());
+        assert!(generate_package(Some(text), &filter, &applied, &mut last_sent).is_some());
+    }
+
+    #[test]
+    fn connect_waits_for_server_test() {
+        use std::net::TcpListener;
+
+        // Nothing listens there until the server comes up a moment later
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            TcpListener::bind(addr).unwrap().accept().unwrap()
+        });
+
+        let timeouts = Timeouts {
+            read: Duration::from_secs(1),
+            write: Duration::from_secs(1),
+        };
+        let client = Server::connect(addr, true, timeouts, None, None);
+        assert_eq!(client.addr, addr);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn outbox_test() {
+        let path =
+            std::env::temp_dir().join(format!("clipper-client-outbox-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let outbox = Mutex::new(Outbox::open(&path).unwrap());
+        let first = Package::text("first".to_string());
+        let second = Package::text("second".to_string());
+        for package in [&first, &second] {
+            outbox.lock().unwrap().push(package.clone()).unwrap();
+        }
+
+        // Sent again on the next connection until the server acknowledges it
+        Server::acknowledge(&outbox, &first.ack().unwrap());
+        Server::acknowledge(&outbox, &Package::Heartbeat);
+        let mut wire = Vec::new();
+        Server::replay_outbox(&outbox, &mut wire, &None, Encoding::default()).unwrap();
+        let mut wire = wire.as_slice();
+        assert_eq!(
+            receive_package(&mut wire, &None, Encoding::default()).unwrap(),
+            second
+        );
+        assert!(wire.is_empty());
+
+        // Also after a restart
+        drop(outbox);
+        let outbox = Mutex::new(Outbox::open(&path).unwrap());
+        assert_eq!(outbox.lock().unwrap().pending().len(), 1);
+        Server::acknowledge(&outbox, &second.ack().unwrap());
+        assert!(outbox.lock().unwrap().pending().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn download_dir_test() {
+        let dir = std::env::temp_dir().join(format!("clipper-download-{}", std::process::id()));
+        let download_dir = dir.join("nested").join("downloads");
+
+        prepare_download_dir(&download_dir).unwrap();
+        assert!(download_dir.is_dir());
+        assert_eq!(std::fs::read_dir(&download_dir).unwrap().count(), 0);
+
+        std::fs::write(dir.join("report.pdf"), b"%PDF").unwrap();
+        let item = TransferableItem::read_file(dir.join("report.pdf")).unwrap();
+        let path = item.write_to_dir(&download_dir, None).unwrap();
+        assert_eq!(path, download_dir.join("report.pdf"));
+        assert_eq!(std::fs::read(path).unwrap(), b"%PDF");
+
+        // A file where the directory should be
+        let blocked = dir.join("file");
+        std::fs::write(&blocked, []).unwrap();
+        assert!(prepare_download_dir(&blocked).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
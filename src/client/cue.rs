@@ -0,0 +1,45 @@
+use std::io::{IsTerminal, Write};
+use std::time::Duration;
+
+const FLASH_DURATION: Duration = Duration::from_millis(100);
+
+/// Feedback given on the receiving machine when a clip is applied. Both cues are escape codes
+/// for the terminal on stderr, so a client running as a service or with stderr redirected gives
+/// none, see [`ReceiveCue::available`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ReceiveCue {
+    #[default]
+    None,
+    /// Ring the terminal bell, which most desktops turn into their alert sound
+    Beep,
+    /// Briefly invert the terminal's colours
+    Flash,
+}
+
+impl ReceiveCue {
+    /// Whether there is a terminal to play cues on.
+    pub fn available() -> bool {
+        std::io::stderr().is_terminal()
+    }
+
+    pub fn play(self) {
+        let mut stderr = std::io::stderr();
+        // Escape codes would only garble a log file
+        if self == Self::None || !Self::available() {
+            return;
+        }
+        let _ = match self {
+            Self::None => Ok(()),
+            Self::Beep => stderr.write_all(b"\x07"),
+            Self::Flash => flash(&mut stderr),
+        };
+    }
+}
+
+fn flash(terminal: &mut impl Write) -> std::io::Result<()> {
+    terminal.write_all(b"\x1b[?5h")?;
+    terminal.flush()?;
+    std::thread::sleep(FLASH_DURATION);
+    terminal.write_all(b"\x1b[?5l")?;
+    terminal.flush()
+}
@@ -1,4 +1,14 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clipper::clipboard::Backend;
+use clipper::item::LineEndings;
+use clipper::network::{Cipher, Codec, Format, PreSharedKey};
+use clipper::proxy::Socks5Proxy;
+use clipper::schedule::{ActiveDays, ActiveHours};
+
+use crate::cue::ReceiveCue;
+use crate::Mode;
 
 pub use clap::Parser;
 
@@ -7,12 +17,150 @@ pub use clap::Parser;
 #[command(version, about, long_about = None)]
 pub struct Args {
     /// Target socket
-    #[arg(short, long)]
-    pub socket: SocketAddr,
+    #[arg(short, long, required_unless_present_any = ["discover", "monitor"])]
+    pub socket: Option<SocketAddr>,
+    /// Find the server on the local network instead, by name when several are advertised
+    #[arg(long, value_name = "NAME", num_args = 0..=1, conflicts_with = "socket")]
+    pub discover: Option<Option<String>>,
+    /// Only print every clipboard change this device sees, without connecting anywhere, e.g.
+    /// to check its clipboard types are supported
+    #[arg(long, conflicts_with_all = ["socket", "discover"])]
+    pub monitor: bool,
+    /// Reach the server through a proxy, given as socks5://[user:password@]host:port
+    #[arg(long)]
+    pub proxy: Option<Socks5Proxy>,
+    /// Name of this device shown to the other peers
+    #[arg(short, long, default_value_t = clipper::network::default_device_name())]
+    pub name: String,
     /// Use encryption
     #[arg(short, long)]
     pub encrypted: bool,
+    /// Secret to prove to a server started with --psk
+    #[arg(long, requires = "encrypted")]
+    pub psk: Option<PreSharedKey>,
+    /// Ciphers to offer the server when encrypting, most preferred first
+    #[arg(long, value_delimiter = ',', default_values_t = Cipher::SUPPORTED)]
+    pub cipher: Vec<Cipher>,
+    /// Connect over TLS, to a server started with --tls-cert
+    #[arg(long)]
+    pub tls: bool,
+    /// Only trust the server certificate, or its issuer, in this PEM file instead of the public
+    /// roots, e.g. to pin a self-signed certificate
+    #[arg(long, requires = "tls")]
+    pub cert: Option<PathBuf>,
+    /// Name the server certificate must be issued for, by default the address connected to
+    #[arg(long, requires = "tls")]
+    pub tls_server_name: Option<String>,
+    /// Ask the server to compress packages both ways with this codec
+    #[arg(long, value_enum)]
+    pub compression: Option<Codec>,
+    /// Ask the server to serialize packages both ways in this format. JSON is easier to inspect
+    /// on the wire and to speak from other languages
+    #[arg(long, value_enum, default_value_t = Format::Bincode)]
+    pub format: Format,
     /// Verbose
     #[arg(short, long)]
     pub verbose: bool,
+    /// Maximum number of bytes of each package hex dumped at trace level
+    #[arg(long, default_value_t = 64)]
+    pub max_packet_log_bytes: usize,
+    /// Log copied text in full instead of its length and a hash, for debugging
+    #[arg(long)]
+    pub log_content: bool,
+    /// Refuse packages larger than this many bytes
+    #[arg(long, default_value_t = clipper::network::MAX_PACKAGE_LEN)]
+    pub max_package_len: u64,
+    /// Don't send clips with more payload bytes than this, like large file copies
+    #[arg(long, default_value_t = clipper::network::MAX_PACKAGE_LEN)]
+    pub max_size: u64,
+    /// Don't send copied files and folders
+    #[arg(long)]
+    pub no_files: bool,
+    /// Don't send copied images
+    #[arg(long)]
+    pub no_images: bool,
+    /// Send clips the copying app marked concealed, like passwords, instead of skipping them.
+    /// Receiving Macs keep them out of clipboard histories. macOS only
+    #[arg(long)]
+    pub send_concealed: bool,
+    /// Only send text and links, e.g. to keep binaries and images on this device
+    #[arg(long)]
+    pub text_only: bool,
+    /// How often the clipboard is checked for changes where the backend can't report them, in
+    /// milliseconds
+    #[arg(long, default_value_t = 200, value_parser = clap::value_parser!(u64).range(1..))]
+    pub poll_interval_ms: u64,
+    /// Reconnect when the server sends nothing, not even its heartbeat every 10 seconds, for
+    /// this many seconds
+    #[arg(
+        long,
+        default_value_t = clipper::network::IDLE_TIMEOUT.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub idle_timeout: u64,
+    /// Reconnect when the server accepts nothing sent to it for this many seconds
+    #[arg(
+        long,
+        default_value_t = clipper::network::WRITE_TIMEOUT.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub write_timeout: u64,
+    /// Leave Nagle's algorithm enabled on the connection
+    #[arg(long)]
+    pub no_tcp_nodelay: bool,
+    /// Shut down when stdin reaches EOF
+    #[arg(long)]
+    pub exit_on_stdin_close: bool,
+    /// Only print the clips broadcast by the server, without sending or writing the clipboard
+    #[arg(long)]
+    pub observer: bool,
+    /// Which way clips flow, e.g. recv-only for a machine that only displays what others copy
+    #[arg(long, value_enum, default_value_t = Mode::Bidirectional, conflicts_with = "observer")]
+    pub mode: Mode,
+    /// Connect and receive as usual, but only log the clips that would be sent
+    #[arg(long)]
+    pub dry_run_send: bool,
+    /// Tag every clip sent with this label
+    #[arg(long)]
+    pub label: Option<String>,
+    /// Only apply received clips with one of these labels; unlabelled clips are always applied
+    #[arg(long, value_delimiter = ',')]
+    pub accept_labels: Vec<String>,
+    /// Never apply received clips with one of these labels
+    #[arg(long, value_delimiter = ',')]
+    pub deny_labels: Vec<String>,
+    /// Beep or flash the terminal whenever a received clip is applied. Needs stderr to be a
+    /// terminal, there is no cue when the client runs as a service
+    #[arg(long, value_enum, default_value_t = ReceiveCue::None)]
+    pub receive_cue: ReceiveCue,
+    /// Directory received files are saved to, created if missing
+    #[arg(long, default_value_os_t = default_download_dir())]
+    pub download_dir: PathBuf,
+    /// Accept status/pause/resume commands on a Unix socket only this user can access
+    #[arg(long)]
+    pub control_socket: Option<PathBuf>,
+    /// Propagate an emptied clipboard to the other devices
+    #[arg(long)]
+    pub propagate_clear: bool,
+    /// Keep every clip sent in this file until the server acknowledges it, and send what is
+    /// left again after reconnecting or restarting
+    #[arg(long)]
+    pub outbox: Option<PathBuf>,
+    /// Clipboard implementation to use
+    #[arg(long, value_enum, default_value_t = Backend::default())]
+    pub clipboard_backend: Backend,
+    /// Only sync during this daily window, e.g. 09:00-18:00
+    #[arg(long)]
+    pub active_hours: Option<ActiveHours>,
+    /// Only sync on these days, e.g. mon,tue,wed,thu,fri
+    #[arg(long)]
+    pub active_days: Option<ActiveDays>,
+    /// Line endings of received text
+    #[arg(long, value_enum, default_value_t = LineEndings::Preserve)]
+    pub line_endings: LineEndings,
+}
+
+/// The user's download directory, or the working directory where the platform has none.
+fn default_download_dir() -> PathBuf {
+    dirs::download_dir().unwrap_or_else(|| PathBuf::from("."))
 }
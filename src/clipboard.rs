@@ -0,0 +1,396 @@
+use crate::item::TransferableItem;
+use std::sync::mpsc::Receiver;
+
+/// Failure reported by a clipboard backend.
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Access to the system clipboard, implemented by every backend so the client can pick one at
+/// runtime.
+pub trait Clipboard {
+    /// Counter that changes on every clipboard write, for backends that provide one cheaply.
+    /// Without it, changes are detected by comparing the clipboard content.
+    fn change_count(&mut self) -> Option<isize> {
+        None
+    }
+
+    /// Current clipboard content, `None` when it is empty or of an unsupported type.
+    fn read(&mut self) -> Option<TransferableItem>;
+
+    /// Channel receiving the new content on every change, for backends that watch the clipboard
+    /// themselves instead of being polled.
+    fn watch(&mut self) -> Option<Receiver<Option<TransferableItem>>> {
+        None
+    }
+
+    fn write_text(&mut self, text: String) -> Result<(), Error>;
+
+    /// Writes styled text read as [`TransferableItem::RichText`]. Backends that only handle
+    /// plain text write `plain`.
+    fn write_rich_text(
+        &mut self,
+        _html: Option<&str>,
+        _rtf: Option<&[u8]>,
+        plain: String,
+    ) -> Result<(), Error> {
+        self.write_text(plain)
+    }
+
+    /// Whether [`Clipboard::write_url`] writes URLs as links rather than falling back to text.
+    fn writes_urls(&self) -> bool {
+        false
+    }
+
+    /// Writes a link read as [`TransferableItem::Url`]. Backends without a URL type write it as
+    /// text.
+    fn write_url(&mut self, url: String) -> Result<(), Error> {
+        self.write_text(url)
+    }
+
+    /// Writes an RGBA bitmap read as [`TransferableItem::Image`].
+    fn write_image(&mut self, _width: usize, _height: usize, _bytes: &[u8]) -> Result<(), Error> {
+        Err("This clipboard backend can't write images".into())
+    }
+
+    /// Empties the clipboard, for a clear on another device. Backends that can't write an empty
+    /// clipboard write empty text.
+    fn clear(&mut self) -> Result<(), Error> {
+        self.write_text(String::new())
+    }
+
+    /// Writes data of a platform specific type read as [`TransferableItem::Raw`]. Returns
+    /// `false` when the backend can't, e.g. because the type comes from another platform.
+    fn write_raw(&mut self, _type_id: &str, _data: &[u8]) -> bool {
+        false
+    }
+
+    /// Marks what is written from now on as a secret clipboard histories should skip, for clips
+    /// sent as sensitive. Backends without such a marker ignore it.
+    fn set_concealed(&mut self, _concealed: bool) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// The platform's own clipboard API (`NSPasteboard` on macOS, `wl-clipboard` or `xclip` on
+    /// Linux)
+    Native,
+    Arboard,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        #[cfg(target_os = "windows")]
+        return Self::Arboard;
+        #[cfg(not(target_os = "windows"))]
+        return Self::Native;
+    }
+}
+
+impl Backend {
+    /// Opens the clipboard through this backend, failing when the tools or services it is
+    /// built on aren't available.
+    #[cfg(any(target_os = "macos", target_os = "windows"))]
+    pub fn open(self) -> Result<Box<dyn Clipboard + Send>, Error> {
+        Ok(match self {
+            #[cfg(target_os = "macos")]
+            Self::Native => Box::new(Native::default()),
+            #[cfg(target_os = "windows")]
+            Self::Native => Box::new(Native),
+            Self::Arboard => Box::new(Arboard {}),
+        })
+    }
+
+    /// Opens the clipboard through this backend, failing when the tools or services it is
+    /// built on aren't available.
+    #[cfg(target_os = "linux")]
+    pub fn open(self) -> Result<Box<dyn Clipboard + Send>, Error> {
+        Ok(match self {
+            Self::Native => Box::new(Native::new()?),
+            Self::Arboard => Box::new(Arboard::new()?),
+        })
+    }
+}
+
+#[cfg(target_os = "macos")]
+#[derive(Default)]
+pub struct Native {
+    concealed: bool,
+}
+
+/// The pasteboard `items` as one, several becoming a [`TransferableItem::Multiple`]. Items of
+/// unsupported types are left out.
+#[cfg(target_os = "macos")]
+fn from_pasteboard(items: Vec<crate::mac::Item>) -> Option<TransferableItem> {
+    let mut items: Vec<_> = items
+        .into_iter()
+        .filter_map(|item| TransferableItem::try_from(item).ok())
+        .collect();
+    match items.len() {
+        0 | 1 => items.pop(),
+        _ => Some(TransferableItem::Multiple { items }),
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl Clipboard for Native {
+    fn change_count(&mut self) -> Option<isize> {
+        Some(crate::mac::get_count())
+    }
+
+    fn read(&mut self) -> Option<TransferableItem> {
+        from_pasteboard(crate::mac::read_all())
+    }
+
+    fn watch(&mut self) -> Option<Receiver<Option<TransferableItem>>> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        crate::mac::watch(move |items| sender.send(from_pasteboard(items)).is_ok());
+        Some(receiver)
+    }
+
+    fn write_text(&mut self, text: String) -> Result<(), Error> {
+        crate::mac::write_text(text, self.concealed);
+        Ok(())
+    }
+
+    fn write_rich_text(
+        &mut self,
+        html: Option<&str>,
+        rtf: Option<&[u8]>,
+        plain: String,
+    ) -> Result<(), Error> {
+        crate::mac::write_rich_text(html, rtf, &plain, self.concealed);
+        Ok(())
+    }
+
+    fn write_raw(&mut self, type_id: &str, data: &[u8]) -> bool {
+        crate::mac::write_data(type_id, data, self.concealed);
+        true
+    }
+
+    fn writes_urls(&self) -> bool {
+        true
+    }
+
+    fn write_url(&mut self, url: String) -> Result<(), Error> {
+        crate::mac::write_url(&url, self.concealed);
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), Error> {
+        crate::mac::clear();
+        Ok(())
+    }
+
+    fn set_concealed(&mut self, concealed: bool) {
+        self.concealed = concealed;
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub struct Native;
+
+#[cfg(target_os = "windows")]
+impl Clipboard for Native {
+    fn change_count(&mut self) -> Option<isize> {
+        Some(crate::windows::get_sequence_number())
+    }
+
+    fn read(&mut self) -> Option<TransferableItem> {
+        if let Ok(text) = crate::windows::read_text() {
+            return Some(TransferableItem::from(text));
+        }
+        crate::windows::read_image()
+            .ok()
+            .map(TransferableItem::from)
+    }
+
+    fn write_text(&mut self, text: String) -> Result<(), Error> {
+        Ok(crate::windows::write_text(text)?)
+    }
+
+    fn write_image(&mut self, width: usize, height: usize, bytes: &[u8]) -> Result<(), Error> {
+        Ok(crate::windows::write_image(arboard::ImageData {
+            width,
+            height,
+            bytes: bytes.into(),
+        })?)
+    }
+
+    fn clear(&mut self) -> Result<(), Error> {
+        Ok(crate::windows::clear()?)
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub struct Native(crate::linux::CachedSelection);
+
+#[cfg(target_os = "linux")]
+impl Native {
+    fn new() -> Result<Self, Error> {
+        let selection = crate::linux::CachedSelection::new(crate::linux::Selection::Clipboard)
+            .map_err(|err| {
+                format!(
+                    "Unable to watch the clipboard, are wl-clipboard or xclip and clipnotify \
+                     installed? {err}"
+                )
+            })?;
+        Ok(Self(selection))
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Clipboard for Native {
+    fn change_count(&mut self) -> Option<isize> {
+        Some(self.0.change_count())
+    }
+
+    fn read(&mut self) -> Option<TransferableItem> {
+        self.0.read().map(TransferableItem::from)
+    }
+
+    fn write_text(&mut self, text: String) -> Result<(), Error> {
+        Ok(crate::linux::write_text(text)?)
+    }
+
+    fn clear(&mut self) -> Result<(), Error> {
+        Ok(crate::linux::clear()?)
+    }
+}
+
+pub struct Arboard {
+    /// Under X11 the clipboard content is served by arboard as long as any of its clipboards
+    /// is alive, so one is kept for the lifetime of the backend
+    #[cfg(target_os = "linux")]
+    _owner: arboard::Clipboard,
+}
+
+#[cfg(target_os = "linux")]
+impl Arboard {
+    fn new() -> Result<Self, Error> {
+        let owner = arboard::Clipboard::new()
+            .map_err(|err| format!("Unable to open the clipboard: {err}"))?;
+        Ok(Self { _owner: owner })
+    }
+}
+
+impl Clipboard for Arboard {
+    #[cfg(target_os = "windows")]
+    fn change_count(&mut self) -> Option<isize> {
+        Some(crate::windows::get_sequence_number())
+    }
+
+    fn read(&mut self) -> Option<TransferableItem> {
+        // Opening doesn't take the clipboard, the reads do, so they are what is retried
+        match retry_occupied(|| arboard::Clipboard::new()?.get_text()) {
+            Ok(text) => return Some(TransferableItem::from(text)),
+            Err(arboard::Error::ContentNotAvailable | arboard::Error::ConversionFailure) => {}
+            Err(err) => {
+                log::warn!("Unable to read the clipboard: {err}");
+                return None;
+            }
+        }
+        match retry_occupied(|| arboard::Clipboard::new()?.get_image()) {
+            Ok(image) => Some(TransferableItem::from(image)),
+            Err(arboard::Error::ContentNotAvailable | arboard::Error::ConversionFailure) => None,
+            Err(err) => {
+                log::warn!("Unable to read the clipboard: {err}");
+                None
+            }
+        }
+    }
+
+    fn write_text(&mut self, text: String) -> Result<(), Error> {
+        retry_occupied(|| arboard::Clipboard::new()?.set_text(text.as_str()))?;
+        Ok(())
+    }
+
+    fn write_rich_text(
+        &mut self,
+        html: Option<&str>,
+        _rtf: Option<&[u8]>,
+        plain: String,
+    ) -> Result<(), Error> {
+        match html {
+            Some(html) => {
+                retry_occupied(|| arboard::Clipboard::new()?.set_html(html, Some(plain.as_str())))?;
+                Ok(())
+            }
+            None => self.write_text(plain),
+        }
+    }
+
+    fn write_image(&mut self, width: usize, height: usize, bytes: &[u8]) -> Result<(), Error> {
+        // Checked, a forged size mustn't overflow into a match
+        let len = width
+            .checked_mul(height)
+            .and_then(|pixels| pixels.checked_mul(4));
+        if len != Some(bytes.len()) {
+            return Err(format!(
+                "{} bytes don't make a {width}x{height} RGBA image",
+                bytes.len()
+            )
+            .into());
+        }
+        let image = arboard::ImageData {
+            width,
+            height,
+            bytes: bytes.into(),
+        };
+        retry_occupied(|| arboard::Clipboard::new()?.set_image(image.clone()))?;
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), Error> {
+        retry_occupied(|| arboard::Clipboard::new()?.clear())?;
+        Ok(())
+    }
+}
+
+const BUSY_ATTEMPTS: u32 = 5;
+const BUSY_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Runs `access`, opening the clipboard and reading or writing it, again while another process
+/// holds the clipboard open, which on Windows happens whenever e.g. Office touches it at the
+/// same time.
+pub(crate) fn retry_occupied<T>(
+    mut access: impl FnMut() -> Result<T, arboard::Error>,
+) -> Result<T, arboard::Error> {
+    let mut attempt = 1;
+    loop {
+        match access() {
+            Err(arboard::Error::ClipboardOccupied) if attempt < BUSY_ATTEMPTS => {
+                log::debug!("Clipboard is busy, retrying ({attempt}/{BUSY_ATTEMPTS})");
+                attempt += 1;
+                std::thread::sleep(BUSY_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_occupied_test() {
+        let mut calls = 0;
+        let result = retry_occupied(|| {
+            calls += 1;
+            if calls < 3 {
+                Err(arboard::Error::ClipboardOccupied)
+            } else {
+                Ok(())
+            }
+        });
+        assert!(result.is_ok());
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result = retry_occupied(|| {
+            calls += 1;
+            Err::<(), _>(arboard::Error::ClipboardOccupied)
+        });
+        assert!(matches!(result, Err(arboard::Error::ClipboardOccupied)));
+        assert_eq!(calls, BUSY_ATTEMPTS);
+    }
+}
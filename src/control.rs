@@ -0,0 +1,102 @@
+//! Local control interface over a Unix domain socket.
+//!
+//! The socket file is created with mode 0600, so only the user running clipper can connect and
+//! no TCP port is opened for administration. Commands are single lines, each answered with a
+//! single line.
+
+use log::{debug, warn};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// Binds `path`, replacing a socket left behind by an earlier run.
+pub fn bind<P: AsRef<Path>>(path: P) -> std::io::Result<UnixListener> {
+    let path = path.as_ref();
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    // Safety: `umask` only swaps the process file mode mask. Creating the socket under a
+    // restrictive mask instead of chmod-ing it afterwards leaves no window where others could
+    // connect.
+    let previous = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(previous) };
+    listener
+}
+
+/// Answers every command received on `listener` with `handle`, on a background thread.
+pub fn serve<F>(listener: UnixListener, handle: F) -> JoinHandle<()>
+where
+    F: Fn(&str) -> String + Send + Sync + 'static,
+{
+    let handle = Arc::new(handle);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let handle = Arc::clone(&handle);
+                    std::thread::spawn(move || {
+                        if let Err(err) = serve_connection(stream, handle.as_ref()) {
+                            debug!("Control connection closed: {err}");
+                        }
+                    });
+                }
+                Err(err) => warn!("Control socket failed: {err}"),
+            }
+        }
+    })
+}
+
+fn serve_connection(stream: UnixStream, handle: &dyn Fn(&str) -> String) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let reply = handle(line.trim());
+        writeln!(writer, "{reply}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn control_socket_test() {
+        let path = std::env::temp_dir().join(format!("clipper-control-{}", std::process::id()));
+        // A stale socket from a crashed run is replaced
+        drop(UnixListener::bind(&path).unwrap());
+
+        let listener = bind(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        serve(listener, |command| format!("got {command}"));
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut reply = String::new();
+        for command in ["status", "pause"] {
+            writeln!(writer, "{command}").unwrap();
+            reply.clear();
+            reader.read_line(&mut reply).unwrap();
+            assert_eq!(reply, format!("got {command}\n"));
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "").unwrap();
+        assert!(bind(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! Finding servers on the local network over mDNS (DNS-SD), so clients don't need to be told
+//! the server's address.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+use crate::network::PROTOCOL_VERSION;
+
+/// DNS-SD service type servers are advertised under.
+pub const SERVICE_TYPE: &str = "_clipper._tcp.local.";
+
+/// TXT record key holding the server's [`PROTOCOL_VERSION`].
+const VERSION_KEY: &str = "protocol";
+
+pub type Error = mdns_sd::Error;
+
+/// A server that answered [`discover`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Found {
+    /// Instance name the server was advertised under
+    pub name: String,
+    /// Every address the server can be reached on, sorted
+    pub addrs: Vec<SocketAddr>,
+    /// `None` if the record doesn't say
+    pub protocol_version: Option<u32>,
+}
+
+impl From<&ServiceInfo> for Found {
+    fn from(info: &ServiceInfo) -> Self {
+        let fullname = info.get_fullname();
+        let name = fullname
+            .strip_suffix(SERVICE_TYPE)
+            .and_then(|name| name.strip_suffix('.'))
+            .unwrap_or(fullname);
+        let mut addrs: Vec<_> = info
+            .get_addresses()
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, info.get_port()))
+            .collect();
+        addrs.sort();
+        Self {
+            name: name.to_string(),
+            addrs,
+            protocol_version: info
+                .get_property_val_str(VERSION_KEY)
+                .and_then(|version| version.parse().ok()),
+        }
+    }
+}
+
+/// The record advertising a server called `name` on `port`. Without `addrs`, the addresses of
+/// every interface are filled in and kept up to date.
+fn service_info(name: &str, port: u16, addrs: &[IpAddr]) -> Result<ServiceInfo, Error> {
+    // Host names only allow letters, digits and dashes
+    let host: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let version = PROTOCOL_VERSION.to_string();
+    let info = ServiceInfo::new(
+        SERVICE_TYPE,
+        name,
+        &format!("{host}.local."),
+        addrs,
+        port,
+        &[(VERSION_KEY, version.as_str())][..],
+    )?;
+    Ok(if addrs.is_empty() {
+        info.enable_addr_auto()
+    } else {
+        info
+    })
+}
+
+/// Keeps a server advertised until dropped.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Advertises a server called `name` listening on `port` on every interface.
+pub fn advertise(name: &str, port: u16) -> Result<Advertisement, Error> {
+    let daemon = ServiceDaemon::new()?;
+    daemon.register(service_info(name, port, &[])?)?;
+    Ok(Advertisement { daemon })
+}
+
+/// Browses the network for `timeout` and returns every server that answered, sorted by name.
+pub fn discover(timeout: Duration) -> Result<Vec<Found>, Error> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    let deadline = Instant::now() + timeout;
+    let mut found: Vec<Found> = Vec::new();
+    while let Ok(event) = events.recv_deadline(deadline) {
+        let ServiceEvent::ServiceResolved(info) = event else {
+            continue;
+        };
+        debug!("Resolved {}", info.get_fullname());
+        let server = Found::from(&info);
+        // A server is resolved again whenever its record changes, keep the latest
+        found.retain(|other| other.name != server.name);
+        found.push(server);
+    }
+    let _ = daemon.shutdown();
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_info_round_trip_test() {
+        let addrs: [IpAddr; 2] = ["192.168.1.20".parse().unwrap(), "fe80::1".parse().unwrap()];
+        let info = service_info("office pc", 4000, &addrs).unwrap();
+
+        assert_eq!(
+            Found::from(&info),
+            Found {
+                name: "office pc".to_string(),
+                addrs: vec![
+                    "192.168.1.20:4000".parse().unwrap(),
+                    "[fe80::1]:4000".parse().unwrap(),
+                ],
+                protocol_version: Some(PROTOCOL_VERSION),
+            }
+        );
+        assert_eq!(info.get_hostname(), "office-pc.local.");
+    }
+}
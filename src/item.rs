@@ -1,10 +1,11 @@
 use core::fmt;
-use serde::de::{self, Visitor};
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::ffi::OsString;
+use serde::{Deserialize, Serialize};
+use std::ffi::{OsStr, OsString};
 use std::path::Path;
 use std::path::*;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+#[cfg(target_os = "macos")]
 const MAX_SYMLINK_RECURSION_DEPTH: usize = 100;
 
 #[cfg(target_os = "macos")]
@@ -13,6 +14,8 @@ mod mac_item {
     use crate::mac;
     use objc2::rc::Retained;
     use objc2_foundation::*;
+    use serde::de::{self, Visitor};
+    use serde::{Deserializer, Serializer};
 
     #[derive(Debug, PartialEq, Eq, Default, Hash, Clone)]
     pub struct RetainedDataWrapper(Retained<NSData>);
@@ -47,6 +50,18 @@ mod mac_item {
         {
             Ok(RetainedDataWrapper(NSData::with_bytes(v)))
         }
+        /// Formats without a bytes type, like JSON, hand the bytes over as a sequence of
+        /// integers.
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: de::SeqAccess<'de>,
+        {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(RetainedDataWrapper(NSData::from_vec(bytes)))
+        }
     }
 
     impl From<Retained<NSData>> for RetainedDataWrapper {
@@ -55,10 +70,20 @@ mod mac_item {
         }
     }
 
+    impl From<Vec<u8>> for RetainedDataWrapper {
+        fn from(value: Vec<u8>) -> Self {
+            Self(NSData::from_vec(value))
+        }
+    }
+
     impl RetainedDataWrapper {
         pub fn len(&self) -> usize {
             self.0.len()
         }
+
+        pub fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
     }
 
     impl AsRef<[u8]> for RetainedDataWrapper {
@@ -67,6 +92,32 @@ mod mac_item {
         }
     }
 
+    impl std::ops::Deref for RetainedDataWrapper {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            self.0.bytes()
+        }
+    }
+
+    /// Follows `path` through every symlink to the file it finally points at. Relative targets
+    /// are resolved against the directory of the link.
+    pub(super) fn resolve_symlinks(mut path: PathBuf) -> Result<PathBuf, mac::Error> {
+        let mut depth = 0;
+        while path.is_symlink() {
+            if depth >= MAX_SYMLINK_RECURSION_DEPTH {
+                return Err(mac::Error::SymlinkDepth(path));
+            }
+            let target = path.read_link()?;
+            path = match path.parent() {
+                Some(parent) => parent.join(target),
+                None => target,
+            };
+            depth += 1;
+        }
+        Ok(path)
+    }
+
     impl TryFrom<mac::Item> for TransferableItem {
         type Error = mac::Error;
         fn try_from(value: mac::Item) -> Result<Self, Self::Error> {
@@ -78,24 +129,22 @@ mod mac_item {
                 mac::Item::Text(text) => Ok(Self::Text {
                     text: text.to_string(),
                 }),
+                mac::Item::Url(url) => Ok(Self::Url {
+                    url: url.to_string(),
+                }),
+                mac::Item::RichText { html, rtf, plain } => Ok(Self::RichText {
+                    html: html.map(|html| html.to_string()),
+                    rtf: rtf.map(|rtf| rtf.bytes().to_vec()),
+                    plain: plain.to_string(),
+                }),
                 mac::Item::FileUrl(url) => {
-                    let path: PathBuf = url.to_string().into();
-
-                    let mut depth = 0;
-                    while path.is_symlink() && depth < MAX_SYMLINK_RECURSION_DEPTH {
-                        path.read_link().expect("Error reading symlink at: {path}");
-                        depth += 1;
-                    }
-
-                    if depth >= MAX_SYMLINK_RECURSION_DEPTH {
-                        panic!("Maximum depth reached while reading symlink")
-                    }
+                    let path = resolve_symlinks(url.to_string().into())?;
 
                     if path.is_dir() {
-                        unimplemented!();
+                        Ok(Self::read_folder(&path)?)
                     } else if path.is_file() {
                         let file_name = path.file_name().unwrap().to_os_string();
-                        let file_data = std::fs::read(path).unwrap();
+                        let file_data = std::fs::read(path)?;
                         Ok(Self::File {
                             file_name,
                             data: NSData::from_vec(file_data).into(),
@@ -104,6 +153,11 @@ mod mac_item {
                         Err(Self::Error::UnsupportedType)
                     }
                 }
+                mac::Item::Raw(data, type_id) => Ok(Self::Raw {
+                    type_id: type_id.to_string(),
+                    data: data.bytes().to_vec(),
+                }),
+                mac::Item::Concealed => Err(Self::Error::Concealed),
                 mac::Item::Unsupported() => Err(Self::Error::UnsupportedType),
             }
         }
@@ -115,40 +169,711 @@ type Data = mac_item::RetainedDataWrapper;
 #[cfg(not(target_os = "macos"))]
 type Data = Vec<u8>;
 
-#[derive(Debug, Serialize, Deserialize, Hash, PartialEq, Clone)]
+#[cfg(target_os = "macos")]
+fn data_from_vec(bytes: Vec<u8>) -> Data {
+    objc2_foundation::NSData::from_vec(bytes).into()
+}
+
+#[cfg(not(target_os = "macos"))]
+fn data_from_vec(bytes: Vec<u8>) -> Data {
+    bytes
+}
+
+/// Serializes byte buffers as base64 strings in human readable formats like JSON, where they
+/// would otherwise be arrays of numbers, and as plain bytes in binary formats like bincode.
+pub(crate) mod base64_bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::de::{self, Visitor};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        bytes: &impl AsRef<[u8]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD.encode(bytes.as_ref()))
+        } else {
+            serializer.serialize_bytes(bytes.as_ref())
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: From<Vec<u8>>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            STANDARD
+                .decode(encoded)
+                .map(T::from)
+                .map_err(de::Error::custom)
+        } else {
+            deserializer.deserialize_byte_buf(BytesVisitor).map(T::from)
+        }
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("bytes")
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+
+    /// Same for optional buffers.
+    pub mod option {
+        use super::*;
+
+        #[derive(Serialize)]
+        struct Borrowed<'a>(#[serde(with = "super")] &'a Vec<u8>);
+
+        #[derive(Deserialize)]
+        struct Owned(#[serde(with = "super")] Vec<u8>);
+
+        pub fn serialize<S: Serializer>(
+            bytes: &Option<Vec<u8>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            bytes.as_ref().map(Borrowed).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Vec<u8>>, D::Error> {
+            Ok(Option::<Owned>::deserialize(deserializer)?.map(|bytes| bytes.0))
+        }
+    }
+}
+
+/// Serializes an item in binary formats as its own bincode encoding behind a length prefix, so a
+/// receiver that doesn't know the item's variant can still skip exactly its bytes: it gets a
+/// [`TransferableItem::Unknown`] holding them, which is serialized back unchanged. Human readable
+/// formats name the variant anyway and get the item as is.
+pub(crate) mod enveloped {
+    use super::TransferableItem;
+    use log::debug;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        item: &TransferableItem,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return item.serialize(serializer);
+        }
+        match item {
+            TransferableItem::Unknown { encoded } => serializer.serialize_bytes(encoded),
+            item => {
+                let encoded = bincode::serialize(item).map_err(serde::ser::Error::custom)?;
+                serializer.serialize_bytes(&encoded)
+            }
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<TransferableItem, D::Error> {
+        if deserializer.is_human_readable() {
+            return TransferableItem::deserialize(deserializer);
+        }
+        let encoded: Vec<u8> = super::base64_bytes::deserialize(deserializer)?;
+        Ok(bincode::deserialize(&encoded).unwrap_or_else(|err| {
+            debug!("Keeping an item this version can't decode: {err}");
+            TransferableItem::Unknown { encoded }
+        }))
+    }
+
+    /// The items of a [`TransferableItem::Multiple`], each enveloped, so one of an unknown kind
+    /// doesn't make the others undecodable.
+    pub mod items {
+        use super::TransferableItem;
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        struct Enveloped<T>(T);
+
+        impl Serialize for Enveloped<&TransferableItem> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                super::serialize(self.0, serializer)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for Enveloped<TransferableItem> {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                super::deserialize(deserializer).map(Enveloped)
+            }
+        }
+
+        pub fn serialize<S: Serializer>(
+            items: &[TransferableItem],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(items.iter().map(Enveloped))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<TransferableItem>, D::Error> {
+            let items = Vec::<Enveloped<TransferableItem>>::deserialize(deserializer)?;
+            Ok(items.into_iter().map(|item| item.0).collect())
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Hash, PartialEq, Eq, Clone)]
 pub enum TransferableItem {
     File {
         file_name: OsString,
+        #[serde(with = "base64_bytes")]
         data: Data,
     },
     Text {
         text: String,
     },
-    // Put this struct at last, because of this bug: https://github.com/bincode-org/bincode/issues/184
-    #[serde(skip)]
+    /// Clipboard data of a type clipper doesn't understand, kept under its platform specific
+    /// type identifier (a UTI on macOS) so a peer of the same platform can write it back as is.
+    Raw {
+        type_id: String,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// A directory tree. Kept after the older variants so adding it didn't renumber the ones
+    /// older peers know, as are the variants below.
     Folder {
-        // dir: ReadDir,
+        name: OsString,
+        entries: Vec<FolderEntry>,
+    },
+    /// A bitmap as 8 bit RGBA pixels, row by row, so it arrives exactly as copied.
+    Image {
+        width: usize,
+        height: usize,
+        #[serde(with = "base64_bytes")]
+        bytes: Vec<u8>,
+    },
+    /// Styled text in the representations the source offered, plus plain text for receivers
+    /// that can't write either.
+    RichText {
+        html: Option<String>,
+        #[serde(with = "base64_bytes::option")]
+        rtf: Option<Vec<u8>>,
+        plain: String,
+    },
+    /// Several items copied at once, like files selected together in Finder. Never nested.
+    Multiple {
+        #[serde(with = "enveloped::items")]
+        items: Vec<TransferableItem>,
+    },
+    /// A link, kept apart from text so it can be pasted as one. Platforms without a URL type
+    /// write it as text.
+    Url {
+        url: String,
+    },
+    /// An item of a kind added by a newer version, as the bincode encoding it arrived in, see
+    /// [`enveloped`]. Never written to a clipboard, only passed on as is.
+    Unknown {
+        #[serde(with = "base64_bytes")]
+        encoded: Vec<u8>,
+    },
+}
+
+/// One entry of a [`TransferableItem::Folder`]. Paths are relative to the folder and use `/` as
+/// separator whatever the platform, so a tree copied on Windows unpacks the same on macOS.
+#[derive(Debug, Serialize, Deserialize, Hash, PartialEq, Eq, Clone)]
+pub enum FolderEntry {
+    Dir {
+        path: String,
+    },
+    File {
+        path: String,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
     },
 }
 
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// `name` as a single plain path component, so joining it can't escape the directory it is
+/// joined to.
+fn plain_component(name: &OsStr) -> std::io::Result<&OsStr> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => Ok(name),
+        _ => Err(invalid_data(format!("Refusing to write to {name:?}"))),
+    }
+}
+
+/// Reduces a file name sent by a peer to its last component, dropping any `/` or `\\` separated
+/// directories and `..` in front of it. Absolute paths and names with nothing left are refused
+/// rather than guessed at.
+fn safe_file_name(name: &OsStr) -> std::io::Result<OsString> {
+    let lossy = name.to_string_lossy();
+    let absolute = Path::new(name).has_root()
+        || lossy.starts_with(['/', '\\'])
+        || lossy.as_bytes().get(1) == Some(&b':');
+    let base = lossy
+        .split(['/', '\\'])
+        .rfind(|component| !matches!(*component, "" | "." | ".."));
+    match base {
+        Some(base) if !absolute => Ok(OsString::from(base)),
+        _ => Err(invalid_data(format!("Refusing to write to {name:?}"))),
+    }
+}
+
+/// Resolves the `/` separated `path` of a [`FolderEntry`] under `dir`, rejecting absolute paths
+/// and `..` so a peer can't write outside of it.
+fn entry_path(dir: &Path, path: &str) -> std::io::Result<PathBuf> {
+    path.split('/')
+        .try_fold(dir.to_path_buf(), |dir, component| {
+            Ok(dir.join(plain_component(OsStr::new(component))?))
+        })
+}
+
+/// Collects every file and directory below `dir` into `entries`, `prefix` being the relative
+/// path of `dir` itself. Only fails when `dir` itself can't be listed: entries that can't be
+/// read are left out with a warning, so one of them doesn't keep the rest from being sent.
+///
+/// Symbolic links are left out too. A link can lead anywhere, e.g. to a key outside of the
+/// copied folder or back to one of its parents, so only what is really inside is sent.
+fn walk_dir(dir: &Path, prefix: &str, entries: &mut Vec<FolderEntry>) -> std::io::Result<()> {
+    let mut children: Vec<_> = std::fs::read_dir(dir)?
+        .filter_map(|child| {
+            child
+                .inspect_err(|err| log::warn!("Skipping an entry of {}: {err}", dir.display()))
+                .ok()
+        })
+        .collect();
+    children.sort_by_key(|child| child.file_name());
+    for child in children {
+        let Ok(name) = child.file_name().into_string() else {
+            log::warn!(
+                "Skipping {}, its name is not valid UTF-8",
+                child.path().display()
+            );
+            continue;
+        };
+        let path = if prefix.is_empty() {
+            name
+        } else {
+            format!("{prefix}/{name}")
+        };
+        let file_type = match child.file_type() {
+            Ok(file_type) => file_type,
+            Err(err) => {
+                log::warn!("Skipping {}: {err}", child.path().display());
+                continue;
+            }
+        };
+        if file_type.is_symlink() {
+            log::debug!("Skipping {}, a symbolic link", child.path().display());
+        } else if file_type.is_dir() {
+            let mut below = vec![FolderEntry::Dir { path: path.clone() }];
+            match walk_dir(&child.path(), &path, &mut below) {
+                Ok(()) => entries.append(&mut below),
+                Err(err) => log::warn!("Skipping {}: {err}", child.path().display()),
+            }
+        } else if !file_type.is_file() {
+            // Reading a pipe would wait for a writer, and sockets or devices aren't content
+            log::debug!("Skipping {}, not a regular file", child.path().display());
+        } else {
+            match std::fs::read(child.path()) {
+                Ok(data) => entries.push(FolderEntry::File { path, data }),
+                Err(err) => log::warn!("Skipping {}: {err}", child.path().display()),
+            }
+        }
+    }
+    Ok(())
+}
+
 impl TransferableItem {
-    pub fn write_to_dir<P: AsRef<Path>>(&self, dir: P) -> () {
+    /// Short name of the variant, e.g. for logs and metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::File { .. } => "file",
+            Self::Text { .. } => "text",
+            Self::Raw { .. } => "raw",
+            Self::Folder { .. } => "folder",
+            Self::Image { .. } => "image",
+            Self::RichText { .. } => "rich text",
+            Self::Multiple { .. } => "multiple",
+            Self::Url { .. } => "url",
+            Self::Unknown { .. } => "unknown",
+        }
+    }
+
+    /// Hash of the item's content, the same for items that are `==`. Only meant for comparing
+    /// items within one process, it isn't stable across builds.
+    pub fn content_hash(&self) -> u64 {
+        use std::hash::{Hash, Hasher};
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Number of payload bytes the item carries: file contents, text, pixels, or the sum over
+    /// every file of a folder. Names and other metadata aren't counted.
+    pub fn size_bytes(&self) -> usize {
+        match self {
+            Self::File { data, .. } => data.len(),
+            Self::Text { text } => text.len(),
+            Self::Raw { data, .. } => data.len(),
+            Self::Folder { entries, .. } => entries
+                .iter()
+                .map(|entry| match entry {
+                    FolderEntry::Dir { .. } => 0,
+                    FolderEntry::File { data, .. } => data.len(),
+                })
+                .sum(),
+            Self::Image { bytes, .. } => bytes.len(),
+            Self::RichText { html, rtf, plain } => {
+                html.as_ref().map_or(0, String::len)
+                    + rtf.as_ref().map_or(0, Vec::len)
+                    + plain.len()
+            }
+            Self::Multiple { items } => items.iter().map(Self::size_bytes).sum(),
+            Self::Url { url } => url.len(),
+            Self::Unknown { encoded } => encoded.len(),
+        }
+    }
+
+    /// A [`TransferableItem::File`] called `name` holding `bytes`, on every platform whatever
+    /// its data is stored in.
+    pub fn file(name: impl Into<OsString>, bytes: Vec<u8>) -> Self {
+        Self::File {
+            file_name: name.into(),
+            data: data_from_vec(bytes),
+        }
+    }
+
+    /// Reads the file at `path` into a [`TransferableItem::File`] named after its last component.
+    pub fn read_file<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let file_name = path.file_name().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Path has no file name")
+        })?;
+        Ok(Self::file(file_name, std::fs::read(path)?))
+    }
+
+    /// Reads the directory at `path` and everything below it into a
+    /// [`TransferableItem::Folder`]. Symbolic links and entries that can't be read are left out.
+    pub fn read_folder<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let name = path.file_name().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Path has no file name")
+        })?;
+        let mut entries = Vec::new();
+        walk_dir(path, "", &mut entries)?;
+        Ok(Self::Folder {
+            name: name.to_os_string(),
+            entries,
+        })
+    }
+
+    /// Writes the item into `dir` and returns the path written. Text is written to
+    /// `text_file_name`, or to a fresh timestamped `clip-<millis>.txt` when no name is given so
+    /// successive clips don't overwrite each other, and images always go to a fresh
+    /// `image-<width>x<height>-<millis>.rgba`. Files and folders keep the name they were sent
+    /// with, plus ` (2)`, ` (3)`, ... when it is taken, so nothing already in `dir` is replaced.
+    /// [`TransferableItem::Multiple`] writes each of its items, text to fresh files, and returns
+    /// `dir`.
+    pub fn write_to_dir<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        text_file_name: Option<&OsStr>,
+    ) -> std::io::Result<PathBuf> {
+        let write = |path: PathBuf, data: &[u8]| std::fs::write(&path, data).map(|()| path);
         match self {
             Self::File { file_name, data } => {
-                std::fs::write(dir.as_ref().join(file_name), data).unwrap()
+                create_new_file(dir.as_ref(), &safe_file_name(file_name)?, data)
+            }
+            Self::Folder { name, entries } => {
+                let (root, ()) =
+                    create_unique(dir.as_ref(), plain_component(name)?, false, |path| {
+                        std::fs::create_dir(path)
+                    })?;
+                for entry in entries {
+                    match entry {
+                        FolderEntry::Dir { path } => {
+                            std::fs::create_dir_all(entry_path(&root, path)?)?
+                        }
+                        FolderEntry::File { path, data } => {
+                            let path = entry_path(&root, path)?;
+                            if let Some(parent) = path.parent() {
+                                std::fs::create_dir_all(parent)?;
+                            }
+                            std::fs::write(path, data)?
+                        }
+                    }
+                }
+                Ok(root)
+            }
+            Self::Raw { type_id, data } => {
+                create_new_file(dir.as_ref(), &safe_file_name(OsStr::new(type_id))?, data)
+            }
+            Self::Image {
+                width,
+                height,
+                bytes,
+            } => write_new_file(
+                dir.as_ref(),
+                &format!("image-{width}x{height}"),
+                "rgba",
+                bytes,
+            ),
+            Self::Text { text } | Self::RichText { plain: text, .. } | Self::Url { url: text } => {
+                match text_file_name {
+                    Some(name) => write(dir.as_ref().join(name), text.as_bytes()),
+                    None => write_new_file(dir.as_ref(), "clip", "txt", text.as_bytes()),
+                }
             }
-            Self::Folder { .. } => unimplemented!(),
-            Self::Text { text } => std::fs::write(dir.as_ref().join("out.txt"), text).unwrap(),
+            Self::Multiple { items } => {
+                for item in items {
+                    item.write_to_dir(dir.as_ref(), None)?;
+                }
+                Ok(dir.as_ref().to_path_buf())
+            }
+            Self::Unknown { .. } => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Item of a kind this version doesn't know",
+            )),
         }
     }
 }
 
+/// Writes `data` to a new file called `name` in `dir`, see [`create_unique`].
+fn create_new_file(dir: &Path, name: &OsStr, data: &[u8]) -> std::io::Result<PathBuf> {
+    use std::io::Write;
+
+    let create = |path: &Path| {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+    };
+    let (path, mut file) = create_unique(dir, name, true, create)?;
+    file.write_all(data)?;
+    Ok(path)
+}
+
+/// Creates the entry `name` in `dir` with `create`, or `name (2)`, `name (3)`, ... while
+/// `create` finds the name taken, like file managers do. Files get the suffix before their
+/// extension. Returns the path created along with what `create` returned.
+fn create_unique<T>(
+    dir: &Path,
+    name: &OsStr,
+    is_file: bool,
+    create: impl Fn(&Path) -> std::io::Result<T>,
+) -> std::io::Result<(PathBuf, T)> {
+    let name = Path::new(name);
+    let (stem, extension) = match (name.file_stem(), name.extension()) {
+        (Some(stem), Some(extension)) if is_file => (stem, Some(extension)),
+        _ => (name.as_os_str(), None),
+    };
+    for n in 1u32.. {
+        let mut candidate = stem.to_os_string();
+        if n > 1 {
+            candidate.push(format!(" ({n})"));
+        }
+        if let Some(extension) = extension {
+            candidate.push(".");
+            candidate.push(extension);
+        }
+        let path = dir.join(candidate);
+        match create(&path) {
+            Ok(created) => return Ok((path, created)),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    unreachable!("Ran out of file names")
+}
+
+/// Writes `data` to `<prefix>-<millis>.<extension>` in `dir`, adding a `-<n>` suffix until the
+/// name is unused. The file is created with `create_new`, so a concurrent writer can't take it in between.
+fn write_new_file(
+    dir: &Path,
+    prefix: &str,
+    extension: &str,
+    data: &[u8],
+) -> std::io::Result<PathBuf> {
+    use std::io::Write;
+
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    for n in 0u32.. {
+        let path = match n {
+            0 => dir.join(format!("{prefix}-{millis}.{extension}")),
+            n => dir.join(format!("{prefix}-{millis}-{n}.{extension}")),
+        };
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(mut file) => return file.write_all(data).map(|()| path),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    unreachable!("Ran out of file names")
+}
+
+/// Line-ending policy applied to received text before it is written to the clipboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LineEndings {
+    #[default]
+    Preserve,
+    Lf,
+    Crlf,
+    /// Whatever the receiving platform uses.
+    Native,
+}
+
+impl LineEndings {
+    pub fn apply(self, text: String) -> String {
+        match self {
+            Self::Preserve => text,
+            Self::Lf => text.replace("\r\n", "\n"),
+            Self::Crlf => Self::Lf.apply(text).replace('\n', "\r\n"),
+            Self::Native if cfg!(target_os = "windows") => Self::Crlf.apply(text),
+            Self::Native => Self::Lf.apply(text),
+        }
+    }
+}
+
+/// Which copies a sender keeps to itself, e.g. to never sync files or anything but text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentFilter {
+    pub no_files: bool,
+    pub no_images: bool,
+    /// Only text, rich text and links are sent
+    pub text_only: bool,
+    /// Items with more payload bytes than this aren't sent
+    pub max_size: u64,
+}
+
+impl Default for ContentFilter {
+    fn default() -> Self {
+        Self {
+            no_files: false,
+            no_images: false,
+            text_only: false,
+            max_size: u64::MAX,
+        }
+    }
+}
+
+impl ContentFilter {
+    /// Why `item` must not be sent, or `None` when it may. The items of a
+    /// [`TransferableItem::Multiple`] are checked one by one and their sizes added up.
+    pub fn rejects(&self, item: &TransferableItem) -> Option<String> {
+        let size = item.size_bytes() as u64;
+        if size > self.max_size {
+            return Some(format!(
+                "{size} bytes is over the {} byte limit",
+                self.max_size
+            ));
+        }
+        match item {
+            TransferableItem::Text { .. }
+            | TransferableItem::RichText { .. }
+            | TransferableItem::Url { .. } => None,
+            // Several links or snippets copied together are still text
+            TransferableItem::Multiple { items } => {
+                items.iter().find_map(|item| self.rejects(item))
+            }
+            _ if self.text_only => Some(format!("only text is sent, not {}", item.kind())),
+            TransferableItem::File { .. } | TransferableItem::Folder { .. } if self.no_files => {
+                Some(format!("{}s aren't sent", item.kind()))
+            }
+            TransferableItem::Image { .. } if self.no_images => Some("images aren't sent".into()),
+            _ => None,
+        }
+    }
+}
+
+impl ContentFilter {
+    /// Why the file or folder at `path` must not be sent, like [`ContentFilter::rejects`] but
+    /// told from its metadata, so a large copy that is dropped anyway isn't read first. A folder's
+    /// size adds up the files [`TransferableItem::read_folder`] would read.
+    pub fn rejects_path(&self, path: &Path) -> Option<String> {
+        let kind = if path.is_dir() { "folder" } else { "file" };
+        if self.text_only {
+            return Some(format!("only text is sent, not {kind}"));
+        }
+        if self.no_files {
+            return Some(format!("{kind}s aren't sent"));
+        }
+        let size = if path.is_dir() {
+            dir_size(path, self.max_size)
+        } else {
+            std::fs::metadata(path).map_or(0, |metadata| metadata.len())
+        };
+        (size > self.max_size)
+            .then(|| format!("{size} bytes is over the {} byte limit", self.max_size))
+    }
+}
+
+/// Bytes of the regular files below `dir`, not following links, counted until they exceed
+/// `limit`.
+fn dir_size(dir: &Path, limit: u64) -> u64 {
+    let Ok(children) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    let mut size = 0u64;
+    for child in children.flatten() {
+        let Ok(file_type) = child.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            size = size.saturating_add(dir_size(&child.path(), limit - size.min(limit)));
+        } else if file_type.is_file() {
+            size = size.saturating_add(child.metadata().map_or(0, |metadata| metadata.len()));
+        }
+        if size > limit {
+            break;
+        }
+    }
+    size
+}
+
 impl From<String> for TransferableItem {
     fn from(value: String) -> Self {
         TransferableItem::Text { text: value }
     }
 }
 
+impl From<arboard::ImageData<'_>> for TransferableItem {
+    fn from(value: arboard::ImageData<'_>) -> Self {
+        Self::Image {
+            width: value.width,
+            height: value.height,
+            bytes: value.bytes.into_owned(),
+        }
+    }
+}
+
 impl fmt::Display for TransferableItem {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -156,7 +881,62 @@ impl fmt::Display for TransferableItem {
                 write!(f, "File name: {:?}; File size: {}", file_name, data.len())
             }
             Self::Text { text } => write!(f, "Text: {text}"),
-            Self::Folder { .. } => write!(f, "DIR"),
+            Self::RichText { plain, .. } => write!(f, "Rich text: {plain}"),
+            Self::Multiple { items } => write!(f, "Multiple items: {}", items.len()),
+            Self::Url { url } => write!(f, "Url: {url}"),
+            Self::Raw { type_id, data } => {
+                write!(f, "Raw type: {type_id}; Size: {}", data.len())
+            }
+            Self::Image { width, height, .. } => write!(f, "Image: {width}x{height}"),
+            Self::Folder { name, entries } => {
+                write!(f, "Folder name: {:?}; Entries: {}", name, entries.len())
+            }
+            Self::Unknown { encoded } => write!(f, "Unknown item; Size: {}", encoded.len()),
+        }
+    }
+}
+
+static LOG_CONTENT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Sets whether [`Redacted`] shows clipboard content in full, for debugging.
+pub fn set_log_content(enabled: bool) {
+    LOG_CONTENT.store(enabled, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Shows an item or package like its `Display`, but for logs: text, rich text and URLs, which may
+/// be passwords or tokens, are replaced by their length and a hash prefix, so log files don't
+/// collect what was copied while the same clip can still be followed through them. Shows
+/// everything once [`set_log_content`] enabled it.
+pub struct Redacted<'a, T>(pub &'a T);
+
+impl TransferableItem {
+    pub fn redacted(&self) -> Redacted<'_, Self> {
+        Redacted(self)
+    }
+}
+
+/// `text`'s length and the start of its hash. The hasher is keyed at random once per process,
+/// so a clip can be followed through one run's logs, but short secrets like PINs can't be found
+/// by hashing every candidate.
+fn hidden(text: &str) -> String {
+    use std::hash::BuildHasher;
+
+    static KEY: std::sync::OnceLock<std::collections::hash_map::RandomState> =
+        std::sync::OnceLock::new();
+    let hash = KEY.get_or_init(Default::default).hash_one(text);
+    format!("<{} bytes, {:08x}>", text.len(), hash >> 32)
+}
+
+impl fmt::Display for Redacted<'_, TransferableItem> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if LOG_CONTENT.load(std::sync::atomic::Ordering::Relaxed) {
+            return write!(f, "{}", self.0);
+        }
+        match self.0 {
+            TransferableItem::Text { text } => write!(f, "Text: {}", hidden(text)),
+            TransferableItem::RichText { plain, .. } => write!(f, "Rich text: {}", hidden(plain)),
+            TransferableItem::Url { url } => write!(f, "Url: {}", hidden(url)),
+            item => write!(f, "{item}"),
         }
     }
 }
@@ -165,6 +945,532 @@ impl fmt::Display for TransferableItem {
 mod tests {
     use super::*;
 
+    #[test]
+    fn hidden_is_keyed_test() {
+        use std::hash::{Hash, Hasher};
+
+        // What an unkeyed hasher gives, which anyone could compute for every 4 digit PIN
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        "1234".hash(&mut hasher);
+        let unkeyed = format!("<4 bytes, {:08x}>", hasher.finish() >> 32);
+
+        assert_ne!(hidden("1234"), unkeyed);
+        assert_eq!(hidden("1234"), hidden("1234"));
+    }
+
+    #[test]
+    fn content_filter_size_test() {
+        let filter = ContentFilter {
+            max_size: 4,
+            ..ContentFilter::default()
+        };
+        let file = |len| TransferableItem::file("data.bin", vec![0; len]);
+        assert_eq!(filter.rejects(&file(4)), None);
+        assert!(filter.rejects(&file(5)).is_some());
+        // Several small items can add up to too much
+        let items = vec![file(3), TransferableItem::from("ab".to_string())];
+        assert!(filter
+            .rejects(&TransferableItem::Multiple { items })
+            .is_some());
+    }
+
+    #[test]
+    fn content_filter_kind_test() {
+        let file = TransferableItem::file("notes.txt", b"notes".to_vec());
+        let folder = TransferableItem::Folder {
+            name: "photos".into(),
+            entries: vec![],
+        };
+        let image = TransferableItem::Image {
+            width: 1,
+            height: 1,
+            bytes: vec![0; 4],
+        };
+        let text = TransferableItem::from("Hello".to_string());
+        let rich = TransferableItem::RichText {
+            html: Some("<b>Hello</b>".to_string()),
+            rtf: None,
+            plain: "Hello".to_string(),
+        };
+
+        let no_files = ContentFilter {
+            no_files: true,
+            ..ContentFilter::default()
+        };
+        assert!(no_files.rejects(&file).is_some());
+        assert!(no_files.rejects(&folder).is_some());
+        assert_eq!(no_files.rejects(&image), None);
+        let both = vec![text.clone(), file.clone()];
+        assert!(no_files
+            .rejects(&TransferableItem::Multiple { items: both })
+            .is_some());
+
+        let no_images = ContentFilter {
+            no_images: true,
+            ..ContentFilter::default()
+        };
+        assert!(no_images.rejects(&image).is_some());
+        assert_eq!(no_images.rejects(&file), None);
+
+        let text_only = ContentFilter {
+            text_only: true,
+            ..ContentFilter::default()
+        };
+        assert!(text_only.rejects(&image).is_some());
+        assert!(text_only.rejects(&file).is_some());
+        assert_eq!(text_only.rejects(&text), None);
+        assert_eq!(text_only.rejects(&rich), None);
+        let url = TransferableItem::Url {
+            url: "https://example.com".to_string(),
+        };
+        assert_eq!(text_only.rejects(&url), None);
+        let links = vec![url.clone(), text.clone()];
+        assert_eq!(
+            text_only.rejects(&TransferableItem::Multiple { items: links }),
+            None
+        );
+        let mixed = vec![url.clone(), image.clone()];
+        assert!(text_only
+            .rejects(&TransferableItem::Multiple { items: mixed })
+            .is_some());
+
+        for item in [&file, &folder, &image, &text, &rich, &url] {
+            assert_eq!(ContentFilter::default().rejects(item), None);
+        }
+    }
+
+    #[test]
+    fn file_constructor_test() {
+        let item = TransferableItem::file("report.pdf", b"%PDF".to_vec());
+        let TransferableItem::File { file_name, data } = &item else {
+            panic!("Expected a file, got {item}");
+        };
+        assert_eq!(file_name, "report.pdf");
+        assert_eq!(AsRef::<[u8]>::as_ref(data), b"%PDF");
+
+        let bincode_item: TransferableItem =
+            bincode::deserialize(&bincode::serialize(&item).unwrap()).unwrap();
+        assert_eq!(bincode_item, item);
+        let json = serde_json::to_string(&item).unwrap();
+        // Bytes travel as base64 in JSON
+        assert!(json.contains("JVBERg=="), "{json}");
+        let json_item: TransferableItem = serde_json::from_str(&json).unwrap();
+        assert_eq!(json_item, item);
+    }
+
+    #[test]
+    fn line_endings_test() {
+        let mixed = "a\r\nb\nc".to_string();
+        assert_eq!(LineEndings::Preserve.apply(mixed.clone()), "a\r\nb\nc");
+        assert_eq!(LineEndings::Lf.apply(mixed.clone()), "a\nb\nc");
+        assert_eq!(LineEndings::Crlf.apply(mixed.clone()), "a\r\nb\r\nc");
+        let native = LineEndings::Native.apply(mixed);
+        if cfg!(target_os = "windows") {
+            assert_eq!(native, "a\r\nb\r\nc");
+        } else {
+            assert_eq!(native, "a\nb\nc");
+        }
+    }
+
+    #[test]
+    fn write_text_to_dir_test() {
+        let dir = std::env::temp_dir().join(format!("clipper-write-text-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let item = TransferableItem::from("Hello".to_string());
+        let path = item
+            .write_to_dir(&dir, Some(OsStr::new("hello.txt")))
+            .unwrap();
+        assert_eq!(path, dir.join("hello.txt"));
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "Hello");
+
+        let path = item.write_to_dir(&dir, None).unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "Hello");
+        let written: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("clip-") && name.ends_with(".txt"))
+            .collect();
+        assert_eq!(written.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_text_to_dir_unique_names_test() {
+        let dir = std::env::temp_dir().join(format!("clipper-unique-text-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Written back to back, so usually within the same millisecond
+        let first = TransferableItem::from("first".to_string())
+            .write_to_dir(&dir, None)
+            .unwrap();
+        let second = TransferableItem::from("second".to_string())
+            .write_to_dir(&dir, None)
+            .unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(std::fs::read_to_string(first).unwrap(), "first");
+        assert_eq!(std::fs::read_to_string(second).unwrap(), "second");
+
+        let image = TransferableItem::Image {
+            width: 1,
+            height: 1,
+            bytes: vec![0, 0, 0, 255],
+        };
+        let first = image.write_to_dir(&dir, None).unwrap();
+        let second = image.write_to_dir(&dir, None).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(std::fs::read(second).unwrap(), [0, 0, 0, 255]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn multiple_with_unknown_item_test() {
+        // An item kind a newer version added, next to one this version knows
+        let future = vec![99, 0, 0, 0, 1, 2, 3];
+        let multiple = TransferableItem::Multiple {
+            items: vec![
+                TransferableItem::Unknown {
+                    encoded: future.clone(),
+                },
+                TransferableItem::from("Hello".to_string()),
+            ],
+        };
+        let encoded = bincode::serialize(&multiple).unwrap();
+        // Each item is its own bincode encoding behind a length prefix, the unknown one exactly
+        // as it arrived
+        let text = bincode::serialize(&TransferableItem::from("Hello".to_string())).unwrap();
+        let mut expected = encoded[..4].to_vec();
+        expected.extend(2u64.to_le_bytes());
+        for item in [&future, &text] {
+            expected.extend((item.len() as u64).to_le_bytes());
+            expected.extend(item);
+        }
+        assert_eq!(encoded, expected);
+        let decoded: TransferableItem = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(decoded, multiple);
+        // Passed on exactly as received
+        assert_eq!(bincode::serialize(&decoded).unwrap(), encoded);
+
+        let json = serde_json::to_string(&multiple).unwrap();
+        assert_eq!(
+            serde_json::from_str::<TransferableItem>(&json).unwrap(),
+            multiple
+        );
+    }
+
+    #[test]
+    fn folder_round_trip_test() {
+        let dir = std::env::temp_dir().join(format!("clipper-folder-{}", std::process::id()));
+        let source = dir.join("project");
+        std::fs::create_dir_all(source.join("src/empty")).unwrap();
+        std::fs::write(source.join("README"), "Hello").unwrap();
+        std::fs::write(source.join("src/main.rs"), "fn main() {}").unwrap();
+
+        let item = TransferableItem::read_folder(&source).unwrap();
+        let item: TransferableItem =
+            bincode::deserialize(&bincode::serialize(&item).unwrap()).unwrap();
+        let target = dir.join("target");
+        std::fs::create_dir_all(&target).unwrap();
+        item.write_to_dir(&target, None).unwrap();
+
+        let copy = target.join("project");
+        assert_eq!(
+            std::fs::read_to_string(copy.join("README")).unwrap(),
+            "Hello"
+        );
+        assert_eq!(
+            std::fs::read_to_string(copy.join("src/main.rs")).unwrap(),
+            "fn main() {}"
+        );
+        assert!(copy.join("src/empty").is_dir());
+        assert_eq!(
+            std::fs::read_dir(copy.join("src/empty")).unwrap().count(),
+            0
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn folder_symlink_cycle_test() {
+        let dir = std::env::temp_dir().join(format!("clipper-cycle-{}", std::process::id()));
+        let source = dir.join("project");
+        std::fs::create_dir_all(source.join("src")).unwrap();
+        std::fs::write(source.join("src/main.rs"), "fn main() {}").unwrap();
+        std::os::unix::fs::symlink(&source, source.join("src/parent")).unwrap();
+
+        let TransferableItem::Folder { entries, .. } =
+            TransferableItem::read_folder(&source).unwrap()
+        else {
+            panic!("Not a folder");
+        };
+        assert_eq!(
+            entries,
+            vec![
+                FolderEntry::Dir {
+                    path: "src".to_string()
+                },
+                FolderEntry::File {
+                    path: "src/main.rs".to_string(),
+                    data: b"fn main() {}".to_vec()
+                },
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn folder_file_symlinks_test() {
+        let dir = std::env::temp_dir().join(format!("clipper-links-{}", std::process::id()));
+        let source = dir.join("project");
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(dir.join("id_rsa"), "secret").unwrap();
+        std::fs::write(source.join("README"), "Hello").unwrap();
+        // One leading out of the folder, one leading nowhere
+        std::os::unix::fs::symlink(dir.join("id_rsa"), source.join("key")).unwrap();
+        std::os::unix::fs::symlink(dir.join("missing"), source.join("dangling")).unwrap();
+
+        let TransferableItem::Folder { entries, .. } =
+            TransferableItem::read_folder(&source).unwrap()
+        else {
+            panic!("Not a folder");
+        };
+        assert_eq!(
+            entries,
+            vec![FolderEntry::File {
+                path: "README".to_string(),
+                data: b"Hello".to_vec()
+            }]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn folder_unreadable_entry_test() {
+        let dir = std::env::temp_dir().join(format!("clipper-unreadable-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("README"), "Hello").unwrap();
+        // Opening a socket fails, and a pipe would never be done reading
+        let _socket = std::os::unix::net::UnixListener::bind(dir.join("socket")).unwrap();
+        let fifo = std::ffi::CString::new(dir.join("fifo").to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+
+        let TransferableItem::Folder { entries, .. } = TransferableItem::read_folder(&dir).unwrap()
+        else {
+            panic!("Not a folder");
+        };
+        assert_eq!(
+            entries,
+            vec![FolderEntry::File {
+                path: "README".to_string(),
+                data: b"Hello".to_vec()
+            }]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn folder_path_traversal_test() {
+        let dir = std::env::temp_dir().join(format!("clipper-traversal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let folder = |name: &str, path: &str| TransferableItem::Folder {
+            name: OsString::from(name),
+            entries: vec![FolderEntry::File {
+                path: path.to_string(),
+                data: b"x".to_vec(),
+            }],
+        };
+        for (name, path) in [
+            ("ok", "../escaped"),
+            ("ok", "a/../../escaped"),
+            ("ok", "/tmp/escaped"),
+            ("..", "escaped"),
+        ] {
+            let err = folder(name, path).write_to_dir(&dir, None).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{name}/{path}");
+        }
+        assert!(!dir.join("escaped").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn size_bytes_test() {
+        let cases = [
+            (TransferableItem::file("a.bin", vec![0; 10]), 10),
+            (TransferableItem::from("Hello".to_string()), 5),
+            (
+                TransferableItem::Raw {
+                    type_id: "public.data".to_string(),
+                    data: vec![0; 3],
+                },
+                3,
+            ),
+            (
+                TransferableItem::Folder {
+                    name: OsString::from("dir"),
+                    entries: vec![
+                        FolderEntry::Dir {
+                            path: "sub".to_string(),
+                        },
+                        FolderEntry::File {
+                            path: "a".to_string(),
+                            data: vec![0; 4],
+                        },
+                        FolderEntry::File {
+                            path: "sub/b".to_string(),
+                            data: vec![0; 6],
+                        },
+                    ],
+                },
+                10,
+            ),
+            (
+                TransferableItem::Image {
+                    width: 2,
+                    height: 3,
+                    bytes: vec![0; 24],
+                },
+                24,
+            ),
+            (
+                TransferableItem::RichText {
+                    html: Some("<b>Hi</b>".to_string()),
+                    rtf: Some(vec![0; 7]),
+                    plain: "Hi".to_string(),
+                },
+                18,
+            ),
+        ];
+        for (item, size) in cases {
+            assert_eq!(item.size_bytes(), size, "{item}");
+        }
+    }
+
+    #[test]
+    fn write_to_dir_keeps_existing_files_test() {
+        let dir = std::env::temp_dir().join(format!("clipper-existing-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("project")).unwrap();
+        std::fs::write(dir.join("notes.txt"), "mine").unwrap();
+        std::fs::write(dir.join("project/README"), "mine").unwrap();
+
+        let file = TransferableItem::file("notes.txt", b"theirs".to_vec());
+        assert_eq!(
+            file.write_to_dir(&dir, None).unwrap(),
+            dir.join("notes (2).txt")
+        );
+        assert_eq!(
+            file.write_to_dir(&dir, None).unwrap(),
+            dir.join("notes (3).txt")
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("notes.txt")).unwrap(),
+            "mine"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("notes (2).txt")).unwrap(),
+            "theirs"
+        );
+
+        let folder = TransferableItem::Folder {
+            name: "project".into(),
+            entries: vec![FolderEntry::File {
+                path: "README".to_string(),
+                data: b"theirs".to_vec(),
+            }],
+        };
+        let root = folder.write_to_dir(&dir, None).unwrap();
+        assert_eq!(root, dir.join("project (2)"));
+        assert_eq!(
+            std::fs::read_to_string(root.join("README")).unwrap(),
+            "theirs"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("project/README")).unwrap(),
+            "mine"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn content_filter_path_test() {
+        let dir = std::env::temp_dir().join(format!("clipper-filter-path-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("project/src")).unwrap();
+        std::fs::write(dir.join("project/README"), [0; 3]).unwrap();
+        std::fs::write(dir.join("project/src/main.rs"), [0; 3]).unwrap();
+        let file = dir.join("project/README");
+        let folder = dir.join("project");
+
+        let filter = ContentFilter::default();
+        assert_eq!(filter.rejects_path(&file), None);
+        assert_eq!(filter.rejects_path(&folder), None);
+
+        let filter = ContentFilter {
+            max_size: 5,
+            ..ContentFilter::default()
+        };
+        assert_eq!(filter.rejects_path(&file), None);
+        assert!(filter.rejects_path(&folder).is_some());
+
+        let filter = ContentFilter {
+            no_files: true,
+            ..ContentFilter::default()
+        };
+        assert!(filter.rejects_path(&file).is_some());
+        assert!(filter.rejects_path(&folder).is_some());
+        let filter = ContentFilter {
+            text_only: true,
+            ..ContentFilter::default()
+        };
+        assert!(filter.rejects_path(&file).is_some());
+
+        // The same as for the items read from them
+        for max_size in [5, 6] {
+            let filter = ContentFilter {
+                max_size,
+                ..ContentFilter::default()
+            };
+            let item = TransferableItem::read_folder(&folder).unwrap();
+            assert_eq!(filter.rejects_path(&folder), filter.rejects(&item));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn file_name_traversal_test() {
+        let dir = std::env::temp_dir().join(format!("clipper-file-name-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |file_name: &str| TransferableItem::file(file_name, b"x".to_vec());
+
+        for (file_name, expected) in [
+            ("../evil", "evil"),
+            ("..\\x", "x"),
+            ("a/../b\\..\\c.txt", "c.txt"),
+        ] {
+            let path = file(file_name).write_to_dir(&dir, None).unwrap();
+            assert_eq!(path, dir.join(expected), "{file_name}");
+            assert!(path.exists(), "{file_name}");
+        }
+        for file_name in ["/etc/passwd", "\\\\server\\share", "C:\\evil", "..", ""] {
+            let err = file(file_name).write_to_dir(&dir, None).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{file_name}");
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[cfg(target_os = "macos")]
     mod mac_test {
         use super::super::mac_item::RetainedDataWrapper;
@@ -181,6 +1487,33 @@ mod tests {
             println!("{}", json);
         }
 
+        #[test]
+        fn data_wrapper_mac_deserialize_json_test() {
+            let data_wrapper = RetainedDataWrapper::from(b"Hello World!".to_vec());
+            let json = serde_json::to_string(&data_wrapper).expect("Failed to serialize");
+            assert_eq!(json, "[72,101,108,108,111,32,87,111,114,108,100,33]");
+            let deserialized: RetainedDataWrapper =
+                serde_json::from_str(&json).expect("Failed to deserialize");
+            assert_eq!(data_wrapper, deserialized);
+
+            let empty: RetainedDataWrapper = serde_json::from_str("[]").unwrap();
+            assert_eq!(empty.len(), 0);
+            assert!(serde_json::from_str::<RetainedDataWrapper>("[256]").is_err());
+        }
+
+        #[test]
+        fn data_wrapper_mac_slice_test() {
+            let empty = RetainedDataWrapper::from(NSData::with_bytes(&[]));
+            assert!(empty.is_empty());
+            assert_eq!(empty.len(), 0);
+
+            let data_wrapper = RetainedDataWrapper::from(NSData::with_bytes(b"Hello World!"));
+            assert!(!data_wrapper.is_empty());
+            assert_eq!(&data_wrapper[..5], b"Hello");
+            assert_eq!(data_wrapper.last(), Some(&b'!'));
+            assert_eq!(*data_wrapper, *b"Hello World!".as_slice());
+        }
+
         #[test]
         fn data_wrapper_mac_serialize_bincode_test() {
             let text = b"Hello World!";
@@ -193,9 +1526,66 @@ mod tests {
             assert_eq!(data_wrapper, deserialized);
         }
 
+        #[test]
+        fn file_url_symlink_chain_test() {
+            let dir = std::env::temp_dir().join(format!("clipper-symlink-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("nested")).unwrap();
+            std::fs::write(dir.join("nested/target.txt"), "Hello").unwrap();
+            std::os::unix::fs::symlink("nested/target.txt", dir.join("first")).unwrap();
+            std::os::unix::fs::symlink(dir.join("first"), dir.join("second")).unwrap();
+
+            let url = NSString::from_str(dir.join("second").to_str().unwrap());
+            let item = TransferableItem::try_from(mac::Item::FileUrl(url)).unwrap();
+            let TransferableItem::File { file_name, data } = item else {
+                panic!("Expected a file");
+            };
+            assert_eq!(file_name, "target.txt");
+            assert_eq!(data.as_ref(), b"Hello");
+
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn file_url_symlink_loop_test() {
+            let dir = std::env::temp_dir().join(format!("clipper-loop-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::os::unix::fs::symlink(dir.join("self"), dir.join("self")).unwrap();
+
+            let url = NSString::from_str(dir.join("self").to_str().unwrap());
+            let result = TransferableItem::try_from(mac::Item::FileUrl(url));
+            assert!(matches!(result, Err(mac::Error::SymlinkDepth(_))));
+
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn file_url_directory_test() {
+            let dir = std::env::temp_dir().join(format!("clipper-dir-url-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("copied/nested")).unwrap();
+            std::fs::write(dir.join("copied/nested/a.txt"), "Hello").unwrap();
+
+            let url = NSString::from_str(dir.join("copied").to_str().unwrap());
+            let item = TransferableItem::try_from(mac::Item::FileUrl(url)).unwrap();
+            let TransferableItem::Folder { name, entries } = item else {
+                panic!("Expected a folder");
+            };
+            assert_eq!(name, "copied");
+            assert!(entries.contains(&FolderEntry::File {
+                path: "nested/a.txt".to_string(),
+                data: b"Hello".to_vec(),
+            }));
+
+            // Gone by the time it is read, neither a file nor a directory
+            let url = NSString::from_str(dir.join("missing").to_str().unwrap());
+            let result = TransferableItem::try_from(mac::Item::FileUrl(url));
+            assert!(matches!(result, Err(mac::Error::UnsupportedType)));
+
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
         #[test]
         fn transferableitem_serialize_bincode_test() {
-            mac::write_text("Hello".to_string());
+            mac::write_text("Hello".to_string(), false);
             let pasteboard_item = mac::read().unwrap();
             let item = TransferableItem::try_from(pasteboard_item).unwrap();
             let serialized = bincode::serialize(&item).unwrap();
@@ -1,5 +1,17 @@
+pub mod chunk;
+pub mod clipboard;
+#[cfg(unix)]
+pub mod control;
+pub mod discovery;
 pub mod item;
 pub mod network;
+pub mod outbox;
+pub mod persist;
+pub mod proxy;
+pub mod schedule;
+pub mod tls;
+#[cfg(target_os = "linux")]
+pub mod linux;
 #[cfg(target_os = "macos")]
 pub mod mac;
 #[cfg(target_os = "windows")]
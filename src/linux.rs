@@ -0,0 +1,353 @@
+//! Linux clipboard support, built on the standard command line tools since there is no
+//! clipboard API to link against that covers both X11 and Wayland.
+
+use log::{debug, warn};
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::Arc;
+
+/// Which X11/Wayland selection to follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Selection {
+    /// The explicit copy/paste clipboard
+    #[default]
+    Clipboard,
+    /// The text last selected with the mouse
+    Primary,
+}
+
+impl Selection {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Clipboard => "clipboard",
+            Self::Primary => "primary",
+        }
+    }
+}
+
+fn is_wayland() -> bool {
+    std::env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+/// Counts selection changes as the display server reports them, the Linux counterpart of
+/// `NSPasteboard.changeCount`, so the sender doesn't have to read the whole clipboard on every
+/// poll (which on X11 is slow and briefly takes the selection from its owner).
+///
+/// Under Wayland this runs `wl-paste --watch`, under X11 `clipnotify`, which waits for an XFixes
+/// selection owner change and exits.
+pub struct ChangeWatcher {
+    count: Arc<AtomicIsize>,
+    child: Arc<std::sync::Mutex<Option<Child>>>,
+}
+
+impl ChangeWatcher {
+    pub fn spawn(selection: Selection) -> std::io::Result<Self> {
+        if is_wayland() {
+            let mut command = Command::new("wl-paste");
+            if selection == Selection::Primary {
+                command.arg("--primary");
+            }
+            // `wl-paste` runs the command once per change, with the new content on its stdin
+            command.args(["--watch", "echo"]);
+            Self::watch(command, false)
+        } else {
+            let mut command = Command::new("clipnotify");
+            command.args(["-s", selection.name()]);
+            Self::watch(command, true)
+        }
+    }
+
+    /// Counts a change for every line `command` prints, or every time it exits when `restart`
+    /// is set.
+    fn watch(mut command: Command, restart: bool) -> std::io::Result<Self> {
+        command.stdin(Stdio::null()).stdout(Stdio::piped());
+        let count = Arc::new(AtomicIsize::new(0));
+        let child = Arc::new(std::sync::Mutex::new(Some(command.spawn()?)));
+
+        let watcher = Self {
+            count: Arc::clone(&count),
+            child: Arc::clone(&child),
+        };
+        std::thread::spawn(move || loop {
+            let Some(stdout) = child
+                .lock()
+                .unwrap()
+                .as_mut()
+                .and_then(|child| child.stdout.take())
+            else {
+                return;
+            };
+            for _ in BufReader::new(stdout).lines() {
+                count.fetch_add(1, Ordering::SeqCst);
+            }
+            let mut child = child.lock().unwrap();
+            let Some(process) = child.as_mut() else {
+                return;
+            };
+            let _ = process.wait();
+            if !restart {
+                warn!("{:?} exited, no longer watching the clipboard", command);
+                return;
+            }
+            count.fetch_add(1, Ordering::SeqCst);
+            match command.spawn() {
+                Ok(process) => *child = Some(process),
+                Err(err) => {
+                    warn!("Unable to restart {:?}: {err}", command);
+                    *child = None;
+                    return;
+                }
+            }
+        });
+        debug!("Watching clipboard changes");
+        Ok(watcher)
+    }
+
+    /// Number of changes seen so far.
+    pub fn get_count(&self) -> isize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
+
+/// Reads the current text of `selection`, `None` when it is empty or not text.
+///
+/// Every call makes the owning application convert its selection, so callers should only read
+/// after [`ChangeWatcher`] reported a change, see [`CachedSelection`].
+pub fn read_selection(selection: Selection) -> std::io::Result<Option<String>> {
+    let mut command = if is_wayland() {
+        let mut command = Command::new("wl-paste");
+        if selection == Selection::Primary {
+            command.arg("--primary");
+        }
+        command.args(["--no-newline", "--type", "text"]);
+        command
+    } else {
+        let mut command = Command::new("xclip");
+        command.args([
+            "-o",
+            "-selection",
+            selection.name(),
+            "-target",
+            "UTF8_STRING",
+        ]);
+        command
+    };
+    let output = command
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    // Both tools fail when nothing owns the selection or it has no text
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(String::from_utf8(output.stdout).ok())
+}
+
+/// A selection that is only read when its owner changed, with the last content cached, so
+/// polling never asks the owning application for a conversion it already answered.
+pub struct CachedSelection {
+    watcher: ChangeWatcher,
+    selection: Selection,
+    read: fn(Selection) -> std::io::Result<Option<String>>,
+    /// Change count the cached content was read at
+    cached_count: Option<isize>,
+    cached: Option<String>,
+}
+
+impl CachedSelection {
+    pub fn new(selection: Selection) -> std::io::Result<Self> {
+        Ok(Self::with_reader(
+            ChangeWatcher::spawn(selection)?,
+            selection,
+            read_selection,
+        ))
+    }
+
+    fn with_reader(
+        watcher: ChangeWatcher,
+        selection: Selection,
+        read: fn(Selection) -> std::io::Result<Option<String>>,
+    ) -> Self {
+        Self {
+            watcher,
+            selection,
+            read,
+            cached_count: None,
+            cached: None,
+        }
+    }
+
+    pub fn change_count(&self) -> isize {
+        self.watcher.get_count()
+    }
+
+    /// Current text, read from the owner only if it changed since the last call.
+    pub fn read(&mut self) -> Option<String> {
+        let count = self.watcher.get_count();
+        if self.cached_count != Some(count) {
+            self.cached = (self.read)(self.selection).unwrap_or_else(|err| {
+                warn!(
+                    "Unable to read the {} selection: {err}",
+                    self.selection.name()
+                );
+                None
+            });
+            self.cached_count = Some(count);
+        }
+        self.cached.clone()
+    }
+}
+
+/// Text on the clipboard, like `windows::read_text`.
+pub fn read_text() -> Result<String, Error> {
+    match read_selection(Selection::Clipboard) {
+        Ok(Some(text)) => Ok(text),
+        Ok(None) => Err(Error::Unsupported),
+        Err(err) => {
+            debug!("Unable to read the clipboard: {err}");
+            Err(Error::Unsupported)
+        }
+    }
+}
+
+pub fn write_text(text: String) -> Result<(), Error> {
+    write_selection(Selection::Clipboard, text)
+}
+
+/// Makes `text` the content of `selection`. `wl-copy` and `xclip` fork a process that keeps
+/// serving the selection until another application takes it over.
+pub fn write_selection(selection: Selection, text: String) -> Result<(), Error> {
+    use std::io::Write;
+
+    let mut command = if is_wayland() {
+        let mut command = Command::new("wl-copy");
+        if selection == Selection::Primary {
+            command.arg("--primary");
+        }
+        command
+    } else {
+        let mut command = Command::new("xclip");
+        command.args(["-i", "-selection", selection.name()]);
+        command
+    };
+    // The forked server inherits stdout, which would keep a pipe open forever
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    let status = command.spawn().and_then(|mut child| {
+        child.stdin.take().unwrap().write_all(text.as_bytes())?;
+        child.wait()
+    });
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(Error::Write(format!("{command:?} failed with {status}"))),
+        Err(err) => Err(Error::Write(format!("Unable to run {command:?}: {err}"))),
+    }
+}
+
+/// Empties the clipboard. `xclip` can't clear a selection, so under X11 it becomes empty text.
+pub fn clear() -> Result<(), Error> {
+    if !is_wayland() {
+        return write_text(String::new());
+    }
+    let mut command = Command::new("wl-copy");
+    command.arg("--clear");
+    match command.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(Error::Write(format!("{command:?} failed with {status}"))),
+        Err(err) => Err(Error::Write(format!("Unable to run {command:?}: {err}"))),
+    }
+}
+
+#[derive(Hash, Debug)]
+pub enum Error {
+    Unsupported,
+    /// Another process kept the clipboard open through every retry
+    Busy,
+    Write(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unsupported => write!(f, "Unsupported clipboard content"),
+            Self::Busy => write!(f, "Clipboard is held open by another process"),
+            Self::Write(err) => write!(f, "Unable to write to the clipboard: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Drop for ChangeWatcher {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.lock().unwrap().take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn wait_for_count(watcher: &ChangeWatcher, count: isize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while watcher.get_count() < count && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(watcher.get_count(), count);
+    }
+
+    #[test]
+    fn change_watcher_counts_lines_test() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo; echo; echo"]);
+        let watcher = ChangeWatcher::watch(command, false).unwrap();
+        wait_for_count(&watcher, 3);
+    }
+
+    #[test]
+    fn cached_selection_reads_once_per_change_test() {
+        use std::sync::atomic::AtomicUsize;
+
+        static READS: AtomicUsize = AtomicUsize::new(0);
+        fn fake_read(_: Selection) -> std::io::Result<Option<String>> {
+            let reads = READS.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(Some(format!("read {reads}")))
+        }
+
+        // Never reports a change
+        let mut command = Command::new("sleep");
+        command.arg("60");
+        let watcher = ChangeWatcher::watch(command, false).unwrap();
+        let mut selection = CachedSelection::with_reader(watcher, Selection::Clipboard, fake_read);
+
+        for _ in 0..100 {
+            assert_eq!(selection.read().as_deref(), Some("read 1"));
+        }
+        assert_eq!(READS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn change_watcher_counts_restarts_test() {
+        let marker = std::env::temp_dir().join(format!("clipper-watch-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        // Exits twice, then blocks like clipnotify waiting for the next change
+        let script = format!(
+            "if [ -e {0}.2 ]; then sleep 60; elif [ -e {0} ]; then touch {0}.2; else touch {0}; fi",
+            marker.display()
+        );
+        let mut command = Command::new("sh");
+        command.args(["-c", &script]);
+        let watcher = ChangeWatcher::watch(command, true).unwrap();
+        wait_for_count(&watcher, 2);
+        drop(watcher);
+        std::fs::remove_file(&marker).unwrap();
+        std::fs::remove_file(marker.with_extension("2")).unwrap();
+    }
+}
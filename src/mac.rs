@@ -1,8 +1,11 @@
 use std::ffi::OsString;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::RwLock;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 use log::{debug, info, warn};
-use objc2::rc::Retained;
+use objc2::rc::{autoreleasepool, Retained};
 use objc2::runtime::ProtocolObject;
 use objc2::*;
 use objc2_app_kit::*;
@@ -15,16 +18,90 @@ lazy_static::lazy_static! {
 #[derive(Debug)]
 pub enum Error {
     UnsupportedType,
+    /// A file URL whose symlink chain is longer than `MAX_SYMLINK_RECURSION_DEPTH`, usually
+    /// because it loops
+    SymlinkDepth(std::path::PathBuf),
+    /// Content the copying app asked not to be shared, like a password
+    Concealed,
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
 }
 
 #[derive(Debug)]
 pub enum Item {
     File(Retained<NSData>, OsString),
     Text(Retained<NSString>),
+    /// A link, as opposed to a file URL
+    Url(Retained<NSString>),
     FileUrl(Retained<NSString>),
+    /// Styled text, with the plain text every rich text copy also provides
+    RichText {
+        html: Option<Retained<NSString>>,
+        rtf: Option<Retained<NSData>>,
+        plain: Retained<NSString>,
+    },
+    /// Data of the first type that is neither a file, a URL nor text, and its type identifier
+    Raw(Retained<NSData>, Retained<NSString>),
+    /// Marked transient or concealed by the app that copied it, see [`CONCEALED_MARKERS`]. Its
+    /// content isn't read at all, unless [`set_read_concealed`] says otherwise
+    Concealed,
     Unsupported(),
 }
 
+/// Types password managers and similar apps add next to the content, following the
+/// nspasteboard.org conventions, to keep it out of clipboard histories and syncing.
+pub const CONCEALED_MARKERS: [&str; 2] = ["org.nspasteboard.TransientType", CONCEALED_TYPE];
+
+/// The marker written next to received sensitive clips, see [`write_text`].
+const CONCEALED_TYPE: &str = "org.nspasteboard.ConcealedType";
+
+static READ_CONCEALED: AtomicBool = AtomicBool::new(false);
+
+/// Reads items marked concealed like any other instead of as [`Item::Concealed`], so they can
+/// be sent on as sensitive clips, see [`is_concealed`].
+pub fn set_read_concealed(read: bool) {
+    READ_CONCEALED.store(read, Ordering::Relaxed);
+}
+
+/// Whether any of [`CONCEALED_MARKERS`] is set on the pasteboard's content.
+pub fn is_concealed() -> bool {
+    let _read_lock = PASTEBOARD_LOCK.read().expect("Lock poisoned");
+
+    autoreleasepool(|_| unsafe {
+        let Some(types) = NSPasteboard::generalPasteboard().types() else {
+            return false;
+        };
+        has_concealed_marker(&types)
+    })
+}
+
+fn has_concealed_marker(types: &NSArray<NSPasteboardType>) -> bool {
+    types.iter().any(|x| {
+        CONCEALED_MARKERS
+            .iter()
+            .any(|marker| unsafe { x.isEqualToString(&NSString::from_str(marker)) })
+    })
+}
+
+/// Marks the item just written to `board` with [`CONCEALED_TYPE`], if `concealed`.
+fn conceal(board: &NSPasteboard, concealed: bool) {
+    if !concealed {
+        return;
+    }
+    let marker = NSString::from_str(CONCEALED_TYPE);
+    unsafe {
+        board.addTypes_owner(&NSArray::from_vec(vec![marker.copy()]), None);
+        if !board.setData_forType(Some(&NSData::new()), &marker) {
+            panic!("Failed writing to pasteboard");
+        }
+    }
+}
+
 impl Item {
     fn get_extension(value: &NSPasteboardType) -> OsString {
         unsafe {
@@ -64,11 +141,14 @@ impl Item {
             vec![
                 NSPasteboardTypeString,
                 NSPasteboardTypeMultipleTextSelection,
-                NSPasteboardTypeURL,
             ]
         }
     }
 
+    fn get_url_type() -> Vec<&'static NSPasteboardType> /*Only return static constant*/ {
+        unsafe { vec![NSPasteboardTypeURL] }
+    }
+
     fn get_file_url_type() -> Vec<&'static NSPasteboardType> /*Only return static constant*/ {
         unsafe { vec![NSPasteboardTypeFileURL] }
     }
@@ -92,6 +172,7 @@ impl Item {
         match self {
             Self::File(..) => Self::get_file_type(),
             Self::Text(_) => Self::get_text_type(),
+            Self::Url(_) => Self::get_url_type(),
             Self::FileUrl(_) => Self::get_file_url_type(),
             _ => Self::get_unsupported_type(),
         }
@@ -100,6 +181,10 @@ impl Item {
     pub fn new(item: Retained<NSPasteboardItem>) -> Self {
         unsafe {
             let all_type = item.types();
+            if !READ_CONCEALED.load(Ordering::Relaxed) && has_concealed_marker(&all_type) {
+                debug!("Pasteboard item is marked concealed, not reading it");
+                return Self::Concealed;
+            }
             let mut file = all_type.iter().filter(|x| {
                 Self::get_file_type()
                     .iter()
@@ -115,17 +200,42 @@ impl Item {
                     .iter()
                     .any(|curr| curr.isEqualToString(x))
             });
+            let mut raw = all_type.iter().filter(|x| {
+                !Self::get_unsupported_type()
+                    .iter()
+                    .any(|curr| curr.isEqualToString(x))
+                    && !CONCEALED_MARKERS
+                        .iter()
+                        .any(|marker| x.isEqualToString(&NSString::from_str(marker)))
+            });
 
-            if let Some(x) = file.next() {
-                Self::File(item.dataForType(x).unwrap(), Self::get_extension(x))
-            } else if let Some(x) = url.next() {
-                let path = NSURL::URLWithDataRepresentation_relativeToURL(
-                    &item.dataForType(&x).unwrap(),
-                    None,
-                );
-                Self::FileUrl(path.relativePath().unwrap())
-            } else if let Some(x) = text.next() {
-                Self::Text(item.stringForType(&x).unwrap())
+            let html = item.stringForType(NSPasteboardTypeHTML);
+            let rtf = item.dataForType(NSPasteboardTypeRTF);
+            let plain = item.stringForType(NSPasteboardTypeString);
+
+            // A type can be listed while its data is unavailable, e.g. when the app that promised
+            // it quit, so each branch only matches once the data is actually there
+            if let (true, Some(plain)) = (html.is_some() || rtf.is_some(), plain) {
+                Self::RichText { html, rtf, plain }
+            } else if let Some((data, x)) =
+                file.find_map(|x| item.dataForType(x).map(|data| (data, x)))
+            {
+                Self::File(data, Self::get_extension(x))
+            } else if let Some(path) = url.find_map(|x| item.dataForType(x)).and_then(|data| {
+                NSURL::URLWithDataRepresentation_relativeToURL(&data, None).relativePath()
+            }) {
+                Self::FileUrl(path)
+            } else if let Some(url) = Self::get_url_type()
+                .into_iter()
+                .find_map(|x| item.stringForType(x))
+            {
+                Self::Url(url)
+            } else if let Some(text) = text.find_map(|x| item.stringForType(x)) {
+                Self::Text(text)
+            } else if let Some((data, x)) =
+                raw.find_map(|x| item.dataForType(x).map(|data| (data, x)))
+            {
+                Self::Raw(data, x.copy())
             } else {
                 Self::Unsupported()
             }
@@ -142,56 +252,191 @@ impl From<Retained<NSPasteboardItem>> for Item {
 pub fn get_count() -> isize {
     let _read_lock = PASTEBOARD_LOCK.read().expect("Lock poisoned");
 
-    unsafe { NSPasteboard::generalPasteboard().changeCount() }
+    // Called on every poll tick, drain the temporaries each time instead of letting them pile up
+    autoreleasepool(|_| unsafe { NSPasteboard::generalPasteboard().changeCount() })
+}
+
+/// How often [`watch`] checks the change count, which unlike the content is cheap to read.
+const WATCH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Calls `callback` from a thread of its own with the new content, see [`read_all`], every time
+/// the pasteboard changes, until it returns `false`.
+///
+/// AppKit has no change notification for the general pasteboard, so this still compares
+/// `changeCount`, but on a short interval and without waking whoever waits on the callback.
+pub fn watch<F>(mut callback: F) -> JoinHandle<()>
+where
+    F: FnMut(Vec<Item>) -> bool + Send + 'static,
+{
+    std::thread::spawn(move || {
+        let mut count = get_count();
+        loop {
+            std::thread::sleep(WATCH_INTERVAL);
+            let new_count = get_count();
+            if new_count == count {
+                continue;
+            }
+            count = new_count;
+            debug!("Pasteboard changed, change count: {count}");
+            if !callback(read_all()) {
+                return;
+            }
+        }
+    })
 }
 
+/// The last item on the pasteboard, for callers that handle a single one.
 pub fn read() -> Option<Item> {
+    let mut items = read_all();
+    if items.len() > 1 {
+        warn!("More than 1 item in NSPasteBoard, selecting the last item by default")
+    }
+    items.pop()
+}
+
+/// Every item on the pasteboard in order. Most copies place a single one, but selecting several
+/// files in Finder places one per file.
+pub fn read_all() -> Vec<Item> {
     let _read_lock = PASTEBOARD_LOCK.read().expect("Lock poisoned");
 
     debug!("Reading NSPasteboard...");
-    unsafe {
+    autoreleasepool(|_| unsafe {
         let board = NSPasteboard::generalPasteboard();
         debug!("Pasteboard: {}", board.name());
         debug!("Pasteboard change count: {}", board.changeCount());
-        let items = board.pasteboardItems()?;
+        let Some(items) = board.pasteboardItems() else {
+            return Vec::new();
+        };
         debug!("Number of items: {}", items.count());
-        if items.len() > 1 {
-            warn!("More than 1 item in NSPasteBoard, selecting the last item by default")
-        }
-        if let Some(item) = items.last() {
-            Some(Item::from(item.retain()))
-        } else {
+        let items: Vec<Item> = items.iter().map(|item| Item::from(item.retain())).collect();
+        if items.is_empty() {
             info!("No items on the pasteboard.");
-            None
         }
-    }
+        items
+    })
+}
+
+pub fn write_file_url(file_url: Retained<NSURL>, concealed: bool) {
+    write_file_urls(vec![file_url], concealed);
 }
 
-pub fn write_file_url(file_url: Retained<NSURL>) {
+/// Writes one item per URL, like copying several files in Finder. Marked like [`write_text`].
+pub fn write_file_urls(file_urls: Vec<Retained<NSURL>>, concealed: bool) {
     let _write_lock = PASTEBOARD_LOCK.write().expect("Lock poisoned");
-    let board = unsafe { NSPasteboard::generalPasteboard() };
 
-    let _ = unsafe { board.clearContents() };
-    let obj = ProtocolObject::from_retained(file_url);
-    let objects = NSArray::from_vec(vec![obj]);
-    let res = unsafe { board.writeObjects(&objects) };
-    if !res {
-        panic!("Failed writing to pasteboard");
-    }
+    // Writes happen on the client's listener thread, which has no autorelease pool of its own
+    autoreleasepool(|_| {
+        let board = unsafe { NSPasteboard::generalPasteboard() };
+
+        let _ = unsafe { board.clearContents() };
+        let objects = NSArray::from_vec(
+            file_urls
+                .into_iter()
+                .map(ProtocolObject::from_retained)
+                .collect(),
+        );
+        let res = unsafe { board.writeObjects(&objects) };
+        if !res {
+            panic!("Failed writing to pasteboard");
+        }
+        conceal(&board, concealed);
+    })
 }
 
-pub fn write_text(text: String) {
+/// Writes `text` as a regular pasteboard item.
+///
+/// No `org.nspasteboard.TransientType`/`ConcealedType` markers are set, so clipboard history
+/// managers on the receiving Mac record synced clips like any other copy. Unless `concealed`,
+/// for clips sent as sensitive, which get the `ConcealedType` marker so those managers skip
+/// them. The same goes for every other write.
+pub fn write_text(text: String, concealed: bool) {
     let _write_lock = PASTEBOARD_LOCK.write().expect("Lock poisoned");
-    let board = unsafe { NSPasteboard::generalPasteboard() };
 
-    let _ = unsafe { board.clearContents() };
-    let s = NSString::from_str(&text);
-    let obj = ProtocolObject::from_retained(s);
-    let objects = NSArray::from_vec(vec![obj]);
-    let res = unsafe { board.writeObjects(&objects) };
-    if !res {
-        panic!("Failed writing to pasteboard");
-    }
+    autoreleasepool(|_| {
+        let board = unsafe { NSPasteboard::generalPasteboard() };
+
+        let _ = unsafe { board.clearContents() };
+        let s = NSString::from_str(&text);
+        let obj = ProtocolObject::from_retained(s);
+        let objects = NSArray::from_vec(vec![obj]);
+        let res = unsafe { board.writeObjects(&objects) };
+        if !res {
+            panic!("Failed writing to pasteboard");
+        }
+        conceal(&board, concealed);
+    })
+}
+
+/// Empties the pasteboard.
+pub fn clear() {
+    let _write_lock = PASTEBOARD_LOCK.write().expect("Lock poisoned");
+
+    autoreleasepool(|_| {
+        let _ = unsafe { NSPasteboard::generalPasteboard().clearContents() };
+    })
+}
+
+/// Writes styled text in every representation given, so the receiving application can pick the
+/// richest it understands.
+pub fn write_rich_text(html: Option<&str>, rtf: Option<&[u8]>, plain: &str, concealed: bool) {
+    let _write_lock = PASTEBOARD_LOCK.write().expect("Lock poisoned");
+
+    autoreleasepool(|_| unsafe {
+        let board = NSPasteboard::generalPasteboard();
+
+        let _ = board.clearContents();
+        let item = NSPasteboardItem::new();
+        item.setString_forType(&NSString::from_str(plain), NSPasteboardTypeString);
+        if let Some(html) = html {
+            item.setString_forType(&NSString::from_str(html), NSPasteboardTypeHTML);
+        }
+        if let Some(rtf) = rtf {
+            item.setData_forType(&NSData::with_bytes(rtf), NSPasteboardTypeRTF);
+        }
+        let objects = NSArray::from_vec(vec![ProtocolObject::from_retained(item)]);
+        if !board.writeObjects(&objects) {
+            panic!("Failed writing to pasteboard");
+        }
+        conceal(&board, concealed);
+    })
+}
+
+/// Writes `url` as a link, along with the plain text of it for apps that only paste text.
+pub fn write_url(url: &str, concealed: bool) {
+    let _write_lock = PASTEBOARD_LOCK.write().expect("Lock poisoned");
+
+    autoreleasepool(|_| unsafe {
+        let board = NSPasteboard::generalPasteboard();
+
+        let _ = board.clearContents();
+        let item = NSPasteboardItem::new();
+        let url = NSString::from_str(url);
+        item.setString_forType(&url, NSPasteboardTypeURL);
+        item.setString_forType(&url, NSPasteboardTypeString);
+        let objects = NSArray::from_vec(vec![ProtocolObject::from_retained(item)]);
+        if !board.writeObjects(&objects) {
+            panic!("Failed writing to pasteboard");
+        }
+        conceal(&board, concealed);
+    })
+}
+
+/// Writes `data` under the pasteboard type `type_id`, as read into [`Item::Raw`].
+pub fn write_data(type_id: &str, data: &[u8], concealed: bool) {
+    let _write_lock = PASTEBOARD_LOCK.write().expect("Lock poisoned");
+
+    autoreleasepool(|_| {
+        let board = unsafe { NSPasteboard::generalPasteboard() };
+
+        let _ = unsafe { board.clearContents() };
+        let data = NSData::with_bytes(data);
+        let type_id = NSString::from_str(type_id);
+        let res = unsafe { board.setData_forType(Some(&data), &type_id) };
+        if !res {
+            panic!("Failed writing to pasteboard");
+        }
+        conceal(&board, concealed);
+    })
 }
 
 #[cfg(test)]
@@ -201,6 +446,198 @@ mod tests {
     use super::*;
     use crate::item::*;
 
+    #[test]
+    fn repeated_poll_test() {
+        write_text("Hello".to_string(), false);
+        let count = get_count();
+        for _ in 0..10_000 {
+            assert_eq!(get_count(), count);
+            assert!(matches!(read(), Some(Item::Text(_))));
+        }
+    }
+
+    #[test]
+    fn watch_fires_once_per_write_test() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        watch(move |items| sender.send(items.len()).is_ok());
+
+        for text in ["first", "second"] {
+            write_text(text.to_string(), false);
+            let timeout = WATCH_INTERVAL * 10;
+            assert_eq!(receiver.recv_timeout(timeout), Ok(1));
+            assert!(receiver.recv_timeout(timeout).is_err());
+        }
+    }
+
+    #[test]
+    fn empty_pasteboard_test() {
+        {
+            let _write_lock = PASTEBOARD_LOCK.write().expect("Lock poisoned");
+            unsafe { NSPasteboard::generalPasteboard().clearContents() };
+        }
+        assert!(read().is_none());
+        assert!(crate::clipboard::Backend::default()
+            .open()
+            .unwrap()
+            .read()
+            .is_none());
+    }
+
+    #[test]
+    fn write_text_is_recorded_by_history_managers_test() {
+        write_text("Hello".to_string(), false);
+        let types = unsafe { NSPasteboard::generalPasteboard().types() }.unwrap();
+        for marker in [
+            "org.nspasteboard.TransientType",
+            "org.nspasteboard.ConcealedType",
+            "org.nspasteboard.AutoGeneratedType",
+        ] {
+            let marker = NSString::from_str(marker);
+            assert!(!types.iter().any(|t| unsafe { t.isEqualToString(&marker) }));
+        }
+        assert!(!is_concealed());
+    }
+
+    #[test]
+    fn write_concealed_test() {
+        write_text("hunter2".to_string(), true);
+        assert!(is_concealed());
+        // Read back like any other concealed copy, so it isn't sent on either
+        assert!(matches!(read(), Some(Item::Concealed)));
+
+        let path = NSString::from_str(std::env::current_dir().unwrap().to_str().unwrap());
+        write_file_url(unsafe { NSURL::fileURLWithPath(&path) }, true);
+        assert!(is_concealed());
+    }
+
+    #[test]
+    fn concealed_item_test() {
+        {
+            let _write_lock = PASTEBOARD_LOCK.write().expect("Lock poisoned");
+            autoreleasepool(|_| unsafe {
+                let board = NSPasteboard::generalPasteboard();
+                let _ = board.clearContents();
+                // What password managers write
+                let item = NSPasteboardItem::new();
+                item.setString_forType(&NSString::from_str("hunter2"), NSPasteboardTypeString);
+                item.setData_forType(
+                    &NSData::new(),
+                    &NSString::from_str("org.nspasteboard.ConcealedType"),
+                );
+                let objects = NSArray::from_vec(vec![ProtocolObject::from_retained(item)]);
+                assert!(board.writeObjects(&objects));
+            });
+        }
+
+        let item = read().unwrap();
+        assert!(matches!(item, Item::Concealed));
+        assert!(matches!(
+            TransferableItem::try_from(item),
+            Err(Error::Concealed)
+        ));
+        // So the sender never sees it
+        assert!(crate::clipboard::Backend::default()
+            .open()
+            .unwrap()
+            .read()
+            .is_none());
+
+        // Unless asked to send it on, marked sensitive
+        assert!(is_concealed());
+        set_read_concealed(true);
+        let item = read();
+        set_read_concealed(false);
+        assert_eq!(
+            TransferableItem::try_from(item.unwrap()).unwrap(),
+            TransferableItem::Text {
+                text: "hunter2".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn url_round_trip_test() {
+        let url = "https://example.com/search?q=clipper";
+        write_url(url, false);
+        let item = TransferableItem::try_from(read().unwrap()).unwrap();
+        let received: TransferableItem =
+            bincode::deserialize(&bincode::serialize(&item).unwrap()).unwrap();
+        assert_eq!(
+            received,
+            TransferableItem::Url {
+                url: url.to_string()
+            }
+        );
+
+        // Plain text stays text
+        write_text(url.to_string(), false);
+        let item = TransferableItem::try_from(read().unwrap()).unwrap();
+        assert!(matches!(item, TransferableItem::Text { .. }));
+    }
+
+    #[test]
+    fn rich_text_round_trip_test() {
+        let html = "<p>Hello <b>World</b></p>";
+        write_rich_text(Some(html), None, "Hello World", false);
+        let item = TransferableItem::try_from(read().unwrap()).unwrap();
+        let received: TransferableItem =
+            bincode::deserialize(&bincode::serialize(&item).unwrap()).unwrap();
+
+        let TransferableItem::RichText {
+            html: Some(received_html),
+            plain,
+            ..
+        } = received
+        else {
+            panic!("Expected rich text, got {received}");
+        };
+        assert!(received_html.contains("<b>World</b>"), "{received_html}");
+        assert_eq!(plain, "Hello World");
+    }
+
+    #[test]
+    fn write_data_test() {
+        let type_id = "com.example.clipper.test";
+        write_data(type_id, &[1, 2, 3], false);
+        let item = TransferableItem::try_from(read().unwrap()).unwrap();
+        assert_eq!(
+            item,
+            TransferableItem::Raw {
+                type_id: type_id.to_string(),
+                data: vec![1, 2, 3],
+            }
+        );
+    }
+
+    #[test]
+    fn read_all_files_test() {
+        let dir = std::env::temp_dir().join(format!("clipper-read-all-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let names = ["a.txt", "b.txt"];
+        let urls = names.map(|name| {
+            std::fs::write(dir.join(name), name).unwrap();
+            let path = NSString::from_str(dir.join(name).to_str().unwrap());
+            unsafe { NSURL::fileURLWithPath(&path) }
+        });
+        write_file_urls(urls.to_vec(), false);
+
+        let items: Vec<TransferableItem> = read_all()
+            .into_iter()
+            .map(|item| TransferableItem::try_from(item).unwrap())
+            .collect();
+        assert_eq!(items.len(), 2);
+        for (item, name) in items.iter().zip(names) {
+            let TransferableItem::File { file_name, .. } = item else {
+                panic!("Expected a file, got {item}");
+            };
+            assert_eq!(file_name, name);
+        }
+        // Still one of them for callers that handle a single item
+        assert!(matches!(read(), Some(Item::FileUrl(_))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn write_file_url_test() {
         let curr_dir = std::env::current_dir().unwrap();
@@ -210,10 +647,11 @@ mod tests {
         let s = NSString::from_str(path.as_path().to_str().unwrap());
         unsafe {
             let url = NSURL::fileURLWithPath(&s);
-            write_file_url(url);
+            write_file_url(url, false);
             let item = read().unwrap();
             let item = TransferableItem::try_from(item).unwrap();
-            item.write_to_dir(std::env::current_dir().unwrap());
+            item.write_to_dir(std::env::current_dir().unwrap(), None)
+                .unwrap();
             std::fs::remove_file(std::env::current_dir().unwrap().join(PathBuf::from(file)))
                 .unwrap();
         }
@@ -1,35 +1,465 @@
-use log::debug;
+use log::{debug, info, trace, warn};
 use orion::aead;
+use orion::errors::UnknownCryptoError;
+use orion::hazardous::aead::{chacha20poly1305, xchacha20poly1305};
+use orion::hazardous::mac::hmac::sha256::HmacSha256;
+use orion::hazardous::mac::poly1305::POLY1305_OUTSIZE;
+use orion::hazardous::stream::chacha20::IETF_CHACHA_NONCESIZE;
+use orion::hazardous::stream::xchacha20::XCHACHA_NONCESIZE;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::io::Read;
 use std::io::Write;
-use std::net::TcpStream;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::{BufReader, BufWriter};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::item::*;
+use crate::tls::TlsStream;
 
 #[cfg(target_os = "macos")]
 use crate::mac;
 
+#[derive(Debug)]
+pub enum NetworkError {
+    Io(std::io::Error),
+    Serialize(bincode::Error),
+    Deserialize(bincode::Error),
+    SerializeJson(serde_json::Error),
+    DeserializeJson(serde_json::Error),
+    EncryptionFailed(UnknownCryptoError),
+    /// The package was sealed with another key or tampered with on the way
+    DecryptionFailed(UnknownCryptoError),
+    /// The package opened fine but its sequence number was already seen, so it was captured
+    /// and sent again
+    Replayed(u64),
+    DecompressionFailed(lz4_flex::block::DecompressError),
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::Serialize(err) => write!(f, "Unable to serialize: {err}"),
+            Self::Deserialize(err) => write!(f, "Malformed package: {err}"),
+            Self::SerializeJson(err) => write!(f, "Unable to serialize to JSON: {err}"),
+            Self::DeserializeJson(err) => write!(f, "Malformed JSON package: {err}"),
+            Self::EncryptionFailed(err) => write!(f, "Unable to encrypt package: {err}"),
+            Self::DecryptionFailed(err) => write!(f, "Unable to decrypt package: {err}"),
+            Self::Replayed(seq) => write!(f, "Package {seq} was replayed"),
+            Self::DecompressionFailed(err) => write!(f, "Unable to decompress package: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+impl From<std::io::Error> for NetworkError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<NetworkError> for std::io::Error {
+    fn from(value: NetworkError) -> Self {
+        match value {
+            NetworkError::Io(err) => err,
+            value => std::io::Error::new(std::io::ErrorKind::InvalidData, value),
+        }
+    }
+}
+
+/// AEAD algorithms packages can be sealed with.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum,
+)]
+pub enum Cipher {
+    #[default]
+    #[value(name = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+    #[value(name = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+    /// Hardware accelerated on most desktop CPUs
+    #[value(name = "aes-256-gcm")]
+    Aes256Gcm,
+}
+
+impl Cipher {
+    /// Every cipher this build can use, most preferred first.
+    pub const SUPPORTED: [Cipher; 3] = [
+        Self::XChaCha20Poly1305,
+        Self::ChaCha20Poly1305,
+        Self::Aes256Gcm,
+    ];
+
+    /// Picks the first of the client's `offered` ciphers that this build supports.
+    pub fn negotiate(offered: &[Cipher]) -> Option<Cipher> {
+        offered
+            .iter()
+            .copied()
+            .find(|cipher| Self::SUPPORTED.contains(cipher))
+    }
+
+    fn nonce_size(self) -> usize {
+        match self {
+            Self::XChaCha20Poly1305 => XCHACHA_NONCESIZE,
+            Self::ChaCha20Poly1305 => IETF_CHACHA_NONCESIZE,
+            Self::Aes256Gcm => ring::aead::NONCE_LEN,
+        }
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use clap::ValueEnum;
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
+
+/// Codecs package payloads can be compressed with, before they are sealed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum)]
+pub enum Codec {
+    #[value(name = "lz4")]
+    Lz4,
+}
+
+impl Codec {
+    /// Every codec this build can use.
+    pub const SUPPORTED: [Codec; 1] = [Self::Lz4];
+
+    /// The client's `requested` codec if this build supports it.
+    pub fn negotiate(requested: Option<Codec>) -> Option<Codec> {
+        requested.filter(|codec| Self::SUPPORTED.contains(codec))
+    }
+
+    fn compress(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Lz4 => lz4_flex::compress_prepend_size(bytes),
+        }
+    }
+
+    /// The size the payload claims to inflate to, checked against the package limit.
+    fn decompressed_len(self, bytes: &[u8]) -> Result<usize, NetworkError> {
+        match self {
+            Self::Lz4 => {
+                let size = bytes
+                    .first_chunk::<4>()
+                    .ok_or(NetworkError::DecompressionFailed(
+                        lz4_flex::block::DecompressError::ExpectedAnotherByte,
+                    ))?;
+                let size = u32::from_le_bytes(*size);
+                check_frame_len(size as u64)?;
+                Ok(size as usize)
+            }
+        }
+    }
+
+    /// Checks the size the payload claims to inflate to against the package limit before
+    /// allocating for it, so a tiny frame can't demand gigabytes.
+    fn decompress(self, bytes: &[u8]) -> Result<Vec<u8>, NetworkError> {
+        let size = self.decompressed_len(bytes)?;
+        match self {
+            Self::Lz4 => {
+                lz4_flex::decompress(&bytes[4..], size).map_err(NetworkError::DecompressionFailed)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use clap::ValueEnum;
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
+
+/// How packages are serialized before they are compressed and sealed.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum,
+)]
+pub enum Format {
+    #[default]
+    #[value(name = "bincode")]
+    Bincode,
+    /// Readable on the wire and by anything with a JSON parser, at the cost of size: binary
+    /// data is base64 encoded.
+    #[value(name = "json")]
+    Json,
+}
+
+impl Format {
+    fn serialize(self, package: &Package) -> Result<Vec<u8>, NetworkError> {
+        match self {
+            Self::Bincode => bincode::serialize(package).map_err(NetworkError::Serialize),
+            Self::Json => serde_json::to_vec(package).map_err(NetworkError::SerializeJson),
+        }
+    }
+
+    fn deserialize(self, bytes: &[u8]) -> Result<Package, NetworkError> {
+        match self {
+            Self::Bincode => Package::from_bytes(bytes),
+            Self::Json => serde_json::from_slice(bytes).map_err(NetworkError::DeserializeJson),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use clap::ValueEnum;
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
+
+/// How the packages of a connection are put on the wire, as agreed in the handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Encoding {
+    pub format: Format,
+    pub compression: Option<Codec>,
+}
+
+/// Trailer after the session and after the server's verdict once a format other than bincode
+/// is involved. Otherwise only the codec is appended, if any, as before formats existed.
+#[derive(Serialize, Deserialize)]
+struct Extensions {
+    compression: Option<Codec>,
+    format: Format,
+}
+
+impl Encoding {
+    fn to_trailer(self) -> Result<Vec<u8>, NetworkError> {
+        let trailer = match (self.format, self.compression) {
+            (Format::Bincode, None) => Ok(Vec::new()),
+            (Format::Bincode, Some(codec)) => bincode::serialize(&codec),
+            (format, compression) => bincode::serialize(&Extensions {
+                compression,
+                format,
+            }),
+        };
+        trailer.map_err(NetworkError::Serialize)
+    }
+
+    /// Reads what [`Encoding::to_trailer`] wrote. A lone codec is too short to be taken for
+    /// [`Extensions`], which always hold a format after the codec.
+    fn from_trailer(trailer: &[u8]) -> Result<Self, NetworkError> {
+        if trailer.is_empty() {
+            return Ok(Self::default());
+        }
+        if let Ok(extensions) = bincode::deserialize::<Extensions>(trailer) {
+            return Ok(Self {
+                format: extensions.format,
+                compression: extensions.compression,
+            });
+        }
+        let codec = bincode::deserialize(trailer).map_err(NetworkError::Deserialize)?;
+        Ok(Self {
+            format: Format::Bincode,
+            compression: Some(codec),
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct SharedKey {
     pub key: aead::SecretKey,
+    /// Authenticated with every package but not sent, see [`SharedKey::with_associated_data`].
+    associated_data: Vec<u8>,
+    /// Side of the connection sealing with this key, see [`SharedKey::with_role`].
+    role: Option<Role>,
+    cipher: Cipher,
+    /// Sequence number of the last package sealed
+    sealed: AtomicU64,
+    /// Highest sequence number opened, packages at or below it are replays
+    opened: AtomicU64,
+}
+
+/// Bytes of the sequence number sealed in front of every plaintext
+const SEQUENCE_LEN: usize = 8;
+
+/// Side of a connection, authenticated with every package so one sealed by either side can't
+/// be reflected back to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    fn peer(self) -> Self {
+        match self {
+            Self::Client => Self::Server,
+            Self::Server => Self::Client,
+        }
+    }
 }
 
 impl SharedKey {
-    fn generate() -> Self {
-        Self {
-            key: aead::SecretKey::default(),
+    /// Seals and opens with `cipher` instead of the default XChaCha20-Poly1305.
+    pub fn with_cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Binds `associated_data` into the authentication tag of every package sealed or opened
+    /// with this key, so a package captured in one session context is rejected in another.
+    pub fn with_associated_data(mut self, associated_data: Vec<u8>) -> Self {
+        self.associated_data = associated_data;
+        self
+    }
+
+    /// Seals as `role` and only opens packages sealed by the other side. Both directions share
+    /// the key and count their sequence numbers separately, so without a role a package could
+    /// be sent back to the side that sealed it and pass as the peer's.
+    pub fn with_role(mut self, role: Role) -> Self {
+        self.role = Some(role);
+        self
+    }
+
+    /// Associated data of the packages sealed by `role`.
+    fn associated_data(&self, role: Option<Role>) -> Vec<u8> {
+        let mut associated_data = self.associated_data.clone();
+        associated_data.extend(role.map(|role| role as u8));
+        associated_data
+    }
+
+    /// Same layout as `orion::aead::seal` (nonce, ciphertext, tag), plus the associated data.
+    /// The plaintext is preceded by a sequence number, one more for every package sealed, so
+    /// the peer can reject a package sent again by someone who captured it.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, UnknownCryptoError> {
+        let seq = self.sealed.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut sequenced = Vec::with_capacity(SEQUENCE_LEN + plaintext.len());
+        sequenced.extend_from_slice(&seq.to_le_bytes());
+        sequenced.extend_from_slice(plaintext);
+        let plaintext = sequenced.as_slice();
+
+        let nonce_size = self.cipher.nonce_size();
+        let mut sealed = vec![0u8; nonce_size + plaintext.len() + POLY1305_OUTSIZE];
+        let (nonce, out) = sealed.split_at_mut(nonce_size);
+        orion::util::secure_rand_bytes(nonce)?;
+        let key = self.aead_key()?;
+        let ad = self.associated_data(self.role);
+        let ad = Some(ad.as_slice());
+        match self.cipher {
+            Cipher::XChaCha20Poly1305 => {
+                let nonce = xchacha20poly1305::Nonce::from_slice(nonce)?;
+                xchacha20poly1305::seal(&key, &nonce, plaintext, ad, out)?
+            }
+            Cipher::ChaCha20Poly1305 => {
+                let nonce = chacha20poly1305::Nonce::from_slice(nonce)?;
+                chacha20poly1305::seal(&key, &nonce, plaintext, ad, out)?
+            }
+            Cipher::Aes256Gcm => aes_256_gcm::seal(&key, nonce, plaintext, ad, out)?,
+        }
+        Ok(sealed)
+    }
+
+    /// Opens a package sealed by the peer, refusing it unless its sequence number is higher
+    /// than that of every package opened before.
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, NetworkError> {
+        let mut plaintext = self
+            .open_sequenced(sealed)
+            .map_err(NetworkError::DecryptionFailed)?;
+        let seq = u64::from_le_bytes(plaintext[..SEQUENCE_LEN].try_into().unwrap());
+        if self.opened.fetch_max(seq, Ordering::SeqCst) >= seq {
+            return Err(NetworkError::Replayed(seq));
+        }
+        plaintext.drain(..SEQUENCE_LEN);
+        Ok(plaintext)
+    }
+
+    fn open_sequenced(&self, sealed: &[u8]) -> Result<Vec<u8>, UnknownCryptoError> {
+        let nonce_size = self.cipher.nonce_size();
+        if sealed.len() < nonce_size + SEQUENCE_LEN + POLY1305_OUTSIZE {
+            return Err(UnknownCryptoError);
+        }
+        let mut plaintext = vec![0u8; sealed.len() - nonce_size - POLY1305_OUTSIZE];
+        let (nonce, ciphertext) = sealed.split_at(nonce_size);
+        let key = self.aead_key()?;
+        let ad = self.associated_data(self.role.map(Role::peer));
+        let ad = Some(ad.as_slice());
+        match self.cipher {
+            Cipher::XChaCha20Poly1305 => {
+                let nonce = xchacha20poly1305::Nonce::from_slice(nonce)?;
+                xchacha20poly1305::open(&key, &nonce, ciphertext, ad, &mut plaintext)?
+            }
+            Cipher::ChaCha20Poly1305 => {
+                let nonce = chacha20poly1305::Nonce::from_slice(nonce)?;
+                chacha20poly1305::open(&key, &nonce, ciphertext, ad, &mut plaintext)?
+            }
+            Cipher::Aes256Gcm => aes_256_gcm::open(&key, nonce, ciphertext, ad, &mut plaintext)?,
         }
+        Ok(plaintext)
+    }
+
+    /// Every cipher takes the same 256-bit key, typed for ChaCha20.
+    fn aead_key(&self) -> Result<xchacha20poly1305::SecretKey, UnknownCryptoError> {
+        xchacha20poly1305::SecretKey::from_slice(self.key.unprotected_as_bytes())
+    }
+}
+
+/// AES-256-GCM from ring, with the same signatures as orion's ChaCha20 ciphers so they can be
+/// used the same way. The tag is as long as Poly1305's.
+mod aes_256_gcm {
+    use orion::errors::UnknownCryptoError;
+    use orion::hazardous::aead::xchacha20poly1305::SecretKey;
+    use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+
+    fn key(key: &SecretKey) -> Result<LessSafeKey, UnknownCryptoError> {
+        let key = UnboundKey::new(&AES_256_GCM, key.unprotected_as_bytes())
+            .map_err(|_| UnknownCryptoError)?;
+        Ok(LessSafeKey::new(key))
+    }
+
+    fn nonce(nonce: &[u8]) -> Result<Nonce, UnknownCryptoError> {
+        Nonce::try_assume_unique_for_key(nonce).map_err(|_| UnknownCryptoError)
+    }
+
+    /// Writes the ciphertext of `plaintext` followed by the tag to `out`.
+    pub fn seal(
+        secret_key: &SecretKey,
+        nonce: &[u8],
+        plaintext: &[u8],
+        ad: Option<&[u8]>,
+        out: &mut [u8],
+    ) -> Result<(), UnknownCryptoError> {
+        let (ciphertext, tag_out) = out.split_at_mut(plaintext.len());
+        ciphertext.copy_from_slice(plaintext);
+        let tag = key(secret_key)?
+            .seal_in_place_separate_tag(
+                self::nonce(nonce)?,
+                Aad::from(ad.unwrap_or_default()),
+                ciphertext,
+            )
+            .map_err(|_| UnknownCryptoError)?;
+        tag_out.copy_from_slice(tag.as_ref());
+        Ok(())
+    }
+
+    /// Writes the plaintext of `ciphertext_with_tag` to `out` if the tag matches.
+    pub fn open(
+        secret_key: &SecretKey,
+        nonce: &[u8],
+        ciphertext_with_tag: &[u8],
+        ad: Option<&[u8]>,
+        out: &mut [u8],
+    ) -> Result<(), UnknownCryptoError> {
+        let mut in_out = ciphertext_with_tag.to_vec();
+        let plaintext = key(secret_key)?
+            .open_in_place(
+                self::nonce(nonce)?,
+                Aad::from(ad.unwrap_or_default()),
+                &mut in_out,
+            )
+            .map_err(|_| UnknownCryptoError)?;
+        out.copy_from_slice(plaintext);
+        Ok(())
     }
 }
 
 impl From<[u8; 32]> for SharedKey {
     fn from(value: [u8; 32]) -> Self {
-        Self {
-            key: aead::SecretKey::from_slice(&value).unwrap(),
-        }
+        Self::from(&value)
     }
 }
 
@@ -37,74 +467,467 @@ impl From<&[u8; 32]> for SharedKey {
     fn from(value: &[u8; 32]) -> Self {
         Self {
             key: aead::SecretKey::from_slice(value).unwrap(),
+            associated_data: Vec::new(),
+            role: None,
+            cipher: Cipher::default(),
+            sealed: AtomicU64::new(0),
+            opened: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Secret shared out of band between the server and its clients.
+///
+/// The x25519 exchange only keeps the traffic confidential, anyone who can reach the server can
+/// run it. With a pre-shared key clients also have to prove they know the secret before the
+/// server lets them join.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PreSharedKey(Vec<u8>);
+
+impl fmt::Debug for PreSharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PreSharedKey(..)")
+    }
+}
+
+impl std::str::FromStr for PreSharedKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("The pre-shared key can't be empty".to_string());
         }
+        Ok(Self(s.as_bytes().to_vec()))
+    }
+}
+
+impl PreSharedKey {
+    /// HMAC-SHA256 over the transcript of the exchange, the public keys of the server and the
+    /// client in that order. Both keys are fresh for every connection, so a proof observed on
+    /// the wire can't be replayed.
+    pub fn prove(&self, server_public: &[u8; 32], client_public: &[u8; 32]) -> Vec<u8> {
+        let key = orion::hazardous::mac::hmac::sha256::SecretKey::from_slice(&self.0)
+            .expect("Pre-shared key is not empty");
+        HmacSha256::hmac(&key, &[server_public.as_slice(), client_public].concat())
+            .expect("HMAC of a short message can't fail")
+            .unprotected_as_bytes()
+            .to_vec()
+    }
+
+    /// Checks a proof made with [`PreSharedKey::prove`] in constant time.
+    pub fn verify(&self, proof: &[u8], server_public: &[u8; 32], client_public: &[u8; 32]) -> bool {
+        use orion::hazardous::mac::hmac::sha256::{SecretKey, Tag};
+
+        let (Ok(key), Ok(tag)) = (SecretKey::from_slice(&self.0), Tag::from_slice(proof)) else {
+            return false;
+        };
+        HmacSha256::verify(
+            &tag,
+            &key,
+            &[server_public.as_slice(), client_public].concat(),
+        )
+        .is_ok()
     }
 }
 
+/// Version of the wire protocol, bumped whenever `SessionInfo`, `Package` or the handshake
+/// change in a way older peers can't decode.
+pub const PROTOCOL_VERSION: u32 = 11;
+
+/// How often each end of a connection sends a [`Package::Heartbeat`].
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a peer may send nothing, heartbeats included, before the connection is considered
+/// dead: three missed heartbeats.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a write may make no progress before the peer is considered stalled, e.g. because
+/// it stopped reading.
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the server waits for each step of a handshake before dropping the connection.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest session accepted. It arrives before the client proved anything, so it is held to
+/// what a session actually needs rather than [`MAX_PACKAGE_LEN`].
+pub const MAX_SESSION_LEN: u64 = 64 * 1024;
+
 #[derive(Debug, Serialize, Deserialize, Hash, PartialEq, Clone)]
 pub struct SessionInfo {
+    pub device_name: String,
     pub os: String,
     pub use_encryption: bool,
+    /// Observers receive every broadcast but never push clips or take part in ordering.
+    pub observer: bool,
+    /// Ciphers the client accepts, most preferred first. The server answers with its pick.
+    pub ciphers: Vec<Cipher>,
+    /// [`PROTOCOL_VERSION`] of the client. Last, so a server that predates it still decodes the
+    /// fields before it.
+    pub protocol_version: u32,
+    /// Codec the client would like packages compressed with. Appended after the session by
+    /// [`send_session`] rather than serialized with it, so servers that predate it ignore it and
+    /// it stays out of [`SessionInfo::associated_data`].
+    #[serde(skip)]
+    pub compression: Option<Codec>,
+    /// Format the client would like packages serialized in, appended like `compression`.
+    #[serde(skip)]
+    pub format: Format,
 }
 
-#[derive(Debug, Serialize, Deserialize, Hash, PartialEq, Clone)]
+impl SessionInfo {
+    /// An unencrypted, uncompressed, bincode session for a client called `device_name` on this platform.
+    pub fn new(device_name: impl Into<String>) -> Self {
+        Self {
+            device_name: device_name.into(),
+            os: std::env::consts::OS.to_string(),
+            use_encryption: false,
+            observer: false,
+            ciphers: Cipher::SUPPORTED.to_vec(),
+            protocol_version: PROTOCOL_VERSION,
+            compression: None,
+            format: Format::Bincode,
+        }
+    }
+
+    /// Session context bound into every encrypted package of the connection.
+    pub fn associated_data(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("Unable to serialize session")
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Hash, PartialEq, Eq, Clone)]
 pub enum Package {
+    #[default]
     Empty,
-    Item { time: u64, item: TransferableItem },
+    Item {
+        /// Milliseconds since the Unix epoch when the item was copied
+        time: u64,
+        /// Increases with every item created by the sending process, so the items it copies
+        /// within the same millisecond still have an order
+        seq: u64,
+        /// Length prefixed in binary encodings, so items of unknown kinds can be skipped
+        #[serde(with = "crate::item::enveloped")]
+        item: TransferableItem,
+        /// Application the item was copied from, where the sending platform can tell
+        source_app: Option<String>,
+        /// User defined tag receivers can filter on, see [`LabelFilter`]
+        label: Option<String>,
+        /// Copied as a password or similar secret, so receivers keep it out of clipboard
+        /// histories where the platform allows, see [`crate::mac::CONCEALED_MARKERS`]
+        #[serde(default)]
+        sensitive: bool,
+    },
+    /// Names of the devices currently connected to the server, pushed whenever that set changes.
+    Presence { peers: Vec<String> },
+    /// Sent every [`HEARTBEAT_INTERVAL`] by both ends, so a peer that vanished without closing
+    /// the connection is noticed. Never applied to the clipboard nor broadcast.
+    Heartbeat,
+    /// Asks the server for the clip copied `index` clips before the latest one, 0 being the
+    /// latest.
+    HistoryRequest { index: usize },
+    /// The server's answer to a [`Package::HistoryRequest`], `None` when its history doesn't
+    /// go back that far.
+    HistoryEntry {
+        index: usize,
+        package: Option<Box<Package>>,
+    },
+    /// Part `seq` of `total` of a package too large to send at once, see [`crate::chunk`].
+    /// Chunks sharing `id` are put back together by the receiver.
+    FileChunk {
+        id: u64,
+        seq: u32,
+        total: u32,
+        #[serde(with = "crate::item::base64_bytes")]
+        bytes: Vec<u8>,
+    },
+    /// The sender's clipboard was emptied. Unlike `Empty`, which only means nothing was copied
+    /// yet, it is forwarded and empties the clipboard of every receiver. Stamped like an item, so
+    /// it replaces older clips and is replaced by newer ones.
+    Clear { time: u64, seq: u64 },
+    /// Sent by the server to the client an item or a clear came from once it has it, naming the
+    /// package by its `time` and `seq`, see [`Package::ack`].
+    Ack { time: u64, seq: u64 },
+    /// Sent by a client after reconnecting for a chunked transfer a dropped connection cut
+    /// short: it holds the first `offset` bytes of transfer `id`, see [`crate::chunk`], and
+    /// asks for the rest.
+    Resume { id: u64, offset: u64 },
 }
 
 impl fmt::Display for Package {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write(f, false)
+    }
+}
+
+impl fmt::Display for Redacted<'_, Package> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.write(f, true)
+    }
+}
+
+impl Package {
+    /// [`Redacted`] form of the package, for logs.
+    pub fn redacted(&self) -> Redacted<'_, Self> {
+        Redacted(self)
+    }
+
+    /// Writes the package for `Display`, with its item shown redacted if `redacted`.
+    fn write(&self, f: &mut std::fmt::Formatter<'_>, redacted: bool) -> std::fmt::Result {
         match self {
             Package::Empty => write!(f, "Package::Empty"),
-            Package::Item { time, item } => {
-                write!(f, "Package::Item{{ Time: {}; {} }}", time, item)
+            Package::Item {
+                time,
+                item,
+                source_app,
+                label,
+                sensitive,
+                ..
+            } => {
+                write!(f, "Package::Item{{ Time: {}; ", time)?;
+                if redacted {
+                    write!(f, "{}", item.redacted())?;
+                } else {
+                    write!(f, "{}", item)?;
+                }
+                if let Some(app) = source_app {
+                    write!(f, "; From: {}", app)?;
+                }
+                if let Some(label) = label {
+                    write!(f, "; Label: {}", label)?;
+                }
+                if *sensitive {
+                    write!(f, "; Sensitive")?;
+                }
+                write!(f, " }}")
+            }
+            Package::Presence { peers } => {
+                write!(f, "Package::Presence{{ Peers: {} }}", peers.join(", "))
+            }
+            Package::Heartbeat => write!(f, "Package::Heartbeat"),
+            Package::HistoryRequest { index } => {
+                write!(f, "Package::HistoryRequest{{ Index: {} }}", index)
+            }
+            Package::HistoryEntry { index, package } => match package {
+                Some(package) => {
+                    write!(f, "Package::HistoryEntry{{ Index: {}; ", index)?;
+                    package.write(f, redacted)?;
+                    write!(f, " }}")
+                }
+                None => write!(f, "Package::HistoryEntry{{ Index: {}; None }}", index),
+            },
+            Package::FileChunk { id, seq, total, .. } => write!(
+                f,
+                "Package::FileChunk{{ Id: {}; {}/{} }}",
+                id,
+                seq + 1,
+                total
+            ),
+            Package::Clear { time, .. } => write!(f, "Package::Clear{{ Time: {} }}", time),
+            Package::Ack { time, seq } => {
+                write!(f, "Package::Ack{{ Time: {}; Seq: {} }}", time, seq)
+            }
+            Package::Resume { id, offset } => {
+                write!(f, "Package::Resume{{ Id: {}; Offset: {} }}", id, offset)
             }
         }
     }
 }
 
+/// Packages are ordered by how recent the clipboard state they carry is: `Empty`, `Presence`,
+/// `Heartbeat`, the history ones, file chunks, acks and resumes, which carry none, first, then
+/// items and clears by `time` and, within the same millisecond, `seq`.
+///
+/// Equally recent packages are ordered by their bincode encoding, which is arbitrary but the
+/// same on every device, so packages compare `Equal` exactly when they are `==`.
+impl Ord for Package {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.recency().cmp(&other.recency()).then_with(|| {
+            bincode::serialize(self)
+                .ok()
+                .cmp(&bincode::serialize(other).ok())
+        })
+    }
+}
+
 impl PartialOrd for Package {
-    fn lt(&self, other: &Self) -> bool {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Package {
+    /// Sort key of the package, see its `Ord` implementation.
+    fn recency(&self) -> Option<(u64, u64)> {
         match self {
-            Self::Empty => match other {
-                Self::Empty => false,
-                Self::Item { .. } => true,
-            },
-            Self::Item { time, .. } => match other {
-                Self::Empty => false,
-                Self::Item { time: time2, .. } => time < time2,
-            },
+            Self::Item { time, seq, .. } | Self::Clear { time, seq } => Some((*time, *seq)),
+            Self::Empty
+            | Self::Presence { .. }
+            | Self::Heartbeat
+            | Self::HistoryRequest { .. }
+            | Self::HistoryEntry { .. }
+            | Self::FileChunk { .. }
+            | Self::Ack { .. }
+            | Self::Resume { .. } => None,
         }
     }
-    fn le(&self, other: &Self) -> bool {
-        self.lt(other) || self.eq(other)
+
+    /// The [`Package::Ack`] the server answers an item or a clear with. `None` for anything
+    /// else, which isn't acknowledged.
+    pub fn ack(&self) -> Option<Package> {
+        match self {
+            Self::Item { time, seq, .. } | Self::Clear { time, seq } => Some(Self::Ack {
+                time: *time,
+                seq: *seq,
+            }),
+            _ => None,
+        }
     }
 
-    fn gt(&self, other: &Self) -> bool {
-        other.lt(self)
+    /// A package carrying `text`, stamped like any other item.
+    pub fn text(text: String) -> Self {
+        Self::from(TransferableItem::Text { text })
     }
 
-    fn ge(&self, other: &Self) -> bool {
-        self.gt(other) || self.eq(other)
+    /// A [`Package::Clear`] stamped now.
+    pub fn clear() -> Self {
+        let (time, seq) = stamp();
+        Self::Clear { time, seq }
     }
 
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        if self.lt(other) {
-            Some(std::cmp::Ordering::Less)
-        } else if self.gt(other) {
-            Some(std::cmp::Ordering::Greater)
-        } else if self.eq(other) {
-            Some(std::cmp::Ordering::Equal)
-        } else {
-            None
+    /// The text of a text item, the plain text of rich text, or a URL. `None` for anything else.
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Self::Item {
+                item: TransferableItem::Text { text },
+                ..
+            }
+            | Self::Item {
+                item: TransferableItem::RichText { plain: text, .. },
+                ..
+            }
+            | Self::Item {
+                item: TransferableItem::Url { url: text },
+                ..
+            } => Some(text),
+            _ => None,
+        }
+    }
+
+    /// [`TransferableItem::content_hash`] of an item, the same for copies of the same content
+    /// whenever and wherever they were made. `None` for anything else.
+    pub fn content_hash(&self) -> Option<u64> {
+        match self {
+            Self::Item { item, .. } => Some(item.content_hash()),
+            _ => None,
+        }
+    }
+
+    /// Records the application an item was copied from. Other packages are returned unchanged.
+    pub fn with_source_app(self, app: Option<String>) -> Self {
+        match self {
+            Self::Item {
+                time,
+                seq,
+                item,
+                label,
+                sensitive,
+                ..
+            } => Self::Item {
+                time,
+                seq,
+                item,
+                source_app: app,
+                label,
+                sensitive,
+            },
+            package => package,
+        }
+    }
+
+    /// Tags an item with `label`. Other packages are returned unchanged.
+    pub fn with_label(self, label: Option<String>) -> Self {
+        match self {
+            Self::Item {
+                time,
+                seq,
+                item,
+                source_app,
+                sensitive,
+                ..
+            } => Self::Item {
+                time,
+                seq,
+                item,
+                source_app,
+                label,
+                sensitive,
+            },
+            package => package,
+        }
+    }
+
+    /// Marks an item as a secret receivers keep out of clipboard histories. Other packages are
+    /// returned unchanged.
+    pub fn with_sensitive(self, sensitive: bool) -> Self {
+        match self {
+            Self::Item {
+                time,
+                seq,
+                item,
+                source_app,
+                label,
+                ..
+            } => Self::Item {
+                time,
+                seq,
+                item,
+                source_app,
+                label,
+                sensitive,
+            },
+            package => package,
+        }
+    }
+
+    /// Short name of what the package carries, e.g. `text` or `heartbeat`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Package::Empty => "empty",
+            Package::Item { item, .. } => item.kind(),
+            Package::Presence { .. } => "presence",
+            Package::Heartbeat => "heartbeat",
+            Package::HistoryRequest { .. } => "history request",
+            Package::HistoryEntry { .. } => "history entry",
+            Package::FileChunk { .. } => "file chunk",
+            Package::Clear { .. } => "clear",
+            Package::Ack { .. } => "ack",
+            Package::Resume { .. } => "resume",
         }
     }
+
+    /// Decodes a package from untrusted bytes. Never panics, malformed input is an error.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Package, NetworkError> {
+        bincode::deserialize(bytes).map_err(NetworkError::Deserialize)
+    }
 }
 
-impl Default for Package {
-    fn default() -> Self {
-        Self::Empty
+/// Which labelled clips a receiver applies. Unlabelled clips are always accepted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LabelFilter {
+    /// When not empty, only these labels are accepted
+    pub accept: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl LabelFilter {
+    pub fn allows(&self, package: &Package) -> bool {
+        let Package::Item {
+            label: Some(label), ..
+        } = package
+        else {
+            return true;
+        };
+        (self.accept.is_empty() || self.accept.contains(label)) && !self.deny.contains(label)
     }
 }
 
@@ -112,126 +935,993 @@ impl Default for Package {
 impl TryFrom<mac::Item> for Package {
     type Error = mac::Error;
     fn try_from(value: mac::Item) -> Result<Self, Self::Error> {
-        let item = TransferableItem::try_from(value)?;
-        match item {
-            TransferableItem::Text { .. } => {
-                let time = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-                Ok(Self::Item { time, item })
-            }
-            _ => Err(Self::Error::UnsupportedType),
-        }
+        Ok(Self::from(TransferableItem::try_from(value)?))
     }
 }
 
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// `time` and `seq` of a package created now.
+fn stamp() -> (u64, u64) {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    (time, NEXT_SEQ.fetch_add(1, Ordering::Relaxed))
+}
+
 impl From<TransferableItem> for Package {
     fn from(value: TransferableItem) -> Self {
+        let (time, seq) = stamp();
         Self::Item {
-            time: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
+            time,
+            seq,
             item: value,
+            source_app: None,
+            label: None,
+            sensitive: false,
         }
     }
 }
 
-pub fn send_package(
-    package: &Package,
-    stream: &mut TcpStream,
-    shared_key: &Option<SharedKey>,
-) -> std::io::Result<()> {
-    let mut bin_stream = bincode::serialize(package).expect("Failed to serialize");
+static MAX_PACKET_LOG_BYTES: AtomicUsize = AtomicUsize::new(64);
 
-    if let Some(key) = shared_key {
-        bin_stream = aead::seal(&key.key, &bin_stream).expect("Failed to encrypt message");
+/// Sets how many bytes of each raw package are hex dumped at trace level.
+pub fn set_max_packet_log_bytes(max: usize) {
+    MAX_PACKET_LOG_BYTES.store(max, Ordering::Relaxed);
+}
+
+fn hex_dump(bytes: &[u8]) -> String {
+    let max = MAX_PACKET_LOG_BYTES.load(Ordering::Relaxed);
+    let mut dump: Vec<String> = bytes.iter().take(max).map(|b| format!("{b:02x}")).collect();
+    if bytes.len() > max {
+        dump.push(format!("... ({} more bytes)", bytes.len() - max));
     }
+    dump.join(" ")
+}
 
-    let len = bin_stream.len();
-    let bin_len = len.to_be_bytes();
-    debug!("Sending {} bytes of data to {}", len, stream.peer_addr()?);
-    // debug!("Raw bytes sent: {:?}", bin_stream);
-
-    stream.write_all(&bin_len)?;
-    stream.write_all(&bin_stream)?;
-    debug!(
-        "Successfully send {} bytes of data to {:?}",
-        len,
-        stream.peer_addr()?
-    );
-    Ok(())
+/// Caps the package data being sent or received at once, summed over all connections.
+pub struct MemoryBudget {
+    capacity: usize,
+    used: Mutex<usize>,
+    released: Condvar,
 }
 
-pub fn receive_package(
-    stream: &mut TcpStream,
-    shared_key: &Option<SharedKey>,
-) -> std::io::Result<Package> {
-    let mut len_buffer = [0u8; 8];
-    let _res = stream.read_exact(&mut len_buffer)?;
-    let package_len = u64::from_be_bytes(len_buffer);
-
-    debug!(
-        "Incoming package of size {} from {}",
-        package_len,
-        stream.peer_addr()?
-    );
-
-    let mut buffer = vec![0u8; package_len as usize];
-    let _ = stream.read_exact(&mut buffer)?;
-    // debug!("Raw bytes received: {:?}", buffer);
-
-    let package: Package;
-    if let Some(key) = shared_key {
-        buffer = aead::open(&key.key, &buffer).expect("Failed to decrypt message");
-    }
-    package = bincode::deserialize(&buffer).expect("Failed to deserialize");
-    debug!("Package received ({}): {}", package_len, package);
-    Ok(package)
+/// Bytes reserved from a [`MemoryBudget`], given back when dropped.
+pub struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: usize,
 }
 
-pub fn send_session(stream: &mut TcpStream, session: &SessionInfo) -> std::io::Result<()> {
-    let bin_stream = bincode::serialize(session).expect("Unable to serialize session");
-    let len = bin_stream.len();
-    let bin_len = len.to_be_bytes();
+impl MemoryBudget {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
 
-    stream.write_all(&bin_len)?;
-    stream.write_all(&bin_stream)?;
-    Ok(())
+    /// Blocks until `bytes` fit in the budget. Requests larger than the whole budget are refused
+    /// instead of waiting forever.
+    pub fn reserve(&self, bytes: usize) -> std::io::Result<Reservation<'_>> {
+        if bytes > self.capacity {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::OutOfMemory,
+                format!(
+                    "Package of {bytes} bytes exceeds the memory budget of {} bytes",
+                    self.capacity
+                ),
+            ));
+        }
+        let mut used = self.used.lock().unwrap();
+        while *used + bytes > self.capacity {
+            debug!("Waiting for {bytes} bytes of memory budget");
+            used = self.released.wait(used).unwrap();
+        }
+        *used += bytes;
+        Ok(Reservation {
+            budget: self,
+            bytes,
+        })
+    }
+
+    /// Like [`MemoryBudget::reserve`], but refuses instead of waiting when `bytes` don't fit
+    /// right now, for data that may never be released if the caller blocks.
+    pub fn try_reserve(&self, bytes: usize) -> std::io::Result<Reservation<'_>> {
+        let mut used = self.used.lock().unwrap();
+        if *used + bytes > self.capacity {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::OutOfMemory,
+                format!(
+                    "{bytes} bytes don't fit in the memory budget, {} of {} bytes are used",
+                    *used, self.capacity
+                ),
+            ));
+        }
+        *used += bytes;
+        Ok(Reservation {
+            budget: self,
+            bytes,
+        })
+    }
 }
 
-pub fn receive_session(stream: &mut TcpStream) -> std::io::Result<SessionInfo> {
-    let mut len_buffer = [0u8; 8];
-    stream.read_exact(&mut len_buffer)?;
-    let len = u64::from_be_bytes(len_buffer);
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        *self.budget.used.lock().unwrap() -= self.bytes;
+        self.budget.released.notify_all();
+    }
+}
 
-    let mut buffer = vec![0u8; len as usize];
-    let _ = stream.read_exact(&mut buffer)?;
+/// Largest frame accepted unless changed with [`set_max_package_len`].
+pub const MAX_PACKAGE_LEN: u64 = 512 * 1024 * 1024;
 
-    let session: SessionInfo = bincode::deserialize(&buffer).expect("Failed to deserialize");
+static MAX_PACKAGE_LEN_LIMIT: AtomicU64 = AtomicU64::new(MAX_PACKAGE_LEN);
 
-    debug!("Received session: {:?}", session);
+/// Frames declaring more than `max` bytes are refused before anything is allocated for them.
+pub fn set_max_package_len(max: u64) {
+    MAX_PACKAGE_LEN_LIMIT.store(max, Ordering::Relaxed);
+}
 
-    Ok(session)
+/// Refuses a frame declaring `len` bytes if that exceeds the limit, see [`set_max_package_len`].
+pub fn check_frame_len(len: u64) -> std::io::Result<()> {
+    let max = MAX_PACKAGE_LEN_LIMIT.load(Ordering::Relaxed);
+    if len > max {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Frame of {len} bytes exceeds the limit of {max} bytes"),
+        ));
+    }
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use orion::aead;
+static MEMORY_BUDGET: OnceLock<MemoryBudget> = OnceLock::new();
 
-    use super::{Package, TransferableItem};
+/// Limits the package data in flight in `send_package`/`receive_package` across all threads.
+/// Can only be set once, before any package is exchanged.
+pub fn set_memory_budget(bytes: usize) {
+    if MEMORY_BUDGET.set(MemoryBudget::new(bytes)).is_err() {
+        panic!("Memory budget is already set");
+    }
+}
 
-    #[test]
-    fn shared_key_encryption_test() {
-        let key = aead::SecretKey::default();
-        let msg = "Hello world".to_string();
+fn reserve_memory(bytes: usize) -> std::io::Result<Option<Reservation<'static>>> {
+    MEMORY_BUDGET
+        .get()
+        .map(|budget| budget.reserve(bytes))
+        .transpose()
+}
 
-        // Sender
-        let package = Package::from(TransferableItem::from(msg));
-        println!("Package: {package:?}");
+/// Reserves `bytes` from the memory budget without waiting, see [`MemoryBudget::try_reserve`].
+pub(crate) fn try_reserve_memory(bytes: usize) -> std::io::Result<Option<Reservation<'static>>> {
+    MEMORY_BUDGET
+        .get()
+        .map(|budget| budget.try_reserve(bytes))
+        .transpose()
+}
 
-        let bin_stream = bincode::serialize(&package).unwrap();
+/// Host name of this machine, used as the default device name.
+pub fn default_device_name() -> String {
+    #[cfg(unix)]
+    {
+        let mut buffer = [0u8; 256];
+        // Safety: the buffer is valid for its whole length
+        if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } == 0 {
+            let len = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+            return String::from_utf8_lossy(&buffer[..len]).into_owned();
+        }
+    }
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Exponential backoff between reconnect attempts.
+///
+/// Each delay is picked at random between half and all of the exponential step, so clients that
+/// lost the same server at the same moment spread their attempts instead of retrying in lockstep.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Delay to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        use rand_core::{OsRng, RngCore};
+
+        let step = self
+            .base
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt += 1;
+        let half = step / 2;
+        let jitter = OsRng.next_u64() % (half.as_millis() as u64 + 1);
+        step - half + Duration::from_millis(jitter).min(half)
+    }
+
+    /// Starts over from `base` once a connection succeeded.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// The byte stream packages travel over.
+pub enum Stream {
+    Tcp(TcpStream),
+    Tls(TlsStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(match self {
+            Self::Tcp(stream) => Self::Tcp(stream.try_clone()?),
+            Self::Tls(stream) => Self::Tls(stream.try_clone()?),
+        })
+    }
+
+    /// The socket underneath, e.g. to shut it down.
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Self::Tcp(stream) => stream,
+            Self::Tls(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Self::Tcp(stream)
+    }
+}
+
+impl From<TlsStream> for Stream {
+    fn from(stream: TlsStream) -> Self {
+        Self::Tls(stream)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Why a handshake failed.
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(std::io::Error),
+    /// The client speaks this other protocol version
+    VersionMismatch(u32),
+    /// The server refused the session, with the reason when it gave one, see
+    /// [`Connection::accept`] and [`Connection::refuse`]
+    Refused(Option<String>),
+    /// The server requires encryption, either because it was told to or because it has a
+    /// pre-shared key, which only works with encryption
+    EncryptionRequired,
+    /// The peers have no cipher in common
+    NoCommonCipher,
+    /// The client failed to prove the pre-shared key
+    PskRejected,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::VersionMismatch(version) => write!(
+                f,
+                "Speaks protocol version {version}, expected {PROTOCOL_VERSION}"
+            ),
+            Self::Refused(Some(reason)) => write!(f, "Session refused: {reason}"),
+            Self::Refused(None) => write!(
+                f,
+                "Session refused: client and server need to run the same version of clipper \
+                 (protocol version {PROTOCOL_VERSION}), and servers with a pre-shared key also \
+                 require encryption"
+            ),
+            Self::EncryptionRequired => write!(f, "Encryption is required by the server"),
+            Self::NoCommonCipher => write!(f, "No cipher is supported by both sides"),
+            Self::PskRejected => write!(f, "Pre-shared key refused"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<std::io::Error> for HandshakeError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// One end of an x25519 key exchange whose public keys have been swapped, run by
+/// [`client_handshake`] or [`server_handshake`].
+pub struct KeyExchange {
+    secret: x25519_dalek::EphemeralSecret,
+    /// Public key of the other end, one of the two below
+    peer_public: [u8; 32],
+    pub server_public: [u8; 32],
+    pub client_public: [u8; 32],
+}
+
+impl KeyExchange {
+    /// The key both ends derive, with the default cipher and no associated data.
+    pub fn shared_key(self) -> SharedKey {
+        let peer_public = x25519_dalek::PublicKey::from(self.peer_public);
+        SharedKey::from(self.secret.diffie_hellman(&peer_public).as_bytes())
+    }
+}
+
+/// Client side of the key exchange: reads the server's public key, then sends its own.
+pub fn client_handshake<S: Read + Write>(stream: &mut S) -> std::io::Result<KeyExchange> {
+    use rand_core::OsRng;
+    use x25519_dalek::{EphemeralSecret, PublicKey};
+
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let client_public = PublicKey::from(&secret).to_bytes();
+
+    let mut server_public = [0u8; 32];
+    stream.read_exact(&mut server_public)?;
+    stream.write_all(&client_public)?;
+    stream.flush()?;
+
+    Ok(KeyExchange {
+        secret,
+        peer_public: server_public,
+        server_public,
+        client_public,
+    })
+}
+
+/// Server side of the key exchange: sends its public key, then reads the client's.
+pub fn server_handshake<S: Read + Write>(stream: &mut S) -> std::io::Result<KeyExchange> {
+    use rand_core::OsRng;
+    use x25519_dalek::{EphemeralSecret, PublicKey};
+
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let server_public = PublicKey::from(&secret).to_bytes();
+
+    stream.write_all(&server_public)?;
+    stream.flush()?;
+    let mut client_public = [0u8; 32];
+    stream.read_exact(&mut client_public)?;
+
+    Ok(KeyExchange {
+        secret,
+        peer_public: client_public,
+        server_public,
+        client_public,
+    })
+}
+
+/// Buffered connection to a peer, the client's to the server or the server's to one client.
+///
+/// Once [`Connection::handshake`] (client side) or [`Connection::accept`] (server side) has
+/// agreed on encryption and encoding, [`Connection::send`] and [`Connection::recv`] exchange
+/// packages accordingly:
+///
+/// ```
+/// use clipper::network::{Connection, Package, SessionInfo};
+/// use std::net::TcpListener;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0")?;
+/// let addr = listener.local_addr()?;
+///
+/// // The server, usually another process
+/// let server = std::thread::spawn(move || {
+///     let (stream, _) = listener.accept().unwrap();
+///     let mut connection = Connection::new(stream).unwrap();
+///     let session = connection.accept(None, false).unwrap();
+///     (session.device_name, connection.recv().unwrap())
+/// });
+///
+/// let mut connection = Connection::connect(addr, &SessionInfo::new("laptop"))?;
+/// connection.send(&Package::text("Hello".to_string()))?;
+///
+/// let (device_name, package) = server.join().unwrap();
+/// assert_eq!(device_name, "laptop");
+/// assert_eq!(package.as_text(), Some("Hello"));
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// Reads and writes go through separate buffers over clones of the same stream, so a package
+/// costs one syscall per direction instead of one per `read_exact`/`write_all`.
+pub struct Connection {
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    shared_key: Arc<Option<SharedKey>>,
+    encoding: Encoding,
+}
+
+impl Connection {
+    /// Wraps a stream on which no handshake has run yet.
+    pub fn new(stream: impl Into<Stream>) -> std::io::Result<Self> {
+        let stream = stream.into();
+        let write_stream = stream.try_clone()?;
+        Ok(Self {
+            reader: BufReader::new(stream),
+            writer: BufWriter::new(write_stream),
+            shared_key: Arc::new(None),
+            encoding: Encoding::default(),
+        })
+    }
+
+    /// Connects to the server at `addr` and runs the handshake for `session`.
+    pub fn connect(addr: SocketAddr, session: &SessionInfo) -> Result<Self, HandshakeError> {
+        let mut connection = Self::new(TcpStream::connect(addr)?)?;
+        connection.handshake(session, None)?;
+        Ok(connection)
+    }
+
+    /// Runs the client side of the handshake for `session`, proving `psk` to the server when
+    /// given, which requires `session.use_encryption`.
+    pub fn handshake(
+        &mut self,
+        session: &SessionInfo,
+        psk: Option<&PreSharedKey>,
+    ) -> Result<(), HandshakeError> {
+        self.shared_key = Arc::new(None);
+        send_session(self, session)?;
+        let encoding = match receive_session_reply(self)? {
+            SessionReply::Accepted(encoding) => encoding,
+            SessionReply::Refused(reason) => return Err(HandshakeError::Refused(reason)),
+        };
+        self.encoding = encoding;
+        match (session.compression, encoding.compression) {
+            (_, Some(codec)) => info!("Compressing with {codec}"),
+            (Some(codec), None) => warn!("The server doesn't support {codec}, not compressing"),
+            (None, None) => {}
+        }
+        if session.format != encoding.format {
+            warn!(
+                "The server doesn't support {}, using {}",
+                session.format, encoding.format
+            );
+        } else if encoding.format != Format::Bincode {
+            info!("Serializing to {}", encoding.format);
+        }
+        if !session.use_encryption {
+            return Ok(());
+        }
+
+        let cipher = receive_cipher(self)?.ok_or(HandshakeError::NoCommonCipher)?;
+        info!("Encrypting with {cipher}");
+
+        let exchange = client_handshake(self)?;
+
+        if let Some(psk) = psk {
+            let proof = psk.prove(&exchange.server_public, &exchange.client_public);
+            send_psk_proof(self, &proof)?;
+            if !receive_session_accepted(self)? {
+                return Err(HandshakeError::PskRejected);
+            }
+        }
+
+        self.shared_key = Arc::new(Some(
+            exchange
+                .shared_key()
+                .with_cipher(cipher)
+                .with_associated_data(session.associated_data())
+                .with_role(Role::Client),
+        ));
+
+        debug!("Shared key: {:?}", self.shared_key);
+        Ok(())
+    }
+
+    /// Runs the server side of the handshake and returns the session the client asked for.
+    /// Clients speaking another protocol version are refused, and so are clients that don't
+    /// encrypt when `require_encryption` or `psk` is given, or fail to prove `psk`.
+    pub fn accept(
+        &mut self,
+        psk: Option<&PreSharedKey>,
+        require_encryption: bool,
+    ) -> Result<SessionInfo, HandshakeError> {
+        let session = receive_session(self)?;
+        if session.protocol_version != PROTOCOL_VERSION {
+            send_session_accepted(self, false)?;
+            return Err(HandshakeError::VersionMismatch(session.protocol_version));
+        }
+        // The proof is made over the key exchange, which only encrypted sessions run
+        if (require_encryption || psk.is_some()) && !session.use_encryption {
+            send_session_refusal(self, "The server requires encryption")?;
+            return Err(HandshakeError::EncryptionRequired);
+        }
+        let encoding = Encoding {
+            // Every format is supported
+            format: session.format,
+            compression: Codec::negotiate(session.compression),
+        };
+        send_session_reply(self, encoding)?;
+        self.encoding = encoding;
+        if let Some(codec) = encoding.compression {
+            debug!("Compressing with {codec}");
+        }
+        debug!("Serializing to {}", encoding.format);
+        if !session.use_encryption {
+            return Ok(session);
+        }
+
+        let cipher = Cipher::negotiate(&session.ciphers);
+        send_cipher(self, cipher)?;
+        let cipher = cipher.ok_or(HandshakeError::NoCommonCipher)?;
+
+        let exchange = server_handshake(self)?;
+
+        if let Some(psk) = psk {
+            let proof = receive_psk_proof(self)?;
+            let valid = psk.verify(&proof, &exchange.server_public, &exchange.client_public);
+            send_session_accepted(self, valid)?;
+            if !valid {
+                return Err(HandshakeError::PskRejected);
+            }
+        }
+
+        self.shared_key = Arc::new(Some(
+            exchange
+                .shared_key()
+                .with_cipher(cipher)
+                .with_associated_data(session.associated_data())
+                .with_role(Role::Server),
+        ));
+
+        debug!("Shared key: {:?}", self.shared_key);
+        Ok(session)
+    }
+
+    /// Reads the session of a client that can't be served and refuses it, telling it `reason`.
+    /// The session is returned for the logs.
+    pub fn refuse(&mut self, reason: &str) -> Result<SessionInfo, HandshakeError> {
+        let session = receive_session(self)?;
+        send_session_refusal(self, reason)?;
+        Ok(session)
+    }
+
+    /// Sends `package`, encrypted and encoded as agreed in the handshake.
+    pub fn send(&mut self, package: &Package) -> Result<(), NetworkError> {
+        send_package(package, &mut self.writer, &self.shared_key, self.encoding)?;
+        Ok(())
+    }
+
+    /// Waits for the next package from the peer.
+    pub fn recv(&mut self) -> Result<Package, NetworkError> {
+        receive_package(&mut self.reader, &self.shared_key, self.encoding)
+    }
+
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.reader.get_ref().tcp().peer_addr()
+    }
+
+    /// Key packages are sealed with, `None` for unencrypted sessions.
+    pub fn shared_key(&self) -> Arc<Option<SharedKey>> {
+        Arc::clone(&self.shared_key)
+    }
+
+    /// Format and codec packages are encoded with.
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// The read and write halves, so one thread can listen while another sends. Packages go
+    /// through them with [`receive_package`] and [`send_package`], passing
+    /// [`Connection::shared_key`] and [`Connection::encoding`].
+    pub fn halves(&mut self) -> (&mut BufReader<Stream>, &mut BufWriter<Stream>) {
+        (&mut self.reader, &mut self.writer)
+    }
+
+    /// Splits the connection into its read and write halves so they can be handed to the
+    /// listener and sender threads. Data already buffered by the reader is kept.
+    pub fn split(self) -> (BufReader<Stream>, BufWriter<Stream>) {
+        (self.reader, self.writer)
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes `payload` prefixed with its length as an LEB128 varint, so the small text packages
+/// that make up most of the traffic pay one or two bytes of framing instead of eight.
+pub fn write_frame<W: Write>(stream: &mut W, payload: &[u8]) -> std::io::Result<()> {
+    let mut len = payload.len() as u64;
+    let mut prefix = Vec::with_capacity(MAX_VARINT_LEN);
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            prefix.push(byte);
+            break;
+        }
+        prefix.push(byte | 0x80);
+    }
+
+    stream.write_all(&prefix)?;
+    stream.write_all(payload)?;
+    stream.flush()
+}
+
+const MAX_VARINT_LEN: usize = 10;
+
+/// Reads the length prefix written by [`write_frame`].
+pub fn read_frame_len<R: Read>(stream: &mut R) -> std::io::Result<u64> {
+    let mut len = 0u64;
+    for i in 0..MAX_VARINT_LEN {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte)?;
+        let bits = (byte[0] & 0x7f) as u64;
+        if i == MAX_VARINT_LEN - 1 && bits > 1 {
+            break;
+        }
+        len |= bits << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(len);
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "Frame length does not fit in 64 bits",
+    ))
+}
+
+/// Reads the `len` bytes of a frame. The buffer grows as data arrives rather than being
+/// allocated upfront, so a peer that declares a large frame and then stalls or hangs up doesn't
+/// cost its full size.
+fn read_payload<R: Read>(stream: &mut R, len: u64) -> std::io::Result<Vec<u8>> {
+    const CHUNK_LEN: u64 = 64 * 1024;
+
+    let mut buffer = Vec::with_capacity(len.min(CHUNK_LEN) as usize);
+    let read = stream.take(len).read_to_end(&mut buffer)?;
+    if (read as u64) < len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("Frame ended after {read} of {len} bytes"),
+        ));
+    }
+    Ok(buffer)
+}
+
+pub fn read_frame<R: Read>(stream: &mut R) -> std::io::Result<Vec<u8>> {
+    let len = read_frame_len(stream)?;
+    check_frame_len(len)?;
+    read_payload(stream, len)
+}
+
+/// What sending one package took, returned and logged by [`send_package`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferStats {
+    /// See [`Package::kind`]
+    pub kind: &'static str,
+    pub serialized_bytes: usize,
+    /// `None` when the connection isn't compressed
+    pub compressed_bytes: Option<usize>,
+    /// `None` when the connection isn't encrypted
+    pub encrypted_bytes: Option<usize>,
+    pub serialize_time: Duration,
+    /// Time spent writing the frame, which includes waiting for a slow peer
+    pub send_time: Duration,
+}
+
+impl TransferStats {
+    /// Payload bytes put on the wire, the frame's length prefix aside.
+    pub fn sent_bytes(&self) -> usize {
+        self.encrypted_bytes
+            .or(self.compressed_bytes)
+            .unwrap_or(self.serialized_bytes)
+    }
+}
+
+impl fmt::Display for TransferStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Sent {}: {} bytes serialized in {:?}",
+            self.kind, self.serialized_bytes, self.serialize_time
+        )?;
+        if let Some(compressed) = self.compressed_bytes {
+            write!(f, ", {compressed} compressed")?;
+        }
+        if let Some(encrypted) = self.encrypted_bytes {
+            write!(f, ", {encrypted} encrypted")?;
+        }
+        write!(f, ", written in {:?}", self.send_time)
+    }
+}
+
+/// Running totals of the [`TransferStats`] of the packages sent to one peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferTotals {
+    pub packages: u64,
+    pub bytes: u64,
+    pub send_time: Duration,
+}
+
+impl TransferTotals {
+    pub fn add(&mut self, stats: &TransferStats) {
+        self.packages += 1;
+        self.bytes += stats.sent_bytes() as u64;
+        self.send_time += stats.send_time;
+    }
+}
+
+impl fmt::Display for TransferTotals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} packages, {} bytes in {:?}",
+            self.packages, self.bytes, self.send_time
+        )
+    }
+}
+
+pub fn send_package<W: Write>(
+    package: &Package,
+    stream: &mut W,
+    shared_key: &Option<SharedKey>,
+    encoding: Encoding,
+) -> Result<TransferStats, NetworkError> {
+    let started = Instant::now();
+    // Nothing has been written yet, so on failure the connection is still usable
+    let (_reservation, mut bin_stream) = match encoding.format {
+        Format::Bincode => {
+            let size = bincode::serialized_size(package).map_err(NetworkError::Serialize)?;
+            let reservation = reserve_memory(size as usize)?;
+            (reservation, encoding.format.serialize(package)?)
+        }
+        // JSON can only be measured by writing it out
+        Format::Json => {
+            let bin_stream = encoding.format.serialize(package)?;
+            (reserve_memory(bin_stream.len())?, bin_stream)
+        }
+    };
+    let mut stats = TransferStats {
+        kind: package.kind(),
+        serialized_bytes: bin_stream.len(),
+        compressed_bytes: None,
+        encrypted_bytes: None,
+        serialize_time: started.elapsed(),
+        send_time: Duration::ZERO,
+    };
+
+    // Compressing sealed bytes would gain nothing, so this has to come first
+    if let Some(codec) = encoding.compression {
+        bin_stream = codec.compress(&bin_stream);
+        stats.compressed_bytes = Some(bin_stream.len());
+    }
+
+    if let Some(key) = shared_key {
+        bin_stream = key
+            .seal(&bin_stream)
+            .map_err(NetworkError::EncryptionFailed)?;
+        stats.encrypted_bytes = Some(bin_stream.len());
+    }
+
+    let len = bin_stream.len();
+    debug!("Sending {} bytes of data", len);
+    trace!("Raw bytes sent: {}", hex_dump(&bin_stream));
+
+    let sending = Instant::now();
+    write_frame(stream, &bin_stream)?;
+    stats.send_time = sending.elapsed();
+    // Heartbeats would drown out everything else
+    if matches!(package, Package::Heartbeat) {
+        debug!("{stats}");
+    } else {
+        info!("{stats}");
+    }
+    Ok(stats)
+}
+
+pub fn receive_package<R: Read>(
+    stream: &mut R,
+    shared_key: &Option<SharedKey>,
+    encoding: Encoding,
+) -> Result<Package, NetworkError> {
+    receive_package_within(stream, shared_key, encoding, MEMORY_BUDGET.get())
+}
+
+fn receive_package_within<R: Read>(
+    stream: &mut R,
+    shared_key: &Option<SharedKey>,
+    encoding: Encoding,
+    budget: Option<&MemoryBudget>,
+) -> Result<Package, NetworkError> {
+    let reserve = |bytes| budget.map(|budget| budget.reserve(bytes)).transpose();
+    let package_len = read_frame_len(stream)?;
+
+    debug!("Incoming package of size {}", package_len);
+
+    check_frame_len(package_len)?;
+    let mut reservation = reserve(package_len as usize)?;
+    let mut buffer = read_payload(stream, package_len)?;
+    trace!("Raw bytes received: {}", hex_dump(&buffer));
+
+    if let Some(key) = shared_key {
+        buffer = key.open(&buffer)?;
+    }
+    if let Some(codec) = encoding.compression {
+        let size = codec.decompressed_len(&buffer)?;
+        // The inflated copy sits next to the frame until it is deserialized. Both are reserved
+        // in one go, so waiting for the budget never holds part of it.
+        drop(reservation);
+        reservation = reserve(package_len as usize + size)?;
+        buffer = codec.decompress(&buffer)?;
+    }
+    let _reservation = reservation;
+    let package = encoding.format.deserialize(&buffer)?;
+    debug!("Package received ({}): {}", package_len, package.redacted());
+    Ok(package)
+}
+
+pub fn send_session<W: Write>(stream: &mut W, session: &SessionInfo) -> std::io::Result<()> {
+    let mut bin_stream = bincode::serialize(session).map_err(NetworkError::Serialize)?;
+    let encoding = Encoding {
+        format: session.format,
+        compression: session.compression,
+    };
+    bin_stream.extend(encoding.to_trailer()?);
+    write_frame(stream, &bin_stream)
+}
+
+pub fn receive_session<R: Read>(stream: &mut R) -> std::io::Result<SessionInfo> {
+    use bincode::Options;
+
+    let len = read_frame_len(stream)?;
+    if len > MAX_SESSION_LEN {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Session of {len} bytes exceeds the limit of {MAX_SESSION_LEN} bytes"),
+        ));
+    }
+    let buffer = read_payload(stream, len)?;
+
+    let mut rest = buffer.as_slice();
+    // Same encoding as `bincode::deserialize`, but the lengths inside the session are checked
+    // against the frame before anything is allocated for them
+    let mut session: SessionInfo = bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(len)
+        .deserialize_from(&mut rest)
+        .map_err(NetworkError::Deserialize)?;
+    // Older clients send nothing after the session, an encoding unknown to this build reads as
+    // the default
+    let encoding = Encoding::from_trailer(rest).unwrap_or_default();
+    session.compression = encoding.compression;
+    session.format = encoding.format;
+
+    debug!("Received session: {:?}", session);
+
+    Ok(session)
+}
+
+/// Tells the client whether its session was accepted. Sent right after the session, before
+/// anything else.
+pub fn send_session_accepted<W: Write>(stream: &mut W, accepted: bool) -> std::io::Result<()> {
+    let bin_stream = bincode::serialize(&accepted).map_err(NetworkError::Serialize)?;
+    write_frame(stream, &bin_stream)
+}
+
+pub fn receive_session_accepted<R: Read>(stream: &mut R) -> std::io::Result<bool> {
+    let buffer = read_frame(stream)?;
+    Ok(bincode::deserialize(&buffer).map_err(NetworkError::Deserialize)?)
+}
+
+/// Accepts the session and tells the client how both sides encode packages: the codec is
+/// `None` when its request isn't supported. Clients that predate compression read the verdict
+/// like one sent by [`send_session_accepted`] and ignore the encoding after it.
+pub fn send_session_reply<W: Write>(stream: &mut W, encoding: Encoding) -> std::io::Result<()> {
+    let mut bin_stream = bincode::serialize(&true).map_err(NetworkError::Serialize)?;
+    bin_stream.extend(encoding.to_trailer()?);
+    write_frame(stream, &bin_stream)
+}
+
+/// Refuses the session and tells the client why. Clients that predate reasons read it like a
+/// refusal sent by [`send_session_accepted`].
+pub fn send_session_refusal<W: Write>(stream: &mut W, reason: &str) -> std::io::Result<()> {
+    let mut bin_stream = bincode::serialize(&false).map_err(NetworkError::Serialize)?;
+    bin_stream.extend(bincode::serialize(reason).map_err(NetworkError::Serialize)?);
+    write_frame(stream, &bin_stream)
+}
+
+/// The server's verdict on a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionReply {
+    /// With how packages are encoded, which is always the default from servers that predate
+    /// compression
+    Accepted(Encoding),
+    /// With the reason, which only [`send_session_refusal`] gives
+    Refused(Option<String>),
+}
+
+pub fn receive_session_reply<R: Read>(stream: &mut R) -> std::io::Result<SessionReply> {
+    let buffer = read_frame(stream)?;
+    let mut rest = buffer.as_slice();
+    let accepted = bincode::deserialize_from(&mut rest).map_err(NetworkError::Deserialize)?;
+    Ok(if accepted {
+        SessionReply::Accepted(Encoding::from_trailer(rest)?)
+    } else {
+        SessionReply::Refused(bincode::deserialize(rest).ok())
+    })
+}
+
+pub fn send_psk_proof<W: Write>(stream: &mut W, proof: &[u8]) -> std::io::Result<()> {
+    write_frame(stream, proof)
+}
+
+pub fn receive_psk_proof<R: Read>(stream: &mut R) -> std::io::Result<Vec<u8>> {
+    read_frame(stream)
+}
+
+/// Server's answer to the ciphers offered in [`SessionInfo`], `None` when none is supported.
+pub fn send_cipher<W: Write>(stream: &mut W, cipher: Option<Cipher>) -> std::io::Result<()> {
+    let bin_stream = bincode::serialize(&cipher).map_err(NetworkError::Serialize)?;
+    write_frame(stream, &bin_stream)
+}
+
+pub fn receive_cipher<R: Read>(stream: &mut R) -> std::io::Result<Option<Cipher>> {
+    let buffer = read_frame(stream)?;
+    let cipher = bincode::deserialize(&buffer).map_err(NetworkError::Deserialize)?;
+    debug!("Negotiated cipher: {:?}", cipher);
+    Ok(cipher)
+}
+
+#[cfg(test)]
+mod tests {
+    use orion::aead;
+
+    use super::{Cipher, Encoding, Format, Package, SessionInfo, TransferableItem};
+
+    #[test]
+    fn shared_key_encryption_test() {
+        let key = aead::SecretKey::default();
+        let msg = "Hello world".to_string();
+
+        // Sender
+        let package = Package::from(TransferableItem::from(msg));
+        println!("Package: {package:?}");
+
+        let bin_stream = bincode::serialize(&package).unwrap();
         let encrypted_bin_stream = aead::seal(&key, &bin_stream).unwrap();
         let decrypted_bin_stream = aead::open(&key, &encrypted_bin_stream).unwrap();
 
@@ -241,4 +1931,1123 @@ mod tests {
 
         assert_eq!(decrypted_payload, package);
     }
+
+    #[test]
+    fn shared_key_associated_data_test() {
+        use super::{SharedKey, SEQUENCE_LEN};
+
+        let secret = [7u8; 32];
+        let key = SharedKey::from(&secret).with_associated_data(b"session a".to_vec());
+        let sealed = key.seal(b"Hello world").unwrap();
+        assert_eq!(key.open(&sealed).unwrap(), b"Hello world");
+
+        let other_session = SharedKey::from(&secret).with_associated_data(b"session b".to_vec());
+        assert!(other_session.open(&sealed).is_err());
+        assert!(key.open(&sealed[..10]).is_err());
+
+        for cipher in Cipher::SUPPORTED {
+            let key = SharedKey::from(&secret).with_cipher(cipher);
+            let sealed = key.seal(b"Hello world").unwrap();
+            assert_eq!(key.open(&sealed).unwrap(), b"Hello world");
+        }
+        let xchacha = SharedKey::from(&secret).with_cipher(Cipher::XChaCha20Poly1305);
+        let chacha = SharedKey::from(&secret).with_cipher(Cipher::ChaCha20Poly1305);
+        let aes = SharedKey::from(&secret).with_cipher(Cipher::Aes256Gcm);
+        assert!(chacha.open(&xchacha.seal(b"Hello world").unwrap()).is_err());
+        assert!(aes.open(&chacha.seal(b"Hello world").unwrap()).is_err());
+        let sealed = aes.seal(b"Hello world").unwrap();
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(aes.open(&tampered).is_err());
+        assert_eq!(aes.open(&sealed).unwrap(), b"Hello world");
+        assert_eq!(
+            Cipher::negotiate(&[Cipher::ChaCha20Poly1305, Cipher::XChaCha20Poly1305]),
+            Some(Cipher::ChaCha20Poly1305)
+        );
+        assert_eq!(
+            Cipher::negotiate(&[Cipher::Aes256Gcm, Cipher::XChaCha20Poly1305]),
+            Some(Cipher::Aes256Gcm)
+        );
+        assert_eq!(Cipher::negotiate(&[]), None);
+
+        // Without associated data the layout is the same as orion's high level API, the
+        // plaintext starting with the sequence number
+        let plain_key = SharedKey::from(&secret);
+        let sealed = plain_key.seal(b"Hello world").unwrap();
+        let opened = aead::open(&plain_key.key, &sealed).unwrap();
+        assert_eq!(opened[..SEQUENCE_LEN], 1u64.to_le_bytes());
+        assert_eq!(&opened[SEQUENCE_LEN..], b"Hello world");
+    }
+
+    #[test]
+    fn package_from_bytes_rejects_garbage_test() {
+        let package = Package::text("Hello".to_string());
+        let bin_stream = bincode::serialize(&package).unwrap();
+        assert_eq!(Package::from_bytes(&bin_stream).unwrap(), package);
+
+        assert!(Package::from_bytes(&[]).is_err());
+        assert!(Package::from_bytes(&[0xff; 16]).is_err());
+        assert!(Package::from_bytes(&bin_stream[..bin_stream.len() - 1]).is_err());
+
+        // Cheap stand-in for the fuzz target: arbitrary bytes must never panic
+        let mut state = 0x2545f4914f6cdd1du64;
+        for len in 0..512 {
+            let bytes: Vec<u8> = (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect();
+            let _ = Package::from_bytes(&bytes);
+        }
+    }
+
+    #[test]
+    fn memory_budget_test() {
+        use super::MemoryBudget;
+        use std::sync::Arc;
+
+        let budget = Arc::new(MemoryBudget::new(100));
+        assert!(budget.reserve(101).is_err());
+
+        let first = budget.reserve(60).unwrap();
+        let waiter = {
+            let budget = Arc::clone(&budget);
+            std::thread::spawn(move || {
+                let _second = budget.reserve(60).unwrap();
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+
+        drop(first);
+        waiter.join().unwrap();
+        let all = budget.reserve(100).unwrap();
+        // Refused right away rather than waiting for the reservation to go
+        assert!(budget.try_reserve(1).is_err());
+        drop(all);
+        let _some = budget.try_reserve(1).unwrap();
+    }
+
+    #[test]
+    fn varint_frame_test() {
+        use super::{read_frame, read_frame_len, write_frame};
+
+        for len in [0u64, 1, 127, 128, 300, 16_384, u32::MAX as u64, u64::MAX] {
+            let mut prefix = vec![];
+            let mut value = len;
+            loop {
+                let byte = (value & 0x7f) as u8;
+                value >>= 7;
+                prefix.push(if value == 0 { byte } else { byte | 0x80 });
+                if value == 0 {
+                    break;
+                }
+            }
+            assert_eq!(read_frame_len(&mut prefix.as_slice()).unwrap(), len);
+        }
+
+        let mut frame = vec![];
+        write_frame(&mut frame, b"Hi").unwrap();
+        assert_eq!(frame, [2, b'H', b'i']);
+        assert_eq!(read_frame(&mut frame.as_slice()).unwrap(), b"Hi");
+
+        let mut frame = vec![];
+        write_frame(&mut frame, &[0u8; 300]).unwrap();
+        assert_eq!(&frame[..2], [0xac, 0x02]);
+        assert_eq!(read_frame(&mut frame.as_slice()).unwrap().len(), 300);
+
+        let overlong = [0xffu8; 11];
+        assert!(read_frame_len(&mut overlong.as_slice()).is_err());
+        let overflow = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02];
+        assert!(read_frame_len(&mut overflow.as_slice()).is_err());
+    }
+
+    #[test]
+    fn source_app_test() {
+        let package = Package::text("Hi".to_string()).with_source_app(Some("chrome".to_string()));
+        assert!(package.to_string().ends_with("From: chrome }"));
+        let bytes = bincode::serialize(&package).unwrap();
+        assert_eq!(Package::from_bytes(&bytes).unwrap(), package);
+        assert_eq!(
+            Package::Empty.with_source_app(Some("chrome".to_string())),
+            Package::Empty
+        );
+    }
+
+    /// Bincode layout of packages as sent on the wire. If this fails, the change breaks
+    /// compatibility with every device running an older build.
+    #[test]
+    fn package_wire_format_test() {
+        let item = |text: &str, source_app: Option<&str>, label: Option<&str>| Package::Item {
+            time: 1_700_000_000_000,
+            seq: 7,
+            item: TransferableItem::from(text.to_string()),
+            source_app: source_app.map(str::to_string),
+            label: label.map(str::to_string),
+            sensitive: false,
+        };
+        let golden: Vec<(Package, Vec<u8>)> = vec![
+            (Package::Empty, vec![0, 0, 0, 0]),
+            (
+                item("Hi", None, None),
+                vec![
+                    1, 0, 0, 0, // Package::Item
+                    0x00, 0x68, 0xe5, 0xcf, 0x8b, 0x01, 0, 0, // time
+                    7, 0, 0, 0, 0, 0, 0, 0, // seq
+                    14, 0, 0, 0, 0, 0, 0, 0, // item length
+                    1, 0, 0, 0, // TransferableItem::Text
+                    2, 0, 0, 0, 0, 0, 0, 0, b'H', b'i', // text
+                    0,    // source_app
+                    0,    // label
+                    0,    // sensitive
+                ],
+            ),
+            (
+                item("", Some("a"), Some("w")),
+                vec![
+                    1, 0, 0, 0, // Package::Item
+                    0x00, 0x68, 0xe5, 0xcf, 0x8b, 0x01, 0, 0, // time
+                    7, 0, 0, 0, 0, 0, 0, 0, // seq
+                    12, 0, 0, 0, 0, 0, 0, 0, // item length
+                    1, 0, 0, 0, // TransferableItem::Text
+                    0, 0, 0, 0, 0, 0, 0, 0, // text
+                    1, 1, 0, 0, 0, 0, 0, 0, 0, b'a', // source_app
+                    1, 1, 0, 0, 0, 0, 0, 0, 0, b'w', // label
+                    0,    // sensitive
+                ],
+            ),
+            (
+                Package::Item {
+                    time: 1_700_000_000_000,
+                    seq: 7,
+                    item: TransferableItem::Raw {
+                        type_id: "x".to_string(),
+                        data: vec![7],
+                    },
+                    source_app: None,
+                    label: None,
+                    sensitive: true,
+                },
+                vec![
+                    1, 0, 0, 0, // Package::Item
+                    0x00, 0x68, 0xe5, 0xcf, 0x8b, 0x01, 0, 0, // time
+                    7, 0, 0, 0, 0, 0, 0, 0, // seq
+                    22, 0, 0, 0, 0, 0, 0, 0, // item length
+                    2, 0, 0, 0, // TransferableItem::Raw
+                    1, 0, 0, 0, 0, 0, 0, 0, b'x', // type_id
+                    1, 0, 0, 0, 0, 0, 0, 0, 7, // data
+                    0, // source_app
+                    0, // label
+                    1, // sensitive
+                ],
+            ),
+            (
+                Package::Presence {
+                    peers: vec!["pc".to_string()],
+                },
+                vec![
+                    2, 0, 0, 0, // Package::Presence
+                    1, 0, 0, 0, 0, 0, 0, 0, // peers
+                    2, 0, 0, 0, 0, 0, 0, 0, b'p', b'c',
+                ],
+            ),
+            (Package::Heartbeat, vec![3, 0, 0, 0]),
+            (
+                Package::HistoryRequest { index: 3 },
+                vec![
+                    4, 0, 0, 0, // Package::HistoryRequest
+                    3, 0, 0, 0, 0, 0, 0, 0, // index
+                ],
+            ),
+            (
+                Package::HistoryEntry {
+                    index: 3,
+                    package: None,
+                },
+                vec![
+                    5, 0, 0, 0, // Package::HistoryEntry
+                    3, 0, 0, 0, 0, 0, 0, 0, // index
+                    0, // package
+                ],
+            ),
+            (
+                Package::FileChunk {
+                    id: 1,
+                    seq: 0,
+                    total: 2,
+                    bytes: vec![9],
+                },
+                vec![
+                    6, 0, 0, 0, // Package::FileChunk
+                    1, 0, 0, 0, 0, 0, 0, 0, // id
+                    0, 0, 0, 0, // seq
+                    2, 0, 0, 0, // total
+                    1, 0, 0, 0, 0, 0, 0, 0, 9, // bytes
+                ],
+            ),
+            (
+                Package::Clear {
+                    time: 1_700_000_000_000,
+                    seq: 7,
+                },
+                vec![
+                    7, 0, 0, 0, // Package::Clear
+                    0x00, 0x68, 0xe5, 0xcf, 0x8b, 0x01, 0, 0, // time
+                    7, 0, 0, 0, 0, 0, 0, 0, // seq
+                ],
+            ),
+            (
+                Package::Ack {
+                    time: 1_700_000_000_000,
+                    seq: 7,
+                },
+                vec![
+                    8, 0, 0, 0, // Package::Ack
+                    0x00, 0x68, 0xe5, 0xcf, 0x8b, 0x01, 0, 0, // time
+                    7, 0, 0, 0, 0, 0, 0, 0, // seq
+                ],
+            ),
+            (
+                Package::Resume { id: 1, offset: 2 },
+                vec![
+                    9, 0, 0, 0, // Package::Resume
+                    1, 0, 0, 0, 0, 0, 0, 0, // id
+                    2, 0, 0, 0, 0, 0, 0, 0, // offset
+                ],
+            ),
+        ];
+        for (package, bytes) in golden {
+            assert_eq!(bincode::serialize(&package).unwrap(), bytes, "{package}");
+            assert_eq!(Package::from_bytes(&bytes).unwrap(), package);
+        }
+
+        // Files can't be built the same way on every platform, so only check they decode. Serde
+        // tags an `OsString` with the platform it came from, which Windows can't decode.
+        #[cfg(unix)]
+        {
+            let file = [
+                0, 0, 0, 0, // TransferableItem::File
+                0, 0, 0, 0, // OsString::Unix
+                5, 0, 0, 0, 0, 0, 0, 0, b'a', b'.', b't', b'x', b't', // file_name
+                2, 0, 0, 0, 0, 0, 0, 0, 1, 2, // data
+            ];
+            let TransferableItem::File { file_name, data } = bincode::deserialize(&file).unwrap()
+            else {
+                panic!("Expected a file");
+            };
+            assert_eq!(file_name, "a.txt");
+            assert_eq!(data.as_ref(), [1, 2]);
+        }
+
+        let session = SessionInfo {
+            device_name: "pc".to_string(),
+            os: "linux".to_string(),
+            use_encryption: true,
+            observer: false,
+            ciphers: vec![Cipher::ChaCha20Poly1305],
+            protocol_version: 3,
+            compression: None,
+            format: Format::Bincode,
+        };
+        assert_eq!(
+            bincode::serialize(&session).unwrap(),
+            [
+                2, 0, 0, 0, 0, 0, 0, 0, b'p', b'c', // device_name
+                5, 0, 0, 0, 0, 0, 0, 0, b'l', b'i', b'n', b'u', b'x', // os
+                1,    // use_encryption
+                0,    // observer
+                1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, // ciphers
+                3, 0, 0, 0, // protocol_version
+            ]
+        );
+    }
+
+    #[test]
+    fn redacted_package_test() {
+        let secret = "hunter2";
+        let package = Package::text(secret.to_string()).with_label(Some("work".into()));
+        let entry = Package::HistoryEntry {
+            index: 0,
+            package: Some(Box::new(package.clone())),
+        };
+        for package in [&package, &entry] {
+            let redacted = package.redacted().to_string();
+            assert!(!redacted.contains(secret), "{redacted}");
+            assert!(redacted.contains("<7 bytes, "), "{redacted}");
+            assert!(redacted.contains("Label: work"), "{redacted}");
+            // The full form stays available
+            assert!(package.to_string().contains(secret));
+        }
+
+        // The same text shows the same, so a clip can be followed through the logs
+        let url = TransferableItem::Url {
+            url: secret.to_string(),
+        };
+        let hidden = |package: Package| {
+            let redacted = package.redacted().to_string();
+            redacted[redacted.find('<').unwrap()..].to_string()
+        };
+        assert_eq!(
+            hidden(Package::from(url)),
+            hidden(Package::text(secret.to_string()))
+        );
+    }
+
+    #[test]
+    fn unknown_item_test() {
+        use super::{receive_package, send_package, write_frame};
+
+        // An item kind a newer version added, with whatever fields it has
+        let future = [
+            99, 0, 0, 0, // TransferableItem variant
+            3, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
+        ];
+        let mut bytes = vec![
+            1, 0, 0, 0, // Package::Item
+            0x00, 0x68, 0xe5, 0xcf, 0x8b, 0x01, 0, 0, // time
+            7, 0, 0, 0, 0, 0, 0, 0, // seq
+            15, 0, 0, 0, 0, 0, 0, 0, // item length
+        ];
+        bytes.extend(future);
+        bytes.extend([
+            1, 1, 0, 0, 0, 0, 0, 0, 0, b'a', // source_app
+            0,    // label
+            0,    // sensitive
+        ]);
+
+        let package = Package::from_bytes(&bytes).unwrap();
+        let Package::Item {
+            item: TransferableItem::Unknown { ref encoded },
+            ref source_app,
+            ..
+        } = package
+        else {
+            panic!("Expected an unknown item, got {package}");
+        };
+        assert_eq!(encoded, &future);
+        assert_eq!(source_app.as_deref(), Some("a"));
+        // Passed on exactly as received
+        assert_eq!(bincode::serialize(&package).unwrap(), bytes);
+
+        // The package after it is still read
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &bytes).unwrap();
+        send_package(&Package::Heartbeat, &mut wire, &None, Encoding::default()).unwrap();
+        let mut stream = wire.as_slice();
+        let received = receive_package(&mut stream, &None, Encoding::default()).unwrap();
+        assert_eq!(received, package);
+        let received = receive_package(&mut stream, &None, Encoding::default()).unwrap();
+        assert_eq!(received, Package::Heartbeat);
+    }
+
+    #[test]
+    fn backoff_test() {
+        use super::Backoff;
+        use std::time::Duration;
+
+        let base = Duration::from_millis(100);
+        let max = Duration::from_secs(2);
+        let mut backoff = Backoff::new(base, max);
+        for step in [100, 200, 400, 800, 1600, 2000, 2000] {
+            let step = Duration::from_millis(step);
+            let delay = backoff.next_delay();
+            assert!(delay >= step / 2 && delay <= step, "{delay:?} for {step:?}");
+        }
+
+        // Jitter keeps a fleet of clients from retrying at the same instant
+        let delays: std::collections::HashSet<_> = (0..20)
+            .map(|_| Backoff::new(base, max).next_delay())
+            .collect();
+        assert!(delays.len() > 1);
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= base);
+    }
+
+    #[test]
+    fn label_filter_test() {
+        use super::LabelFilter;
+
+        let clip = |label: Option<&str>| {
+            Package::text("Hi".to_string()).with_label(label.map(str::to_string))
+        };
+        let work_only = LabelFilter {
+            accept: vec!["work".to_string()],
+            deny: vec![],
+        };
+        assert!(work_only.allows(&clip(Some("work"))));
+        assert!(!work_only.allows(&clip(Some("personal"))));
+        assert!(work_only.allows(&clip(None)));
+        assert!(clip(Some("work")).to_string().ends_with("Label: work }"));
+
+        let no_personal = LabelFilter {
+            accept: vec![],
+            deny: vec!["personal".to_string()],
+        };
+        assert!(no_personal.allows(&clip(Some("work"))));
+        assert!(!no_personal.allows(&clip(Some("personal"))));
+        assert!(LabelFilter::default().allows(&clip(Some("anything"))));
+    }
+
+    #[test]
+    fn hex_dump_test() {
+        use super::{hex_dump, set_max_packet_log_bytes};
+
+        set_max_packet_log_bytes(2);
+        assert_eq!(hex_dump(&[0x01, 0xab]), "01 ab");
+        assert_eq!(
+            hex_dump(&[0x01, 0xab, 0xff, 0x00]),
+            "01 ab ... (2 more bytes)"
+        );
+        set_max_packet_log_bytes(0);
+        assert_eq!(hex_dump(&[0x01]), "... (1 more bytes)");
+        assert_eq!(hex_dump(&[]), "");
+    }
+
+    #[test]
+    fn rapid_items_are_ordered_test() {
+        let first = Package::text("first".to_string());
+        let second = Package::text("second".to_string());
+        // Most likely created within the same millisecond
+        assert!(first < second);
+        assert!(second > first);
+        // Labels and source apps don't change the order
+        assert!(first.clone().with_label(Some("work".to_string())) < second);
+    }
+
+    #[test]
+    fn image_round_trip_test() {
+        use super::{receive_package, send_package};
+
+        let bytes: Vec<u8> = (0..16).map(|i| i * 17).collect();
+        let package = Package::from(TransferableItem::Image {
+            width: 2,
+            height: 2,
+            bytes: bytes.clone(),
+        });
+        let mut wire = Vec::new();
+        send_package(&package, &mut wire, &None, Encoding::default()).unwrap();
+        let received = receive_package(&mut wire.as_slice(), &None, Encoding::default()).unwrap();
+
+        assert_eq!(received, package);
+        let Package::Item {
+            item:
+                TransferableItem::Image {
+                    width,
+                    height,
+                    bytes: received_bytes,
+                },
+            ..
+        } = received
+        else {
+            panic!("Expected an image");
+        };
+        assert_eq!((width, height), (2, 2));
+        assert_eq!(received_bytes, bytes);
+    }
+
+    #[test]
+    fn package_order_test() {
+        use std::cmp::Ordering;
+
+        let item = |time, seq, text: &str| Package::Item {
+            time,
+            seq,
+            item: TransferableItem::from(text.to_string()),
+            source_app: None,
+            label: None,
+            sensitive: false,
+        };
+        let packages = [
+            Package::Empty,
+            Package::Presence {
+                peers: vec!["pc".to_string()],
+            },
+            Package::Heartbeat,
+            item(1, 0, "a"),
+            item(1, 0, "b"),
+            item(1, 1, "a"),
+            Package::Clear { time: 1, seq: 2 },
+            item(2, 0, "a"),
+            item(2, 5, "c"),
+        ];
+
+        for a in &packages {
+            assert_eq!(a.cmp(a), Ordering::Equal);
+            assert_eq!(a.cmp(&a.clone()), Ordering::Equal);
+            for b in &packages {
+                assert_eq!(a.partial_cmp(b), Some(a.cmp(b)));
+                assert_eq!(a.cmp(b) == Ordering::Equal, a == b, "{a} vs {b}");
+                assert_eq!(a.cmp(b), b.cmp(a).reverse(), "{a} vs {b}");
+                for c in &packages {
+                    if a <= b && b <= c {
+                        assert!(a <= c, "{a} <= {b} <= {c}");
+                    }
+                }
+            }
+        }
+        assert!(Package::Empty < item(0, 0, ""));
+        assert!(item(1, 9, "") < item(2, 0, ""));
+        assert!(item(1, 1, "") < Package::Clear { time: 1, seq: 2 });
+        assert!(Package::Clear { time: 1, seq: 2 } < item(2, 0, ""));
+
+        let mut heap: std::collections::BinaryHeap<_> = packages.iter().cloned().collect();
+        assert_eq!(heap.pop(), Some(item(2, 5, "c")));
+        assert_eq!(heap.pop(), Some(item(2, 0, "a")));
+    }
+
+    #[test]
+    fn key_exchange_test() {
+        use super::{client_handshake, server_handshake};
+        use std::net::TcpStream;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            server_handshake(&mut stream).unwrap()
+        });
+        let client = client_handshake(&mut TcpStream::connect(addr).unwrap()).unwrap();
+        let server = server.join().unwrap();
+
+        // Both ends agree on what was exchanged, which is what a PSK proof is made over
+        assert_eq!(client.server_public, server.server_public);
+        assert_eq!(client.client_public, server.client_public);
+        assert_ne!(client.server_public, client.client_public);
+
+        let (client, server) = (client.shared_key(), server.shared_key());
+        assert!(client.key == server.key);
+        let sealed = client.seal(b"Hello").unwrap();
+        assert_eq!(server.open(&sealed).unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn pre_shared_key_test() {
+        use super::PreSharedKey;
+
+        let psk: PreSharedKey = "correct horse".parse().unwrap();
+        let (server, client) = ([1u8; 32], [2u8; 32]);
+        let proof = psk.prove(&server, &client);
+        assert!(psk.verify(&proof, &server, &client));
+
+        let wrong: PreSharedKey = "battery staple".parse().unwrap();
+        assert!(!wrong.verify(&proof, &server, &client));
+        assert!(!psk.verify(&wrong.prove(&server, &client), &server, &client));
+        // A proof from another connection doesn't carry over
+        assert!(!psk.verify(&proof, &server, &[3u8; 32]));
+        assert!(!psk.verify(&proof, &client, &server));
+        assert!(!psk.verify(&proof[1..], &server, &client));
+        assert!("".parse::<PreSharedKey>().is_err());
+    }
+
+    #[test]
+    fn oversized_frame_test() {
+        use super::{read_frame, receive_package, write_frame, NetworkError, MAX_PACKAGE_LEN};
+
+        // A declared length just over the limit is refused without waiting for its bytes
+        let mut prefix = Vec::new();
+        let mut len = MAX_PACKAGE_LEN + 1;
+        while len >= 0x80 {
+            prefix.push((len & 0x7f) as u8 | 0x80);
+            len >>= 7;
+        }
+        prefix.push(len as u8);
+        let Err(NetworkError::Io(err)) =
+            receive_package(&mut prefix.as_slice(), &None, Encoding::default())
+        else {
+            panic!("Expected an I/O error");
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let err = read_frame(&mut prefix.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // A frame cut short errors instead of yielding zeroes
+        let mut frame = Vec::new();
+        write_frame(&mut frame, &[1, 2, 3, 4]).unwrap();
+        frame.truncate(3);
+        let err = read_frame(&mut frame.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn malformed_session_test() {
+        use super::{receive_session, send_session, write_frame, SessionInfo, MAX_SESSION_LEN};
+
+        // A huge declared length is refused before any of it is read
+        let mut prefix = Vec::new();
+        let mut len = u64::MAX >> 1;
+        while len >= 0x80 {
+            prefix.push((len & 0x7f) as u8 | 0x80);
+            len >>= 7;
+        }
+        prefix.push(len as u8);
+        let err = receive_session(&mut prefix.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // Only just over the limit too
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &vec![0; MAX_SESSION_LEN as usize + 1]).unwrap();
+        let err = receive_session(&mut wire.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // A small frame whose device name claims to be huge
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &[0xff; 16]).unwrap();
+        assert!(receive_session(&mut wire.as_slice()).is_err());
+
+        // Cut short
+        let mut wire = Vec::new();
+        send_session(&mut wire, &SessionInfo::new("laptop")).unwrap();
+        wire.truncate(wire.len() / 2);
+        assert!(receive_session(&mut wire.as_slice()).is_err());
+    }
+
+    #[test]
+    fn text_package_test() {
+        let package = Package::text("Hello".to_string());
+        assert_eq!(package.as_text(), Some("Hello"));
+        let Package::Item { item, .. } = &package else {
+            panic!("Expected an item");
+        };
+        assert_eq!(*item, TransferableItem::from("Hello".to_string()));
+
+        let rich = Package::from(TransferableItem::RichText {
+            html: Some("<i>Hello</i>".to_string()),
+            rtf: None,
+            plain: "Hello".to_string(),
+        });
+        assert_eq!(rich.as_text(), Some("Hello"));
+        let url = Package::from(TransferableItem::Url {
+            url: "https://example.com".to_string(),
+        });
+        assert_eq!(url.as_text(), Some("https://example.com"));
+
+        let image = Package::from(TransferableItem::Image {
+            width: 1,
+            height: 1,
+            bytes: vec![0; 4],
+        });
+        assert_eq!(image.as_text(), None);
+        assert_eq!(Package::Empty.as_text(), None);
+    }
+
+    #[test]
+    fn receive_with_wrong_key_test() {
+        use super::{receive_package, send_package, NetworkError, SharedKey};
+
+        let package = Package::text("secret".to_string());
+        let mut wire = Vec::new();
+        send_package(
+            &package,
+            &mut wire,
+            &Some(SharedKey::from(&[1u8; 32])),
+            Encoding::default(),
+        )
+        .unwrap();
+
+        let wrong_key = Some(SharedKey::from(&[2u8; 32]));
+        let result = receive_package(&mut wire.as_slice(), &wrong_key, Encoding::default());
+        assert!(matches!(result, Err(NetworkError::DecryptionFailed(_))));
+    }
+
+    #[test]
+    fn replayed_package_test() {
+        use super::{receive_package, send_package, NetworkError, SharedKey};
+
+        let sender = Some(SharedKey::from(&[1u8; 32]));
+        let receiver = Some(SharedKey::from(&[1u8; 32]));
+        let mut captured = Vec::new();
+        let first = Package::text("first".to_string());
+        send_package(&first, &mut captured, &sender, Encoding::default()).unwrap();
+        let mut wire = captured.clone();
+        let second = Package::text("second".to_string());
+        send_package(&second, &mut wire, &sender, Encoding::default()).unwrap();
+
+        let mut stream = wire.as_slice();
+        let receive = |stream: &mut &[u8]| receive_package(stream, &receiver, Encoding::default());
+        assert_eq!(receive(&mut stream).unwrap(), first);
+        assert_eq!(receive(&mut stream).unwrap(), second);
+        // Both the latest sequence number and an older one are refused
+        let result = receive(&mut captured.as_slice());
+        assert!(matches!(result, Err(NetworkError::Replayed(1))));
+        let result = receive(&mut &wire[captured.len()..]);
+        assert!(matches!(result, Err(NetworkError::Replayed(2))));
+    }
+
+    #[test]
+    fn reflected_package_test() {
+        use super::{receive_package, send_package, NetworkError, Role, SharedKey};
+
+        let client = Some(SharedKey::from(&[1u8; 32]).with_role(Role::Client));
+        let server = Some(SharedKey::from(&[1u8; 32]).with_role(Role::Server));
+        let package = Package::text("Hello".to_string());
+        let mut wire = Vec::new();
+        send_package(&package, &mut wire, &client, Encoding::default()).unwrap();
+
+        // Sent back to the client, its own package is refused even though the client hasn't
+        // opened a package with its sequence number yet
+        let result = receive_package(&mut wire.as_slice(), &client, Encoding::default());
+        assert!(matches!(result, Err(NetworkError::DecryptionFailed(_))));
+        let received = receive_package(&mut wire.as_slice(), &server, Encoding::default());
+        assert_eq!(received.unwrap(), package);
+
+        let mut wire = Vec::new();
+        send_package(&package, &mut wire, &server, Encoding::default()).unwrap();
+        let result = receive_package(&mut wire.as_slice(), &server, Encoding::default());
+        assert!(matches!(result, Err(NetworkError::DecryptionFailed(_))));
+        let received = receive_package(&mut wire.as_slice(), &client, Encoding::default());
+        assert_eq!(received.unwrap(), package);
+    }
+
+    #[test]
+    fn compressed_package_test() {
+        use super::{receive_package, send_package, Codec, SharedKey};
+
+        let lz4 = Encoding {
+            compression: Some(Codec::Lz4),
+            ..Encoding::default()
+        };
+        let text = "2024-01-01 12:00:00 INFO Listening on 0.0.0.0:8080\n".repeat(25_000);
+        assert!(text.len() > 1024 * 1024);
+        let package = Package::text(text);
+
+        let mut plain = Vec::new();
+        send_package(&package, &mut plain, &None, Encoding::default()).unwrap();
+        let mut wire = Vec::new();
+        send_package(&package, &mut wire, &None, lz4).unwrap();
+        assert!(wire.len() < plain.len() / 10, "{} bytes", wire.len());
+        let received = receive_package(&mut wire.as_slice(), &None, lz4).unwrap();
+        assert_eq!(received, package);
+
+        // Compressed before sealing, so encrypting doesn't cost the gain
+        let key = || Some(SharedKey::from(&[1u8; 32]));
+        let mut sealed = Vec::new();
+        send_package(&package, &mut sealed, &key(), lz4).unwrap();
+        assert!(sealed.len() < plain.len() / 10, "{} bytes", sealed.len());
+        let received = receive_package(&mut sealed.as_slice(), &key(), lz4).unwrap();
+        assert_eq!(received, package);
+    }
+
+    #[test]
+    fn transfer_stats_test() {
+        use super::{read_frame_len, send_package, Codec, SharedKey, SEQUENCE_LEN};
+
+        let package = Package::text("Hello ".repeat(100));
+        let serialized = bincode::serialize(&package).unwrap();
+        let compressed = lz4_flex::compress_prepend_size(&serialized);
+
+        let mut wire = Vec::new();
+        let stats = send_package(&package, &mut wire, &None, Encoding::default()).unwrap();
+        assert_eq!(stats.kind, "text");
+        assert_eq!(stats.serialized_bytes, serialized.len());
+        assert_eq!(stats.compressed_bytes, None);
+        assert_eq!(stats.encrypted_bytes, None);
+        assert_eq!(stats.sent_bytes(), serialized.len());
+
+        let mut wire = Vec::new();
+        let key = Some(SharedKey::from(&[1u8; 32]));
+        let lz4 = Encoding {
+            compression: Some(Codec::Lz4),
+            ..Encoding::default()
+        };
+        let stats = send_package(&package, &mut wire, &key, lz4).unwrap();
+        assert_eq!(stats.serialized_bytes, serialized.len());
+        assert_eq!(stats.compressed_bytes, Some(compressed.len()));
+        // Nonce and sequence number in front, tag at the end
+        assert_eq!(
+            stats.encrypted_bytes,
+            Some(compressed.len() + 24 + SEQUENCE_LEN + 16)
+        );
+        assert_eq!(
+            read_frame_len(&mut wire.as_slice()).unwrap(),
+            stats.sent_bytes() as u64
+        );
+
+        let mut totals = super::TransferTotals::default();
+        totals.add(&stats);
+        totals.add(&stats);
+        assert_eq!(totals.packages, 2);
+        assert_eq!(totals.bytes, 2 * stats.sent_bytes() as u64);
+    }
+
+    #[test]
+    fn compression_bomb_test() {
+        use super::{receive_package, write_frame, Codec};
+
+        let lz4 = Encoding {
+            compression: Some(Codec::Lz4),
+            ..Encoding::default()
+        };
+        // Claims to inflate to 4 GiB
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &[0xff, 0xff, 0xff, 0xff, 0x00]).unwrap();
+        let err = receive_package(&mut wire.as_slice(), &None, lz4).unwrap_err();
+        assert!(err.to_string().contains("exceeds"), "{err}");
+    }
+
+    #[test]
+    fn decompressed_budget_test() {
+        use super::{receive_package_within, send_package, Codec, MemoryBudget};
+
+        let lz4 = Encoding {
+            compression: Some(Codec::Lz4),
+            ..Encoding::default()
+        };
+        let package = Package::text("x".repeat(1000));
+        let mut wire = Vec::new();
+        send_package(&package, &mut wire, &None, lz4).unwrap();
+        assert!(wire.len() < 100, "{} bytes", wire.len());
+
+        // The frame fits, what it inflates to doesn't
+        let budget = MemoryBudget::new(500);
+        let err =
+            receive_package_within(&mut wire.as_slice(), &None, lz4, Some(&budget)).unwrap_err();
+        assert!(err.to_string().contains("memory budget"), "{err}");
+
+        let budget = MemoryBudget::new(2000);
+        let received =
+            receive_package_within(&mut wire.as_slice(), &None, lz4, Some(&budget)).unwrap();
+        assert_eq!(received, package);
+        assert_eq!(*budget.used.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn device_name_test() {
+        use super::{receive_session, send_session, SessionInfo};
+
+        // Names are free text, spaces and all
+        let session = SessionInfo::new("Büro Mac (2)");
+        let mut wire = Vec::new();
+        send_session(&mut wire, &session).unwrap();
+        assert_eq!(
+            receive_session(&mut wire.as_slice()).unwrap().device_name,
+            "Büro Mac (2)"
+        );
+        assert!(!super::default_device_name().is_empty());
+    }
+
+    #[test]
+    fn session_compression_test() {
+        use super::{
+            receive_session, receive_session_accepted, receive_session_reply, send_session,
+            send_session_accepted, send_session_refusal, send_session_reply, Cipher, Codec,
+            SessionInfo, SessionReply,
+        };
+
+        let mut session = SessionInfo {
+            device_name: "pc".to_string(),
+            os: "linux".to_string(),
+            use_encryption: false,
+            observer: false,
+            ciphers: vec![Cipher::ChaCha20Poly1305],
+            protocol_version: 3,
+            compression: Some(Codec::Lz4),
+            format: Format::Bincode,
+        };
+        let mut wire = Vec::new();
+        send_session(&mut wire, &session).unwrap();
+        assert_eq!(receive_session(&mut wire.as_slice()).unwrap(), session);
+        // A server that predates compression reads the session and ignores the codec
+        let frame = super::read_frame(&mut wire.as_slice()).unwrap();
+        let mut expected = session.clone();
+        expected.compression = None;
+        assert_eq!(
+            bincode::deserialize::<SessionInfo>(&frame).unwrap(),
+            expected
+        );
+
+        session.compression = None;
+        let mut wire = Vec::new();
+        send_session(&mut wire, &session).unwrap();
+        assert_eq!(receive_session(&mut wire.as_slice()).unwrap(), session);
+
+        // Formats other than bincode, with or without a codec
+        for compression in [None, Some(Codec::Lz4)] {
+            session.compression = compression;
+            session.format = Format::Json;
+            let mut wire = Vec::new();
+            send_session(&mut wire, &session).unwrap();
+            assert_eq!(receive_session(&mut wire.as_slice()).unwrap(), session);
+        }
+
+        let lz4 = Encoding {
+            compression: Some(Codec::Lz4),
+            ..Encoding::default()
+        };
+        let json = Encoding {
+            format: Format::Json,
+            ..lz4
+        };
+        for encoding in [lz4, json] {
+            let mut wire = Vec::new();
+            send_session_reply(&mut wire, encoding).unwrap();
+            assert_eq!(
+                receive_session_reply(&mut wire.as_slice()).unwrap(),
+                SessionReply::Accepted(encoding)
+            );
+            // And a client that predates it sees a plain acceptance
+            assert!(receive_session_accepted(&mut wire.as_slice()).unwrap());
+        }
+
+        let mut wire = Vec::new();
+        send_session_accepted(&mut wire, false).unwrap();
+        assert_eq!(
+            receive_session_reply(&mut wire.as_slice()).unwrap(),
+            SessionReply::Refused(None)
+        );
+
+        let mut wire = Vec::new();
+        send_session_refusal(&mut wire, "Server full").unwrap();
+        assert_eq!(
+            receive_session_reply(&mut wire.as_slice()).unwrap(),
+            SessionReply::Refused(Some("Server full".to_string()))
+        );
+        assert!(!receive_session_accepted(&mut wire.as_slice()).unwrap());
+    }
+
+    #[test]
+    fn json_package_test() {
+        use super::{receive_package, send_package};
+
+        let json = Encoding {
+            format: Format::Json,
+            ..Encoding::default()
+        };
+        let dir = std::env::temp_dir().join(format!("clipper-json-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.bin"), [0u8, 1, 2, 255]).unwrap();
+        let file = Package::from(TransferableItem::read_file(dir.join("notes.bin")).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+        for package in [Package::text("Hello".to_string()), file] {
+            let mut wire = Vec::new();
+            send_package(&package, &mut wire, &None, json).unwrap();
+            let payload = super::read_frame(&mut wire.as_slice()).unwrap();
+            let text = std::str::from_utf8(&payload).expect("JSON isn't valid UTF-8");
+            serde_json::from_str::<serde_json::Value>(text).unwrap();
+            assert_eq!(
+                receive_package(&mut wire.as_slice(), &None, json).unwrap(),
+                package
+            );
+        }
+
+        // Binary data is base64 encoded rather than spelled out byte by byte
+        let image = Package::from(TransferableItem::Image {
+            width: 1,
+            height: 1,
+            bytes: vec![255, 0, 0, 255],
+        });
+        let payload = json.format.serialize(&image).unwrap();
+        assert!(String::from_utf8(payload).unwrap().contains("\"/wAA/w==\""));
+    }
+
+    #[test]
+    fn file_transfer_test() {
+        use super::{receive_package, send_package};
+
+        let dir =
+            std::env::temp_dir().join(format!("clipper-file-transfer-{}", std::process::id()));
+        let download_dir = dir.join("downloads");
+        std::fs::create_dir_all(&download_dir).unwrap();
+        std::fs::write(dir.join("notes.bin"), [0u8, 1, 2, 255]).unwrap();
+
+        let package = Package::from(TransferableItem::read_file(dir.join("notes.bin")).unwrap());
+        let mut wire = Vec::new();
+        send_package(&package, &mut wire, &None, Encoding::default()).unwrap();
+        let Package::Item { item, .. } =
+            receive_package(&mut wire.as_slice(), &None, Encoding::default()).unwrap()
+        else {
+            panic!("Expected an item");
+        };
+        item.write_to_dir(&download_dir, None).unwrap();
+
+        assert_eq!(
+            std::fs::read(download_dir.join("notes.bin")).unwrap(),
+            [0u8, 1, 2, 255]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn connection_round_trip_test() {
+        use super::{receive_package, send_package, Connection};
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = Connection::new(TcpStream::connect(addr).unwrap()).unwrap();
+        let (server_stream, _) = listener.accept().unwrap();
+        let (mut reader, mut writer) = Connection::new(server_stream).unwrap().split();
+
+        let first = Package::text("first".to_string());
+        let second = Package::text("second".to_string());
+        send_package(&first, &mut client, &None, Encoding::default()).unwrap();
+        send_package(&second, &mut client, &None, Encoding::default()).unwrap();
+        assert_eq!(
+            receive_package(&mut reader, &None, Encoding::default()).unwrap(),
+            first
+        );
+        assert_eq!(
+            receive_package(&mut reader, &None, Encoding::default()).unwrap(),
+            second
+        );
+
+        send_package(&first, &mut writer, &None, Encoding::default()).unwrap();
+        assert_eq!(
+            receive_package(&mut client, &None, Encoding::default()).unwrap(),
+            first
+        );
+    }
+
+    #[test]
+    fn connection_handshake_test() {
+        use super::{Codec, Connection, HandshakeError, PreSharedKey, SessionInfo};
+        use std::net::{TcpListener, TcpStream};
+
+        let psk: PreSharedKey = "secret".parse().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut results = Vec::new();
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                let mut connection = Connection::new(stream).unwrap();
+                let result = connection.accept(Some(&psk), false).map(|session| {
+                    let package = connection.recv().unwrap();
+                    connection.send(&package).unwrap();
+                    session.device_name
+                });
+                results.push(result);
+            }
+            results
+        });
+
+        let session = SessionInfo {
+            use_encryption: true,
+            compression: Some(Codec::Lz4),
+            ..SessionInfo::new("laptop")
+        };
+        let mut client = Connection::new(TcpStream::connect(addr).unwrap()).unwrap();
+        client
+            .handshake(&session, Some(&"secret".parse().unwrap()))
+            .unwrap();
+        assert!(client.shared_key().is_some());
+        assert_eq!(client.encoding().compression, Some(Codec::Lz4));
+        let package = Package::text("Hello".to_string());
+        client.send(&package).unwrap();
+        assert_eq!(client.recv().unwrap(), package);
+
+        let mut client = Connection::new(TcpStream::connect(addr).unwrap()).unwrap();
+        assert!(matches!(
+            client.handshake(&session, Some(&"guess".parse().unwrap())),
+            Err(HandshakeError::PskRejected)
+        ));
+
+        let results = server.join().unwrap();
+        assert_eq!(results[0].as_ref().unwrap(), "laptop");
+        assert!(matches!(results[1], Err(HandshakeError::PskRejected)));
+    }
 }
@@ -0,0 +1,321 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{ErrorKind, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::network::{read_frame, write_frame, NetworkError, Package};
+use crate::persist;
+
+const MAGIC: [u8; 4] = *b"CLPO";
+/// Bump when `Record` changes, and teach [`migrate`] to convert the previous version.
+const VERSION: u16 = 2;
+
+/// One entry of the outbox log.
+#[derive(Debug, Serialize, Deserialize)]
+enum Record {
+    Push { id: u64, package: Package },
+    Ack { id: u64 },
+}
+
+/// Packages waiting for the server to acknowledge them with a [`Package::Ack`], kept in an
+/// append-only file so they survive a crash or restart of the client.
+///
+/// Every push and ack is appended as a length prefixed record and synced to disk before
+/// returning. Reopening the file replays the log, and it is truncated whenever nothing is
+/// pending so it doesn't grow forever.
+#[derive(Debug)]
+pub struct Outbox {
+    file: File,
+    pending: Vec<(u64, Package)>,
+    next_id: u64,
+}
+
+impl Outbox {
+    /// Opens or creates the outbox at `path`, loading whatever is still unacknowledged.
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let persist::Versioned {
+            mut file,
+            version,
+            body,
+        } = persist::open(path.as_ref(), MAGIC, VERSION)?;
+        let log = migrate(version, body)?;
+
+        let mut pending = Vec::new();
+        let mut next_id = 0;
+        let mut cursor = log.as_slice();
+        let mut valid_len = 0;
+        loop {
+            let frame = match read_frame(&mut cursor) {
+                Ok(frame) => frame,
+                // A record cut short by a crash; everything before it is intact
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            };
+            match bincode::deserialize(&frame).map_err(NetworkError::Deserialize)? {
+                Record::Push { id, package } => {
+                    next_id = next_id.max(id + 1);
+                    pending.push((id, package));
+                }
+                Record::Ack { id } => pending.retain(|(pending_id, _)| *pending_id != id),
+            }
+            valid_len = log.len() - cursor.len();
+        }
+        if version == VERSION {
+            file.set_len(persist::HEADER_LEN + valid_len as u64)?;
+            file.seek(SeekFrom::End(0))?;
+        } else {
+            persist::reset(&mut file, MAGIC, VERSION)?;
+            std::io::Write::write_all(&mut file, &log[..valid_len])?;
+            file.sync_all()?;
+        }
+
+        Ok(Self {
+            file,
+            pending,
+            next_id,
+        })
+    }
+
+    /// Stores `package` until [`Outbox::ack`] is called with the returned id.
+    pub fn push(&mut self, package: Package) -> std::io::Result<u64> {
+        let id = self.next_id;
+        self.append(&Record::Push {
+            id,
+            package: package.clone(),
+        })?;
+        self.next_id += 1;
+        self.pending.push((id, package));
+        Ok(id)
+    }
+
+    /// Forgets package `id` once the server confirmed receiving it.
+    pub fn ack(&mut self, id: u64) -> std::io::Result<()> {
+        self.pending.retain(|(pending_id, _)| *pending_id != id);
+        if self.pending.is_empty() {
+            return persist::reset(&mut self.file, MAGIC, VERSION);
+        }
+        self.append(&Record::Ack { id })
+    }
+
+    /// Unacknowledged packages with their ids, oldest first.
+    pub fn pending(&self) -> &[(u64, Package)] {
+        &self.pending
+    }
+
+    fn append(&mut self, record: &Record) -> std::io::Result<()> {
+        let bytes = bincode::serialize(record).map_err(NetworkError::Serialize)?;
+        write_frame(&mut self.file, &bytes)?;
+        self.file.sync_data()
+    }
+}
+
+/// Converts a log written in an older format `version` to the current one.
+fn migrate(version: u16, log: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    match version {
+        VERSION => Ok(log),
+        1 => {
+            let mut migrated = Vec::new();
+            let mut cursor = log.as_slice();
+            loop {
+                let frame = match read_frame(&mut cursor) {
+                    Ok(frame) => frame,
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                    Err(err) => return Err(err),
+                };
+                let record: v1::Record =
+                    bincode::deserialize(&frame).map_err(NetworkError::Deserialize)?;
+                let bytes =
+                    bincode::serialize(&Record::from(record)).map_err(NetworkError::Serialize)?;
+                write_frame(&mut migrated, &bytes)?;
+            }
+            Ok(migrated)
+        }
+        _ => Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("No migration from outbox version {version}"),
+        )),
+    }
+}
+
+/// Version 1, from before items were length prefixed in packages, see
+/// [`crate::item::enveloped`].
+mod v1 {
+    use serde::Deserialize;
+
+    use crate::item::TransferableItem;
+
+    #[derive(Deserialize)]
+    pub enum Record {
+        Push { id: u64, package: Package },
+        Ack { id: u64 },
+    }
+
+    /// [`crate::network::Package`] as it was, variant for variant.
+    #[derive(Deserialize)]
+    pub enum Package {
+        Empty,
+        Item {
+            time: u64,
+            seq: u64,
+            item: TransferableItem,
+            source_app: Option<String>,
+            label: Option<String>,
+        },
+        Presence {
+            peers: Vec<String>,
+        },
+        Heartbeat,
+        HistoryRequest {
+            index: usize,
+        },
+        HistoryEntry {
+            index: usize,
+            package: Option<Box<Package>>,
+        },
+        FileChunk {
+            id: u64,
+            seq: u32,
+            total: u32,
+            bytes: Vec<u8>,
+        },
+        Clear {
+            time: u64,
+            seq: u64,
+        },
+    }
+}
+
+impl From<v1::Record> for Record {
+    fn from(record: v1::Record) -> Self {
+        match record {
+            v1::Record::Push { id, package } => Self::Push {
+                id,
+                package: package.into(),
+            },
+            v1::Record::Ack { id } => Self::Ack { id },
+        }
+    }
+}
+
+impl From<v1::Package> for Package {
+    fn from(package: v1::Package) -> Self {
+        match package {
+            v1::Package::Empty => Self::Empty,
+            v1::Package::Item {
+                time,
+                seq,
+                item,
+                source_app,
+                label,
+            } => Self::Item {
+                time,
+                seq,
+                item,
+                source_app,
+                label,
+                sensitive: false,
+            },
+            v1::Package::Presence { peers } => Self::Presence { peers },
+            v1::Package::Heartbeat => Self::Heartbeat,
+            v1::Package::HistoryRequest { index } => Self::HistoryRequest { index },
+            v1::Package::HistoryEntry { index, package } => Self::HistoryEntry {
+                index,
+                package: package.map(|package| Box::new((*package).into())),
+            },
+            v1::Package::FileChunk {
+                id,
+                seq,
+                total,
+                bytes,
+            } => Self::FileChunk {
+                id,
+                seq,
+                total,
+                bytes,
+            },
+            v1::Package::Clear { time, seq } => Self::Clear { time, seq },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item::TransferableItem;
+
+    #[test]
+    fn outbox_replay_test() {
+        let path = std::env::temp_dir().join(format!("clipper-outbox-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let [first_package, second_package, third_package] =
+            ["first", "second", "third"].map(|text| Package::text(text.to_string()));
+        let mut outbox = Outbox::open(&path).unwrap();
+        let first = outbox.push(first_package).unwrap();
+        let second = outbox.push(second_package.clone()).unwrap();
+        outbox.ack(first).unwrap();
+        drop(outbox); // client crashed before the second ack
+
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.pending(), [(second, second_package)]);
+        let third = outbox.push(third_package.clone()).unwrap();
+        assert!(third > second);
+
+        // A record torn by a crash mid-write is dropped, the ones before it survive
+        drop(outbox);
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        std::io::Write::write_all(&mut file, &[40, 1, 2]).unwrap();
+        drop(file);
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.pending().len(), 2);
+        outbox.ack(second).unwrap();
+        drop(outbox);
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.pending(), [(third, third_package)]);
+
+        outbox.ack(third).unwrap();
+        assert!(outbox.pending().is_empty());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), persist::HEADER_LEN);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn outbox_migration_test() {
+        let path =
+            std::env::temp_dir().join(format!("clipper-outbox-migration-{}", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        persist::reset(&mut file, MAGIC, 1).unwrap();
+        let mut record = vec![
+            0, 0, 0, 0, // Record::Push
+            3, 0, 0, 0, 0, 0, 0, 0, // id
+            1, 0, 0, 0, // Package::Item
+            0x00, 0x68, 0xe5, 0xcf, 0x8b, 0x01, 0, 0, // time
+            7, 0, 0, 0, 0, 0, 0, 0, // seq
+        ];
+        record.extend(bincode::serialize(&TransferableItem::from("old".to_string())).unwrap());
+        record.extend([0, 0]); // source_app, label
+        write_frame(&mut file, &record).unwrap();
+        drop(file);
+
+        let expected = Package::Item {
+            time: 1_700_000_000_000,
+            seq: 7,
+            item: TransferableItem::from("old".to_string()),
+            source_app: None,
+            label: None,
+            sensitive: false,
+        };
+        let outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.pending(), [(3, expected.clone())]);
+        drop(outbox);
+        // Rewritten in the current format
+        let outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.pending(), [(3, expected)]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+//! Version header for the files clipper keeps on disk, so a newer build can migrate them and an
+//! older one refuses a format it doesn't know instead of misreading it.
+
+use log::warn;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Magic bytes followed by the format version as a little endian `u16`.
+pub const HEADER_LEN: u64 = 6;
+
+pub struct Versioned {
+    /// Positioned at the end of the file
+    pub file: File,
+    pub version: u16,
+    /// Everything after the header
+    pub body: Vec<u8>,
+}
+
+/// Opens `path` as a file identified by `magic`, creating it with a header for `version` if
+/// needed.
+///
+/// Files written by a newer build, or not recognisably of this kind, are moved aside to
+/// `<path>.bak` and replaced by an empty file: the data is kept for the user or a later upgrade
+/// rather than misread or lost.
+pub fn open(path: &Path, magic: [u8; 4], version: u16) -> std::io::Result<Versioned> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+
+    if contents.is_empty() {
+        write_header(&mut file, magic, version)?;
+        return Ok(Versioned {
+            file,
+            version,
+            body: Vec::new(),
+        });
+    }
+
+    let found = (contents.len() as u64 >= HEADER_LEN && contents[..4] == magic)
+        .then(|| u16::from_le_bytes([contents[4], contents[5]]));
+    match found {
+        Some(found) if found <= version => Ok(Versioned {
+            file,
+            version: found,
+            body: contents.split_off(HEADER_LEN as usize),
+        }),
+        _ => {
+            let backup = path.with_extension("bak");
+            match found {
+                Some(found) => warn!(
+                    "{} uses format version {found}, newer than {version}, moving it to {}",
+                    path.display(),
+                    backup.display()
+                ),
+                None => warn!(
+                    "{} is not a file this version understands, moving it to {}",
+                    path.display(),
+                    backup.display()
+                ),
+            }
+            drop(file);
+            std::fs::rename(path, &backup)?;
+            open(path, magic, version)
+        }
+    }
+}
+
+/// Empties the file, leaving only a header for `version`.
+pub fn reset(file: &mut File, magic: [u8; 4], version: u16) -> std::io::Result<()> {
+    file.set_len(0)?;
+    write_header(file, magic, version)
+}
+
+fn write_header(file: &mut File, magic: [u8; 4], version: u16) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&magic)?;
+    file.write_all(&version.to_le_bytes())?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_version_is_backed_up_test() {
+        let path = std::env::temp_dir().join(format!("clipper-persist-{}", std::process::id()));
+        let backup = path.with_extension("bak");
+
+        let mut versioned = open(&path, *b"TEST", 1).unwrap();
+        assert_eq!(versioned.version, 1);
+        versioned.file.write_all(b"data").unwrap();
+        drop(versioned);
+        assert_eq!(open(&path, *b"TEST", 2).unwrap().body, b"data");
+
+        // Written by a future build
+        std::fs::write(&path, b"TEST\x07\x00future").unwrap();
+        let versioned = open(&path, *b"TEST", 2).unwrap();
+        assert_eq!(versioned.version, 2);
+        assert!(versioned.body.is_empty());
+        assert_eq!(std::fs::read(&backup).unwrap(), b"TEST\x07\x00future");
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+use std::str::FromStr;
+
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Daily window such as `09:00-18:00`. A window ending before it starts spans midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActiveHours {
+    /// Minutes since midnight
+    start: u32,
+    end: u32,
+}
+
+impl ActiveHours {
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+fn parse_time(time: &str) -> Result<u32, String> {
+    let (hours, minutes) = time
+        .split_once(':')
+        .ok_or_else(|| format!("Expected HH:MM, got {time:?}"))?;
+    let hours: u32 = hours
+        .parse()
+        .map_err(|_| format!("Invalid hour in {time:?}"))?;
+    let minutes: u32 = minutes
+        .parse()
+        .map_err(|_| format!("Invalid minute in {time:?}"))?;
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes != 0) {
+        return Err(format!("Time out of range: {time:?}"));
+    }
+    Ok(hours * 60 + minutes)
+}
+
+impl FromStr for ActiveHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("Expected HH:MM-HH:MM, got {s:?}"))?;
+        Ok(Self {
+            start: parse_time(start.trim())?,
+            end: parse_time(end.trim())?,
+        })
+    }
+}
+
+impl fmt::Display for ActiveHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// Days of the week, parsed from a comma separated list such as `mon,tue,wed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActiveDays(u8);
+
+impl ActiveDays {
+    /// `weekday` counts from Sunday = 0.
+    pub fn contains(&self, weekday: u32) -> bool {
+        weekday < 7 && self.0 & (1 << weekday) != 0
+    }
+}
+
+impl FromStr for ActiveDays {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut days = 0;
+        for day in s.split(',') {
+            let day = day.trim().to_lowercase();
+            let index = DAY_NAMES
+                .iter()
+                .position(|name| day.starts_with(name))
+                .ok_or_else(|| format!("Unknown day of the week: {day:?}"))?;
+            days |= 1 << index;
+        }
+        Ok(Self(days))
+    }
+}
+
+/// Local wall-clock time, as far as the schedule is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    /// Days since Sunday
+    pub weekday: u32,
+    /// Minutes since midnight
+    pub minute: u32,
+}
+
+impl LocalTime {
+    #[cfg(unix)]
+    pub fn now() -> Self {
+        // Safety: `localtime_r` only writes into the `tm` we hand it
+        unsafe {
+            let now = libc::time(std::ptr::null_mut());
+            let mut tm: libc::tm = std::mem::zeroed();
+            libc::localtime_r(&now, &mut tm);
+            Self {
+                weekday: tm.tm_wday as u32,
+                minute: (tm.tm_hour * 60 + tm.tm_min) as u32,
+            }
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    pub fn now() -> Self {
+        use windows_sys::Win32::System::SystemInformation::GetLocalTime;
+
+        // Safety: `GetLocalTime` only writes into the `SYSTEMTIME` we hand it
+        let time = unsafe {
+            let mut time = std::mem::zeroed();
+            GetLocalTime(&mut time);
+            time
+        };
+        Self {
+            weekday: time.wDayOfWeek as u32,
+            minute: time.wHour as u32 * 60 + time.wMinute as u32,
+        }
+    }
+}
+
+/// When syncing is allowed. Outside of it the client neither sends nor applies clips.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Schedule {
+    pub hours: Option<ActiveHours>,
+    pub days: Option<ActiveDays>,
+}
+
+impl Schedule {
+    pub fn is_active(&self, now: LocalTime) -> bool {
+        self.hours.is_none_or(|hours| hours.contains(now.minute))
+            && self.days.is_none_or(|days| days.contains(now.weekday))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(weekday: u32, time: &str) -> LocalTime {
+        LocalTime {
+            weekday,
+            minute: parse_time(time).unwrap(),
+        }
+    }
+
+    #[test]
+    fn active_hours_test() {
+        let hours: ActiveHours = "09:00-18:00".parse().unwrap();
+        assert_eq!(hours.to_string(), "09:00-18:00");
+        assert!(!hours.contains(parse_time("08:59").unwrap()));
+        assert!(hours.contains(parse_time("09:00").unwrap()));
+        assert!(hours.contains(parse_time("17:59").unwrap()));
+        assert!(!hours.contains(parse_time("18:00").unwrap()));
+
+        let overnight: ActiveHours = "22:00-06:00".parse().unwrap();
+        assert!(overnight.contains(parse_time("23:30").unwrap()));
+        assert!(overnight.contains(parse_time("05:00").unwrap()));
+        assert!(!overnight.contains(parse_time("12:00").unwrap()));
+
+        assert!("9-18".parse::<ActiveHours>().is_err());
+        assert!("25:00-26:00".parse::<ActiveHours>().is_err());
+    }
+
+    #[test]
+    fn schedule_test() {
+        let schedule = Schedule {
+            hours: Some("09:00-18:00".parse().unwrap()),
+            days: Some("mon,tue,wed,thu,fri".parse().unwrap()),
+        };
+        assert!(schedule.is_active(at(1, "10:00")));
+        assert!(!schedule.is_active(at(1, "19:00")));
+        assert!(!schedule.is_active(at(0, "10:00")));
+        assert!(Schedule::default().is_active(at(0, "03:00")));
+        assert!("mon,funday".parse::<ActiveDays>().is_err());
+    }
+}
@@ -0,0 +1,175 @@
+//! Single threaded alternative to `ClientHandler::start_listener`: every client socket is
+//! watched with `poll(2)` and read only once it has data, so the server needs one thread no
+//! matter how many devices are connected.
+
+use log::debug;
+use std::io::{BufReader, ErrorKind, Read};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use clipper::network::{
+    check_frame_len, read_frame_len, receive_package, NetworkError, Stream, HEARTBEAT_INTERVAL,
+};
+
+use crate::{Client, ClientHandler, Server};
+
+const READ_CHUNK: usize = 8 * 1024;
+
+struct Registered {
+    client: Arc<RwLock<Client>>,
+    stream: TcpStream,
+    /// Bytes received but not yet forming a whole package
+    pending: Vec<u8>,
+    /// When the client last sent anything, heartbeats included
+    last_seen: Instant,
+}
+
+impl Registered {
+    fn new(client: Arc<RwLock<Client>>, listen_stream: BufReader<Stream>) -> Self {
+        // Anything the client sent right after the handshake may already sit in the buffer
+        let pending = listen_stream.buffer().to_vec();
+        let Stream::Tcp(stream) = listen_stream.into_inner() else {
+            unreachable!("TLS connections are refused by run");
+        };
+        Self {
+            client,
+            stream,
+            pending,
+            last_seen: Instant::now(),
+        }
+    }
+
+    /// Reads what is available without blocking and handles every complete package.
+    fn on_readable(&mut self, server: &Server) -> Result<(), NetworkError> {
+        let mut chunk = [0u8; READ_CHUNK];
+        // poll reported the socket readable, so this returns without waiting
+        let read = self.stream.read(&mut chunk)?;
+        if read == 0 {
+            return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        self.pending.extend_from_slice(&chunk[..read]);
+        self.last_seen = Instant::now();
+
+        let shared_key = self.client.read().unwrap().shared_key.clone();
+        let encoding = self.client.read().unwrap().encoding;
+        while let Some(len) = self.complete_frame()? {
+            let mut frame = &self.pending[..len];
+            let package = receive_package(&mut frame, &shared_key, encoding)?;
+            self.pending.drain(..len);
+            ClientHandler::handle(
+                &self.client,
+                &server.package,
+                &server.history,
+                &server.broadcaster,
+                package,
+            );
+        }
+        Ok(())
+    }
+
+    /// Length of the first frame in `pending`, prefix included, once all of it has arrived.
+    fn complete_frame(&self) -> std::io::Result<Option<usize>> {
+        let mut cursor = &self.pending[..];
+        let payload_len = match read_frame_len(&mut cursor) {
+            Ok(len) => len,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        // Refused before it is buffered, not once it all arrived
+        check_frame_len(payload_len)?;
+        let prefix_len = self.pending.len() - cursor.len();
+        if (cursor.len() as u64) < payload_len {
+            return Ok(None);
+        }
+        Ok(Some(prefix_len + payload_len as usize))
+    }
+}
+
+fn poll_fd(fd: i32) -> libc::pollfd {
+    libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    }
+}
+
+pub fn run(server: &mut Server) -> std::io::Result<()> {
+    // Records would have to be decrypted as they trickle in, which only the blocking reads do
+    if server.tls.is_some() {
+        return Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            "TLS isn't supported by the event loop",
+        ));
+    }
+    let listeners: Vec<TcpListener> = server.bind()?;
+    let _advertisement = server.advertise(&listeners);
+    server.start_heartbeat();
+    let mut connections: Vec<Registered> = vec![];
+    // Wakes up regularly even when nothing arrives, to notice clients gone silent
+    let timeout = HEARTBEAT_INTERVAL.min(server.idle_timeout).as_millis() as i32;
+
+    loop {
+        // Listeners first, then one entry per connection in the same order
+        let mut fds: Vec<libc::pollfd> = listeners
+            .iter()
+            .map(|listener| listener.as_raw_fd())
+            .chain(connections.iter().map(|conn| conn.stream.as_raw_fd()))
+            .map(poll_fd)
+            .collect();
+
+        // Safety: `fds` is a valid array of `fds.len()` pollfd entries
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if server.stop.is_raised() {
+            break;
+        }
+        if ready < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+
+        let mut closed = vec![];
+        let (listener_fds, connection_fds) = fds.split_at(listeners.len());
+        for (ind, (conn, fd)) in connections.iter_mut().zip(connection_fds).enumerate() {
+            if fd.revents == 0 {
+                if conn.last_seen.elapsed() > server.idle_timeout {
+                    let err = NetworkError::Io(ErrorKind::TimedOut.into());
+                    ClientHandler::log_disconnect(&conn.client, &err);
+                    closed.push(ind);
+                }
+                continue;
+            }
+            if let Err(err) = conn.on_readable(server) {
+                ClientHandler::log_disconnect(&conn.client, &err);
+                closed.push(ind);
+            }
+        }
+        for ind in closed.into_iter().rev() {
+            let conn = connections.remove(ind);
+            server.broadcaster.remove(&conn.client);
+        }
+
+        for (listener, fd) in listeners.iter().zip(listener_fds) {
+            if fd.revents == 0 {
+                continue;
+            }
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Some((client, listen_stream)) = server.accept(stream) {
+                        connections.push(Registered::new(client, listen_stream));
+                        server.broadcaster.broadcast_presence();
+                    }
+                }
+                Err(e) => eprintln!("Connection failed: {}", e),
+            }
+        }
+        debug!("Serving {} clients", connections.len());
+    }
+
+    server.disconnect_all();
+    Ok(())
+}
@@ -0,0 +1,111 @@
+//! The last few distinct clips the server relayed, so clients can ask for an older one.
+
+use std::collections::VecDeque;
+
+use clipper::network::Package;
+
+/// Bounded history of clips, most recent first.
+pub struct History {
+    entries: VecDeque<(Package, usize)>,
+    capacity: usize,
+    max_bytes: usize,
+    bytes: usize,
+}
+
+impl History {
+    /// Keeps up to `capacity` clips, none when it is 0, holding no more than `max_bytes` of
+    /// payload between them.
+    pub fn new(capacity: usize, max_bytes: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            max_bytes,
+            bytes: 0,
+        }
+    }
+
+    /// Records `package` as the latest clip. A clip with the same content already kept moves up
+    /// instead of being kept twice, and the oldest ones are dropped beyond the capacity or the
+    /// byte limit. A clip larger than the byte limit on its own isn't kept, and anything but an
+    /// item is ignored.
+    pub fn push(&mut self, package: Package) {
+        let Package::Item { item, .. } = &package else {
+            return;
+        };
+        let size = item.size_bytes();
+        self.entries.retain(
+            |(entry, _)| !matches!(entry, Package::Item { item: kept, .. } if kept == item),
+        );
+        self.bytes = self.entries.iter().map(|(_, size)| size).sum();
+        if self.capacity == 0 || size > self.max_bytes {
+            return;
+        }
+        self.entries.push_front((package, size));
+        self.bytes += size;
+        while self.entries.len() > self.capacity || self.bytes > self.max_bytes {
+            let (_, size) = self.entries.pop_back().expect("the latest clip fits");
+            self.bytes -= size;
+        }
+    }
+
+    /// The clip copied `index` clips before the latest one, 0 being the latest.
+    pub fn get(&self, index: usize) -> Option<&Package> {
+        self.entries.get(index).map(|(package, _)| package)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_evicts_oldest_test() {
+        let mut history = History::new(2, usize::MAX);
+        for text in ["first", "second", "third"] {
+            history.push(Package::text(text.to_string()));
+        }
+
+        assert_eq!(history.get(0).and_then(Package::as_text), Some("third"));
+        assert_eq!(history.get(1).and_then(Package::as_text), Some("second"));
+        assert_eq!(history.get(2), None);
+    }
+
+    #[test]
+    fn history_dedup_test() {
+        let mut history = History::new(3, usize::MAX);
+        for text in ["first", "second", "first"] {
+            history.push(Package::text(text.to_string()));
+        }
+        history.push(Package::Heartbeat);
+
+        // Copying it again makes it the latest without a second entry
+        assert_eq!(history.get(0).and_then(Package::as_text), Some("first"));
+        assert_eq!(history.get(1).and_then(Package::as_text), Some("second"));
+        assert_eq!(history.get(2), None);
+
+        let mut disabled = History::new(0, usize::MAX);
+        disabled.push(Package::text("first".to_string()));
+        assert_eq!(disabled.get(0), None);
+    }
+
+    #[test]
+    fn history_max_bytes_test() {
+        let mut history = History::new(10, 10);
+        for text in ["first", "second", "third"] {
+            history.push(Package::text(text.to_string()));
+        }
+
+        // "second" and "third" are 11 bytes together
+        assert_eq!(history.get(0).and_then(Package::as_text), Some("third"));
+        assert_eq!(history.get(1), None);
+
+        // Too large to keep at all, so the clips before it stay
+        history.push(Package::text("x".repeat(11)));
+        assert_eq!(history.get(0).and_then(Package::as_text), Some("third"));
+        assert_eq!(history.get(1), None);
+
+        history.push(Package::text("sixth".to_string()));
+        assert_eq!(history.get(0).and_then(Package::as_text), Some("sixth"));
+        assert_eq!(history.get(1).and_then(Package::as_text), Some("third"));
+    }
+}
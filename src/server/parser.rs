@@ -1,4 +1,7 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clipper::network::PreSharedKey;
 
 pub use clap::Parser;
 
@@ -6,7 +9,79 @@ pub use clap::Parser;
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
-    /// Target socket
+    /// Address to listen on; repeat to listen on several, e.g. both IPv4 and IPv6
+    #[arg(short, long, required = true)]
+    pub socket: Vec<SocketAddr>,
+    /// Verbose
     #[arg(short, long)]
-    pub socket: SocketAddr,
+    pub verbose: bool,
+    /// Log at this level, e.g. warn or trace, instead of info; RUST_LOG takes precedence
+    #[arg(long, conflicts_with = "verbose")]
+    pub log_level: Option<log::LevelFilter>,
+    /// Maximum bytes of package data in flight across all clients
+    #[arg(long)]
+    pub memory_budget: Option<usize>,
+    /// Refuse packages larger than this many bytes
+    #[arg(long, default_value_t = clipper::network::MAX_PACKAGE_LEN)]
+    pub max_package_len: u64,
+    /// Maximum number of bytes of each package hex dumped at trace level
+    #[arg(long, default_value_t = 64)]
+    pub max_packet_log_bytes: usize,
+    /// Log copied text in full instead of its length and a hash, for debugging
+    #[arg(long)]
+    pub log_content: bool,
+    /// Only admit clients that prove they know this secret; requires them to use encryption
+    #[arg(long)]
+    pub psk: Option<PreSharedKey>,
+    /// Refuse clients that don't encrypt their connection, so no clip is ever broadcast in
+    /// plaintext
+    #[arg(long)]
+    pub require_encryption: bool,
+    /// Disconnect clients that send nothing, not even their heartbeat every 10 seconds, for this
+    /// many seconds
+    #[arg(long, default_value_t = clipper::network::IDLE_TIMEOUT.as_secs())]
+    pub idle_timeout: u64,
+    /// Disconnect clients that accept nothing sent to them for this many seconds
+    #[arg(
+        long,
+        default_value_t = clipper::network::WRITE_TIMEOUT.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub write_timeout: u64,
+    /// Refuse new connections, observers included, while this many devices are connected
+    #[arg(long)]
+    pub max_clients: Option<usize>,
+    /// Number of distinct clips kept for clients asking for an older one, 0 to keep none
+    #[arg(long, default_value_t = crate::DEFAULT_HISTORY)]
+    pub history: usize,
+    /// Payload bytes the history holds at most, dropping the oldest clips beyond it
+    #[arg(long, default_value_t = crate::DEFAULT_HISTORY_BYTES)]
+    pub history_bytes: usize,
+    /// Leave Nagle's algorithm enabled on client connections
+    #[arg(long)]
+    pub no_tcp_nodelay: bool,
+    /// Advertise the server on the local network so clients started with --discover find it
+    #[arg(long)]
+    pub advertise: bool,
+    /// Serve every client from a single thread polling their sockets, instead of one thread
+    /// per client
+    #[arg(long, conflicts_with = "tls_cert")]
+    pub event_loop: bool,
+    /// Serve clients started with --tls over TLS with this PEM certificate chain, leaf first
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+}
+
+impl Args {
+    /// Logging filter used unless RUST_LOG is set.
+    pub fn log_filter(&self) -> String {
+        match self.log_level {
+            Some(level) => level.to_string().to_lowercase(),
+            None if self.verbose => "debug".to_string(),
+            None => "info".to_string(),
+        }
+    }
 }
@@ -1,44 +1,96 @@
 use clipper::network::Package;
-use log::{debug, info};
-use std::io::{Read, Write};
-use std::net::SocketAddr;
+use log::{debug, info, warn};
+use std::io::{BufReader, BufWriter, ErrorKind};
 use std::net::TcpListener;
 use std::net::TcpStream;
-use std::ops::Deref;
+use std::net::{Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::RwLock;
-use std::sync::Weak;
+use std::sync::{OnceLock, Weak};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
+use clipper::chunk::{send_chunked_from, split, Reassembler};
 use clipper::network::*;
+use clipper::tls::Acceptor;
 
+#[cfg(unix)]
+mod event_loop;
+mod history;
 mod parser;
 
+use history::History;
+
+/// Clips kept for clients asking for an older one, unless --history says otherwise
+const DEFAULT_HISTORY: usize = 10;
+
+/// Payload bytes the history holds at most, unless --history-bytes says otherwise
+const DEFAULT_HISTORY_BYTES: usize = 64 * 1024 * 1024;
+
 struct Client {
-    stream: TcpStream,
+    name: String,
+    stream: BufWriter<Stream>,
     package: Package,
+    /// [`Package::content_hash`] of the clip the client last sent or was sent, so the same
+    /// content copied on another device isn't sent to it again
+    seen: Option<u64>,
     shared_key: Arc<Option<SharedKey>>,
+    /// Format and codec negotiated with the client, used both ways
+    encoding: Encoding,
+    /// Everything sent to the client so far
+    sent: TransferTotals,
+    /// Large files the client is sending in chunks
+    chunks: Reassembler,
+    /// The current clip when the client connected, if it is a large file: held back until the
+    /// client's first package, which may ask to resume it
+    catch_up: Option<Package>,
+}
+
+impl Client {
+    /// Sends `package` to the client, in chunks if it is a large file, and adds it to the totals.
+    fn send(&mut self, package: &Package) -> Result<(), NetworkError> {
+        self.send_from(package, 0)
+    }
+
+    /// Like [`Client::send`], leaving out the chunks before byte `offset`, which the client
+    /// already has.
+    fn send_from(&mut self, package: &Package, offset: u64) -> Result<(), NetworkError> {
+        let stats = send_chunked_from(
+            package,
+            offset,
+            &mut self.stream,
+            &self.shared_key,
+            self.encoding,
+        )?;
+        stats.iter().for_each(|stats| self.sent.add(stats));
+        Ok(())
+    }
 }
 
+/// A client that completed the handshake, with the stream its packages arrive on
+type Accepted = (Arc<RwLock<Client>>, BufReader<Stream>);
+
 struct ClientHandler {
     client: Arc<RwLock<Client>>,
-    listen_stream: TcpStream,
+    listen_stream: BufReader<Stream>,
     server_package: Arc<RwLock<Package>>,
+    history: Arc<RwLock<History>>,
 }
 
 impl ClientHandler {
-    fn new(client: Arc<RwLock<Client>>, server_package: Arc<RwLock<Package>>) -> Self {
-        let tcp_clone = client
-            .read()
-            .unwrap()
-            .stream
-            .try_clone()
-            .expect("Unable to clone TcpStream");
+    fn new(
+        client: Arc<RwLock<Client>>,
+        listen_stream: BufReader<Stream>,
+        server_package: Arc<RwLock<Package>>,
+        history: Arc<RwLock<History>>,
+    ) -> Self {
         Self {
             client,
-            listen_stream: tcp_clone,
+            listen_stream,
             server_package,
+            history,
         }
     }
 
@@ -47,127 +99,549 @@ impl ClientHandler {
             let client = self.client;
             let mut stream = self.listen_stream;
             let server_package = self.server_package;
+            let history = self.history;
             let shared_key = client.read().unwrap().shared_key.clone();
+            let encoding = client.read().unwrap().encoding;
             loop {
-                let package_received = receive_package(&mut stream, &shared_key);
-                if let Ok(package) = package_received {
-                    if !matches!(package, Package::Empty) {
-                        if client.read().unwrap().package != package {
-                            client.write().unwrap().package = package;
-                        }
-                        if *server_package.read().unwrap() < client.read().unwrap().package {
-                            *server_package.write().unwrap() =
-                                client.read().unwrap().package.clone();
-                            broadcaster.boardcast();
-                        }
+                match receive_package(&mut stream, &shared_key, encoding) {
+                    Ok(package) => ClientHandler::handle(
+                        &client,
+                        &server_package,
+                        &history,
+                        &broadcaster,
+                        package,
+                    ),
+                    Err(err) => {
+                        ClientHandler::log_disconnect(&client, &err);
+                        break;
                     }
-                } else if let Err(_) = package_received {
-                    // server disconnected
-                    break;
                 }
             }
+            broadcaster.remove(&client);
         })
     }
+
+    fn handle(
+        client: &RwLock<Client>,
+        server_package: &RwLock<Package>,
+        history: &RwLock<History>,
+        broadcaster: &Broadcaster,
+        package: Package,
+    ) {
+        let catch_up = client.write().unwrap().catch_up.take();
+        if let Some(catch_up) = catch_up {
+            if ClientHandler::catch_up(client, server_package, catch_up, &package) {
+                return;
+            }
+        }
+        let Some(package) = client.write().unwrap().chunks.push(package) else {
+            return;
+        };
+        match package {
+            Package::Item { .. } | Package::Clear { .. } => {
+                let ack = package.ack();
+                if client.read().unwrap().package != package {
+                    let mut client = client.write().unwrap();
+                    client.seen = package.content_hash();
+                    client.package = package;
+                }
+                if *server_package.read().unwrap() < client.read().unwrap().package {
+                    let package = client.read().unwrap().package.clone();
+                    *server_package.write().unwrap() = package.clone();
+                    // Clearing leaves the clips copied before it in the history
+                    if matches!(package, Package::Item { .. }) {
+                        history.write().unwrap().push(package);
+                    }
+                    let name = client.read().unwrap().name.clone();
+                    broadcaster.boardcast(&name);
+                }
+                // Even when it is older than the clip kept, the client can stop resending it
+                if let Some(ack) = ack {
+                    let mut client = client.write().unwrap();
+                    if let Err(err) = client.send(&ack) {
+                        debug!("Unable to acknowledge to {}: {err}", client.name);
+                    }
+                }
+            }
+            Package::HistoryRequest { index } => {
+                let package = history.read().unwrap().get(index).cloned().map(Box::new);
+                let mut client = client.write().unwrap();
+                if let Err(err) = client.send(&Package::HistoryEntry { index, package }) {
+                    debug!("Unable to send history to {}: {err}", client.name);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Sends the clip held back when the client connected, see [`Client::catch_up`], unless a
+    /// newer one was broadcast since. Starts where `first`, the client's first package, asks to
+    /// resume it, and returns whether it did.
+    fn catch_up(
+        client: &RwLock<Client>,
+        server_package: &RwLock<Package>,
+        package: Package,
+        first: &Package,
+    ) -> bool {
+        if *server_package.read().unwrap() != package {
+            return false;
+        }
+        let (offset, resumed) = match first {
+            Package::Resume { id, offset }
+                if split(&package).is_ok_and(|chunks| chunks.is_some_and(|c| c.id() == *id)) =>
+            {
+                (*offset, true)
+            }
+            _ => (0, false),
+        };
+        let mut client = client.write().unwrap();
+        match client.send_from(&package, offset) {
+            Ok(()) => {
+                client.seen = package.content_hash();
+                client.package = package;
+            }
+            Err(err) => debug!("Unable to send the current clip to {}: {err}", client.name),
+        }
+        resumed
+    }
+
+    fn log_disconnect(client: &RwLock<Client>, err: &NetworkError) {
+        let name = client.read().unwrap().name.clone();
+        info!("Sent {name} {}", client.read().unwrap().sent);
+        let NetworkError::Io(io_err) = err else {
+            warn!("{name} sent a bad package, disconnecting: {err}");
+            return;
+        };
+        match io_err.kind() {
+            ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted => {
+                info!("{name} disconnected")
+            }
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => warn!("{name} timed out"),
+            ErrorKind::InvalidData => {
+                warn!("{name} sent a malformed package, disconnecting: {err}")
+            }
+            _ => warn!("Lost connection to {name}: {err}"),
+        }
+    }
 }
 
 // This is needed to make clients and package thread-safe without putting the entire server under Arc and Rwlock
 struct Broadcaster {
     clients: Weak<RwLock<Vec<Arc<RwLock<Client>>>>>,
+    observers: Weak<RwLock<Vec<Client>>>,
     package: Weak<RwLock<Package>>,
 }
 
 impl Broadcaster {
-    fn boardcast(&self) {
+    /// Sends the server's package to every client that doesn't have it yet, `from` being the
+    /// device it came from. Clients that already have the same content, e.g. because it was
+    /// copied on two devices at once, are skipped too.
+    fn boardcast(&self, from: &str) {
         let _arc_package = self.package.upgrade().expect("Server disconnected");
         let clients = self.clients.upgrade().expect("Server disconnected");
 
         let package = _arc_package.read().unwrap();
-        let mut package_to_remove = vec![];
+        let hash = package.content_hash();
 
-        info!("Broadcasting: {}", package);
+        info!("Broadcasting from {from}: {}", package.redacted());
 
-        for (ind, client) in clients.read().unwrap().iter().enumerate() {
-            if client.read().unwrap().package != *package {
+        let removed = send_to_clients(&clients, |client| {
+            let has_it = {
+                let client = client.read().unwrap();
+                client.package == *package || (hash.is_some() && client.seen == hash)
+            };
+            if has_it {
+                return true;
+            }
+            let mut target = client.write().unwrap();
+            let sent = target.send(&package).is_ok();
+            if sent {
+                target.seen = hash;
+            } else {
+                debug!("{} disconnected", target.name);
+            }
+            sent
+        });
+
+        let observers = self.observers.upgrade().expect("Server disconnected");
+        observers.write().unwrap().retain_mut(|observer| {
+            let sent = observer.send(&package).is_ok();
+            if !sent {
+                debug!("Observer disconnected");
+            }
+            sent
+        });
+        debug!("Broadcasting done");
+
+        drop(package);
+        if removed {
+            self.broadcast_presence();
+        }
+    }
+
+    /// Tells every client and observer which devices are currently connected. Clients it doesn't
+    /// reach are dropped, and the others told again without them.
+    fn broadcast_presence(&self) {
+        let clients = self.clients.upgrade().expect("Server disconnected");
+        let presence = loop {
+            let peers = clients
+                .read()
+                .unwrap()
+                .iter()
+                .map(|client| client.read().unwrap().name.clone())
+                .collect();
+            let presence = Package::Presence { peers };
+
+            info!("Broadcasting: {}", presence);
+
+            let removed = send_to_clients(&clients, |client| {
                 let mut target = client.write().unwrap();
-                let key = target.shared_key.clone();
-                if send_package(package.deref(), &mut target.stream, &key).is_err() {
-                    debug!("Client disconnected");
-                    package_to_remove.push(ind);
+                let sent = target.send(&presence).is_ok();
+                if !sent {
+                    debug!("{} disconnected", target.name);
                 }
+                sent
+            });
+            if !removed {
+                break presence;
+            }
+        };
+
+        let observers = self.observers.upgrade().expect("Server disconnected");
+        observers
+            .write()
+            .unwrap()
+            .retain_mut(|observer| observer.send(&presence).is_ok());
+    }
+
+    /// Sends a heartbeat to every client and observer, dropping the ones it doesn't reach.
+    fn heartbeat(&self) {
+        let (Some(clients), Some(observers)) = (self.clients.upgrade(), self.observers.upgrade())
+        else {
+            return;
+        };
+        let removed = send_to_clients(&clients, |client| {
+            let mut target = client.write().unwrap();
+            let sent = target.send(&Package::Heartbeat).is_ok();
+            if !sent {
+                debug!("{} disconnected", target.name);
             }
+            sent
+        });
+        observers
+            .write()
+            .unwrap()
+            .retain_mut(|observer| observer.send(&Package::Heartbeat).is_ok());
+        if removed {
+            self.broadcast_presence();
         }
-        for i in package_to_remove {
-            clients.write().unwrap().swap_remove(i);
+    }
+
+    fn remove(&self, client: &Arc<RwLock<Client>>) {
+        let clients = self.clients.upgrade().expect("Server disconnected");
+        let before = clients.read().unwrap().len();
+        clients
+            .write()
+            .unwrap()
+            .retain(|other| !Arc::ptr_eq(other, client));
+        if clients.read().unwrap().len() != before {
+            self.broadcast_presence();
         }
-        debug!("Broadcasting done");
+    }
+}
+
+/// Calls `send` for every client, which returns whether the client was reached, and removes
+/// the ones that weren't. The sends run on a snapshot of the pool, so a client slow to take
+/// them doesn't keep others from connecting meanwhile. Returns whether a client was removed.
+fn send_to_clients(
+    clients: &RwLock<Vec<Arc<RwLock<Client>>>>,
+    mut send: impl FnMut(&RwLock<Client>) -> bool,
+) -> bool {
+    let targets = clients.read().unwrap().clone();
+    let unreached: Vec<_> = targets.into_iter().filter(|client| !send(client)).collect();
+    if unreached.is_empty() {
+        return false;
+    }
+    clients
+        .write()
+        .unwrap()
+        .retain(|client| !unreached.iter().any(|other| Arc::ptr_eq(client, other)));
+    true
+}
+
+/// `name`, or if a connected device already uses it `name (2)`, `name (3)`, ... so presence
+/// lists and logs can tell the devices apart.
+fn unique_name(name: &str, clients: &[Arc<RwLock<Client>>], observers: &[Client]) -> String {
+    let taken = |candidate: &str| {
+        clients
+            .iter()
+            .any(|client| client.read().unwrap().name == candidate)
+            || observers.iter().any(|observer| observer.name == candidate)
+    };
+    if !taken(name) {
+        return name.to_string();
+    }
+    let unique = (2..)
+        .map(|suffix| format!("{name} ({suffix})"))
+        .find(|candidate| !taken(candidate))
+        .unwrap();
+    warn!("A device named {name:?} is already connected, calling the new one {unique:?}");
+    unique
+}
+
+/// Raised to shut the server down. The accept loops only notice it when they wake up, so
+/// raising it also connects to every listening socket to wake them.
+#[derive(Clone, Default)]
+struct Stop {
+    raised: Arc<AtomicBool>,
+    /// Where the listening sockets can be reached, known once they are bound
+    addrs: Arc<OnceLock<Vec<SocketAddr>>>,
+}
+
+impl Stop {
+    fn is_raised(&self) -> bool {
+        self.raised.load(Ordering::SeqCst)
+    }
+
+    fn raise(&self) {
+        self.raised.store(true, Ordering::SeqCst);
+        for addr in self.addrs.get().into_iter().flatten() {
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    fn listening_on(&self, listeners: &[TcpListener]) -> std::io::Result<()> {
+        let mut addrs = Vec::new();
+        for listener in listeners {
+            let mut addr = listener.local_addr()?;
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            addrs.push(addr);
+        }
+        let _ = self.addrs.set(addrs);
+        Ok(())
     }
 }
 
 struct Server {
-    addr: SocketAddr,
+    /// Addresses to listen on, each with a socket of its own
+    addrs: Vec<SocketAddr>,
+    nodelay: bool,
+    /// Clients must prove they know it before they are added
+    psk: Option<PreSharedKey>,
+    /// Refuse clients that don't encrypt, so none can make the server broadcast in plaintext
+    require_encryption: bool,
     clients: Arc<RwLock<Vec<Arc<RwLock<Client>>>>>,
+    observers: Arc<RwLock<Vec<Client>>>,
     package: Arc<RwLock<Package>>,
+    /// The latest distinct clips, `package` first
+    history: Arc<RwLock<History>>,
     broadcaster: Arc<Broadcaster>,
+    stop: Stop,
+    /// Announce the server over mDNS while it runs
+    advertise: bool,
+    /// Run every connection over TLS instead of plain TCP
+    tls: Option<Acceptor>,
+    /// Clients that send nothing for this long are disconnected
+    idle_timeout: Duration,
+    /// Clients that accept nothing sent to them for this long are disconnected
+    write_timeout: Duration,
+    /// Connections beyond this many clients and observers are refused
+    max_clients: Option<usize>,
 }
 
 impl Server {
-    fn new(addr: SocketAddr) -> Self {
+    fn new(addrs: Vec<SocketAddr>, nodelay: bool) -> Self {
         let clients = Arc::new(RwLock::new(Vec::new()));
+        let observers = Arc::new(RwLock::new(Vec::new()));
         let package = Arc::new(RwLock::new(Package::default()));
         let broadcaster = Broadcaster {
             clients: Arc::downgrade(&clients),
+            observers: Arc::downgrade(&observers),
             package: Arc::downgrade(&package),
         };
         Self {
-            addr,
+            addrs,
+            nodelay,
+            psk: None,
+            require_encryption: false,
             clients,
+            observers,
             package,
+            history: Arc::new(RwLock::new(History::new(
+                DEFAULT_HISTORY,
+                DEFAULT_HISTORY_BYTES,
+            ))),
             broadcaster: Arc::new(broadcaster),
+            stop: Stop::default(),
+            advertise: false,
+            tls: None,
+            idle_timeout: IDLE_TIMEOUT,
+            write_timeout: WRITE_TIMEOUT,
+            max_clients: None,
         }
     }
 
-    fn start(&mut self) -> std::io::Result<()> {
-        let listener = TcpListener::bind(self.addr)?;
-        debug!("Server started: {}", listener.local_addr().unwrap());
+    fn with_history(mut self, capacity: usize, max_bytes: usize) -> Self {
+        self.history = Arc::new(RwLock::new(History::new(capacity, max_bytes)));
+        self
+    }
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(mut stream) => {
-                    debug!("New connection: {}", stream.peer_addr().unwrap());
-                    let session = receive_session(&mut stream).expect("Failed to receive session");
-                    let mut shared_key = Arc::new(None);
+    fn with_max_clients(mut self, max_clients: Option<usize>) -> Self {
+        self.max_clients = max_clients;
+        self
+    }
 
-                    if session.use_encryption {
-                        use rand_core::OsRng;
-                        use x25519_dalek::{EphemeralSecret, PublicKey};
-                        let server_private = EphemeralSecret::random_from_rng(OsRng);
-                        let server_public = PublicKey::from(&server_private);
+    fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
 
-                        stream.write_all(server_public.as_bytes())?;
+    fn with_write_timeout(mut self, write_timeout: Duration) -> Self {
+        self.write_timeout = write_timeout;
+        self
+    }
 
-                        let mut client_public_key = [0u8; 32];
-                        stream.read_exact(&mut client_public_key)?;
+    fn with_tls(mut self, tls: Option<Acceptor>) -> Self {
+        self.tls = tls;
+        self
+    }
 
-                        let client_public = PublicKey::from(client_public_key);
-                        let shared_secret = server_private.diffie_hellman(&client_public);
+    fn with_advertise(mut self, advertise: bool) -> Self {
+        self.advertise = advertise;
+        self
+    }
 
-                        shared_key = Arc::new(Some(SharedKey::from(shared_secret.as_bytes())));
+    fn with_psk(mut self, psk: Option<PreSharedKey>) -> Self {
+        self.psk = psk;
+        self
+    }
+
+    fn with_require_encryption(mut self, require_encryption: bool) -> Self {
+        self.require_encryption = require_encryption;
+        self
+    }
 
-                        debug!("Shared key: {:?}", shared_key);
+    /// Binds every address, or takes the sockets passed by systemd. Addresses that can't be
+    /// bound are skipped as long as one of them can.
+    fn bind(&self) -> std::io::Result<Vec<TcpListener>> {
+        let mut listeners = inherited_listeners();
+        if listeners.is_empty() {
+            let mut last_err = None;
+            for addr in &self.addrs {
+                match TcpListener::bind(addr) {
+                    Ok(listener) => listeners.push(listener),
+                    Err(err) => {
+                        warn!("Unable to bind to {addr}: {err}");
+                        last_err = Some(err);
                     }
+                }
+            }
+            if listeners.is_empty() {
+                return Err(last_err.unwrap_or_else(|| {
+                    std::io::Error::new(ErrorKind::InvalidInput, "No address to listen on")
+                }));
+            }
+        } else {
+            info!("Using sockets passed by systemd");
+        }
+        for listener in &listeners {
+            info!("Listening on {}", listener.local_addr()?);
+        }
+        self.stop.listening_on(&listeners)?;
+        Ok(listeners)
+    }
 
-                    let client = Client {
-                        stream,
-                        package: Package::default(),
-                        shared_key,
-                    };
+    /// Advertises the port of the first listener if enabled, for as long as the returned value
+    /// is kept. Failing to is logged rather than fatal, clients can still be given the address.
+    fn advertise(&self, listeners: &[TcpListener]) -> Option<clipper::discovery::Advertisement> {
+        if !self.advertise {
+            return None;
+        }
+        let port = listeners.first()?.local_addr().ok()?.port();
+        let name = default_device_name();
+        match clipper::discovery::advertise(&name, port) {
+            Ok(advertisement) => {
+                info!("Advertising {name:?} on port {port}");
+                Some(advertisement)
+            }
+            Err(err) => {
+                warn!("Unable to advertise the server: {err}");
+                None
+            }
+        }
+    }
 
-                    let shared_client = Arc::new(RwLock::new(client));
-                    self.add_client(Arc::clone(&shared_client));
+    /// Stops the server on Ctrl-C: no new connections are accepted, every client is
+    /// disconnected and [`Server::start`] returns once their threads have finished.
+    fn stop_on_ctrl_c(&self) {
+        let stop = self.stop.clone();
+        ctrlc::set_handler(move || {
+            info!("Interrupted, shutting down");
+            stop.raise();
+        })
+        .expect("Unable to install the Ctrl-C handler");
+    }
+
+    /// Sends a heartbeat to every client and observer every [`HEARTBEAT_INTERVAL`] until the
+    /// server stops, so they notice when it goes away.
+    fn start_heartbeat(&self) {
+        let broadcaster = Arc::clone(&self.broadcaster);
+        let stop = self.stop.clone();
+        thread::spawn(move || loop {
+            thread::sleep(HEARTBEAT_INTERVAL);
+            if stop.is_raised() {
+                return;
+            }
+            broadcaster.heartbeat();
+        });
+    }
+
+    fn start(&mut self) -> std::io::Result<()> {
+        let listeners = self.bind()?;
+        let _advertisement = self.advertise(&listeners);
+        self.start_heartbeat();
+        let mut handlers = Vec::new();
+
+        // A thread per socket waits for connections, and hands them over to be accepted one at a
+        // time into the same pool
+        let (connections, incoming) = std::sync::mpsc::channel();
+        for listener in listeners {
+            let connections = connections.clone();
+            let stop = self.stop.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.is_raised() || connections.send(stream).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        drop(connections);
 
-                    let client_handler =
-                        ClientHandler::new(Arc::clone(&shared_client), Arc::clone(&self.package));
-                    client_handler.start_listener(Arc::clone(&self.broadcaster));
+        for stream in incoming {
+            if self.stop.is_raised() {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    let Some((client, listen_stream)) = self.accept(stream) else {
+                        continue;
+                    };
+                    let client_handler = ClientHandler::new(
+                        client,
+                        listen_stream,
+                        Arc::clone(&self.package),
+                        Arc::clone(&self.history),
+                    );
+                    handlers.push(client_handler.start_listener(Arc::clone(&self.broadcaster)));
+                    self.broadcaster.broadcast_presence();
                 }
                 Err(e) => {
                     eprintln!("Connection failed: {}", e);
@@ -175,21 +649,1330 @@ impl Server {
             }
         }
 
+        self.disconnect_all();
+        for handler in handlers {
+            let _ = handler.join();
+        }
         Ok(())
     }
 
-    fn add_client(&mut self, client: Arc<RwLock<Client>>) {
-        self.clients.write().unwrap().push(Arc::clone(&client));
+    /// Closes the connection to every client and observer, which ends their listener threads.
+    fn disconnect_all(&self) {
+        for client in self.clients.read().unwrap().iter() {
+            let _ = client
+                .read()
+                .unwrap()
+                .stream
+                .get_ref()
+                .tcp()
+                .shutdown(Shutdown::Both);
+        }
+        for observer in self.observers.write().unwrap().drain(..) {
+            let _ = observer.stream.get_ref().tcp().shutdown(Shutdown::Both);
+        }
+        info!("Disconnected every client");
+    }
+
+    /// Runs the handshake with a new connection. Observers are registered right away and yield
+    /// `None`, as do refused connections; other clients are added and returned along with the
+    /// stream to read from.
+    ///
+    /// A failure only concerns this connection, e.g. a client resetting it right after
+    /// connecting, so it is logged and the connection dropped while the server goes on.
+    fn accept(&mut self, stream: TcpStream) -> Option<Accepted> {
+        match self.try_accept(stream) {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Dropping a new connection: {err}");
+                None
+            }
+        }
+    }
+
+    fn try_accept(&mut self, stream: TcpStream) -> std::io::Result<Option<Accepted>> {
+        let peer = stream.peer_addr()?;
+        debug!("New connection: {peer}");
+        stream.set_nodelay(self.nodelay)?;
+        // The handshake runs on the accept loop, which a client connecting and staying silent
+        // would otherwise hold up for the whole idle timeout
+        let handshake_timeout = HANDSHAKE_TIMEOUT.min(self.idle_timeout);
+        stream.set_read_timeout(Some(handshake_timeout))?;
+        // A client that stops reading would otherwise block every broadcast to it for good
+        stream.set_write_timeout(Some(self.write_timeout))?;
+        let socket = stream.try_clone()?;
+        // The read timeout starts over with every byte, so a client sending one at a time
+        // needs this to be cut off too
+        let _deadline = HandshakeDeadline::new(socket.try_clone()?, handshake_timeout);
+        let stream = match &self.tls {
+            Some(tls) => match tls.accept(stream) {
+                Ok(stream) => Stream::from(stream),
+                Err(err) => {
+                    warn!("TLS handshake failed, closing connection: {err}");
+                    return Ok(None);
+                }
+            },
+            None => Stream::from(stream),
+        };
+        let mut stream = Connection::new(stream)?;
+        let connected = self.clients.read().unwrap().len() + self.observers.read().unwrap().len();
+        if self.max_clients.is_some_and(|max| connected >= max) {
+            let reason = format!("The server is full, {connected} devices are connected");
+            match stream.refuse(&reason) {
+                Ok(session) => warn!("Refused {} from {peer}: {reason}", session.device_name),
+                Err(err) => warn!("Refused {peer}: {err}"),
+            }
+            return Ok(None);
+        }
+        let session = match stream.accept(self.psk.as_ref(), self.require_encryption) {
+            Ok(session) => session,
+            // Only this connection is affected, whatever the client sent
+            Err(err) => {
+                warn!("Refused {peer}: {err}, closing connection");
+                return Ok(None);
+            }
+        };
+        // Clients send heartbeats, so a read waiting this long means the client is gone
+        socket.set_read_timeout(Some(self.idle_timeout))?;
+        let shared_key = stream.shared_key();
+        let encoding = stream.encoding();
+
+        // Both pools stay locked until the client is in one, so two devices connecting with the
+        // same name at once can't both be given it
+        let mut clients = self.clients.write().unwrap();
+        let mut observers = self.observers.write().unwrap();
+        let name = unique_name(&session.device_name, &clients, &observers);
+        info!("{name} connected from {peer} ({})", session.os);
+        let (listen_stream, stream) = stream.split();
+        let client = Client {
+            name,
+            stream,
+            package: Package::default(),
+            seen: None,
+            shared_key,
+            encoding,
+            sent: TransferTotals::default(),
+            chunks: Reassembler::default(),
+            catch_up: None,
+        };
+
+        if session.observer {
+            // Observers only receive broadcasts, nothing they send is read
+            info!("{} is an observer", client.name);
+            observers.push(client);
+            drop((clients, observers));
+            self.broadcaster.broadcast_presence();
+            return Ok(None);
+        }
+
+        let shared_client = Arc::new(RwLock::new(client));
+        clients.push(Arc::clone(&shared_client));
+        drop((clients, observers));
+        self.add_client(&shared_client);
+        Ok(Some((shared_client, listen_stream)))
+    }
+
+    /// Sends `client`, just added to the pool, the current clip, so a client that just
+    /// (re)connected doesn't wait for the next copy to catch up.
+    fn add_client(&self, client: &RwLock<Client>) {
+        let package = self.package.read().unwrap().clone();
+        // A clear only makes sense for the clipboards that held the clip, replaying it would
+        // wipe whatever a (re)connecting client copied in the meantime
+        if matches!(package, Package::Empty | Package::Clear { .. }) {
+            return;
+        }
+        let mut client = client.write().unwrap();
+        // The client first says how much of a large file it got before losing the connection
+        if matches!(split(&package), Ok(Some(_))) {
+            client.catch_up = Some(package);
+            return;
+        }
+        match client.send(&package) {
+            // Also keeps the next broadcast of the same clip from sending it again
+            Ok(()) => {
+                client.seen = package.content_hash();
+                client.package = package;
+            }
+            Err(err) => debug!("Unable to send the current clip to {}: {err}", client.name),
+        }
     }
 }
 
-fn main() {
-    env_logger::init();
+/// Shuts a connection down unless dropped within the timeout, so the whole handshake is bounded
+/// and not only each read.
+struct HandshakeDeadline {
+    /// Never sent on, dropping it tells the watching thread the handshake is over
+    _done: std::sync::mpsc::Sender<()>,
+}
 
+impl HandshakeDeadline {
+    fn new(socket: TcpStream, timeout: Duration) -> Self {
+        let (done, finished) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            // Dropping the deadline disconnects the channel and ends the wait early
+            if let Err(std::sync::mpsc::RecvTimeoutError::Timeout) = finished.recv_timeout(timeout)
+            {
+                debug!("Handshake timed out, closing connection");
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+        });
+        Self { _done: done }
+    }
+}
+
+/// Takes over the listening sockets passed by systemd socket activation (see
+/// `sd_listen_fds(3)`), none when the server wasn't started that way.
+#[cfg(unix)]
+fn inherited_listeners() -> Vec<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    const SD_LISTEN_FDS_START: i32 = 3;
+
+    let listen_fds = || -> Option<i32> {
+        let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+        if pid != std::process::id() {
+            return None;
+        }
+        std::env::var("LISTEN_FDS").ok()?.parse().ok()
+    };
+    let Some(fds) = listen_fds() else {
+        return Vec::new();
+    };
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    // Safety: systemd hands over ownership of the sockets starting at SD_LISTEN_FDS_START
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds.max(0))
+        .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn inherited_listeners() -> Vec<TcpListener> {
+    Vec::new()
+}
+
+fn main() {
     use crate::parser::*;
 
     let args = Args::parse();
-    let socket = args.socket;
-    let mut server = Server::new(socket);
-    let _ = server.start().expect("Unable to bind to socket {socket}");
+
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(args.log_filter()))
+        .init();
+    set_max_packet_log_bytes(args.max_packet_log_bytes);
+    clipper::item::set_log_content(args.log_content);
+    set_max_package_len(args.max_package_len);
+    if let Some(bytes) = args.memory_budget {
+        set_memory_budget(bytes);
+    }
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            Some(Acceptor::from_pem_files(cert, key).expect("Unable to load the TLS certificate"))
+        }
+        _ => None,
+    };
+    let mut server = Server::new(args.socket, !args.no_tcp_nodelay)
+        .with_psk(args.psk)
+        .with_require_encryption(args.require_encryption)
+        .with_advertise(args.advertise)
+        .with_tls(tls)
+        .with_idle_timeout(Duration::from_secs(args.idle_timeout))
+        .with_write_timeout(Duration::from_secs(args.write_timeout))
+        .with_max_clients(args.max_clients)
+        .with_history(args.history, args.history_bytes);
+    server.stop_on_ctrl_c();
+    if args.event_loop {
+        #[cfg(unix)]
+        event_loop::run(&mut server).expect("Unable to listen on any socket");
+        #[cfg(not(unix))]
+        panic!("--event-loop is only supported on unix");
+    } else {
+        server.start().expect("Unable to listen on any socket");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::sync::mpsc;
+
+    /// A client whose server side stream is connected to a real socket, closed for writing when
+    /// `alive` is false so every send to it fails.
+    fn client(listener: &TcpListener, name: &str, alive: bool) -> (Arc<RwLock<Client>>, TcpStream) {
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        if !alive {
+            stream.shutdown(Shutdown::Write).unwrap();
+        }
+        let client = Client {
+            name: name.to_string(),
+            stream: BufWriter::new(stream.into()),
+            package: Package::default(),
+            seen: None,
+            shared_key: Arc::new(None),
+            encoding: Encoding::default(),
+            sent: TransferTotals::default(),
+            chunks: Reassembler::default(),
+            catch_up: None,
+        };
+        (Arc::new(RwLock::new(client)), peer)
+    }
+
+    #[test]
+    fn log_level_test() {
+        use parser::{Args, Parser};
+
+        let parse = |flags: &[&str]| {
+            let args = ["server", "-s", "127.0.0.1:4000"].iter().chain(flags);
+            Args::try_parse_from(args)
+        };
+        assert_eq!(parse(&[]).unwrap().log_filter(), "info");
+        assert_eq!(parse(&["--verbose"]).unwrap().log_filter(), "debug");
+        assert_eq!(parse(&["-v"]).unwrap().log_filter(), "debug");
+        assert_eq!(
+            parse(&["--log-level", "trace"]).unwrap().log_filter(),
+            "trace"
+        );
+        assert!(parse(&["--log-level", "loud"]).is_err());
+        assert!(parse(&["-v", "--log-level", "warn"]).is_err());
+    }
+
+    #[test]
+    fn protocol_version_mismatch_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut server = Server::new(vec![listener.local_addr().unwrap()], true);
+
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let session = SessionInfo {
+            device_name: "old".to_string(),
+            os: "linux".to_string(),
+            use_encryption: false,
+            observer: false,
+            ciphers: Vec::new(),
+            protocol_version: PROTOCOL_VERSION - 1,
+            compression: None,
+            format: Format::Bincode,
+        };
+        send_session(&mut peer, &session).unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        assert!(server.accept(stream).is_none());
+        assert!(server.clients.read().unwrap().is_empty());
+
+        assert!(!receive_session_accepted(&mut peer).unwrap());
+        // The connection is closed without a single package
+        assert_eq!(peer.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn unencrypted_session_refused_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut server =
+            Server::new(vec![listener.local_addr().unwrap()], true).with_require_encryption(true);
+
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        send_session(&mut peer, &SessionInfo::new("plain")).unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        assert!(server.accept(stream).is_none());
+        assert!(server.clients.read().unwrap().is_empty());
+
+        assert_eq!(
+            receive_session_reply(&mut peer).unwrap(),
+            SessionReply::Refused(Some("The server requires encryption".to_string()))
+        );
+        assert_eq!(peer.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    /// Runs the client side of an encrypted handshake proving `psk` and returns whether the
+    /// server accepted it, along with the number of clients it has afterwards.
+    fn psk_handshake(server_psk: &str, client_psk: &str) -> (bool, usize) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut server = Server::new(vec![listener.local_addr().unwrap()], true)
+            .with_psk(Some(server_psk.parse().unwrap()));
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let accepted = thread::scope(|s| {
+            s.spawn(|| server.accept(stream));
+
+            let session = SessionInfo {
+                device_name: "laptop".to_string(),
+                os: "linux".to_string(),
+                use_encryption: true,
+                observer: false,
+                ciphers: Cipher::SUPPORTED.to_vec(),
+                protocol_version: PROTOCOL_VERSION,
+                compression: None,
+                format: Format::Bincode,
+            };
+            send_session(&mut peer, &session).unwrap();
+            assert!(receive_session_accepted(&mut peer).unwrap());
+            receive_cipher(&mut peer).unwrap().unwrap();
+
+            let exchange = client_handshake(&mut peer).unwrap();
+
+            let psk: PreSharedKey = client_psk.parse().unwrap();
+            let proof = psk.prove(&exchange.server_public, &exchange.client_public);
+            send_psk_proof(&mut peer, &proof).unwrap();
+            receive_session_accepted(&mut peer).unwrap()
+        });
+        let clients = server.clients.read().unwrap().len();
+        (accepted, clients)
+    }
+
+    #[test]
+    fn pre_shared_key_test() {
+        assert_eq!(psk_handshake("secret", "secret"), (true, 1));
+        assert_eq!(psk_handshake("secret", "guess"), (false, 0));
+    }
+
+    #[test]
+    fn rapid_updates_propagate_in_order_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::new(vec![listener.local_addr().unwrap()], true);
+        let (sender, _sender_peer) = client(&listener, "sender", true);
+        let (receiver, mut receiver_peer) = client(&listener, "receiver", true);
+        server.clients.write().unwrap().push(Arc::clone(&sender));
+        server.clients.write().unwrap().push(receiver);
+
+        let first = Package::text("first".to_string());
+        let second = Package::text("second".to_string());
+        for package in [first.clone(), second.clone()] {
+            ClientHandler::handle(
+                &sender,
+                &server.package,
+                &server.history,
+                &server.broadcaster,
+                package,
+            );
+        }
+
+        assert_eq!(*server.package.read().unwrap(), second);
+        assert_eq!(
+            receive_package(&mut receiver_peer, &None, Encoding::default()).unwrap(),
+            first
+        );
+        assert_eq!(
+            receive_package(&mut receiver_peer, &None, Encoding::default()).unwrap(),
+            second
+        );
+    }
+
+    /// Waits for the server owning `stop` to bind and returns the addresses it listens on.
+    fn listening_on(stop: &Stop) -> Vec<SocketAddr> {
+        loop {
+            if let Some(addrs) = stop.addrs.get() {
+                return addrs.clone();
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    /// Connects to `addr` and completes an unencrypted handshake as `name`.
+    fn connect(addr: SocketAddr, name: &str) -> TcpStream {
+        let mut peer = TcpStream::connect(addr).unwrap();
+        let session = SessionInfo {
+            device_name: name.to_string(),
+            os: "linux".to_string(),
+            use_encryption: false,
+            observer: false,
+            ciphers: Vec::new(),
+            protocol_version: PROTOCOL_VERSION,
+            compression: None,
+            format: Format::Bincode,
+        };
+        send_session(&mut peer, &session).unwrap();
+        assert_eq!(
+            receive_session_reply(&mut peer).unwrap(),
+            SessionReply::Accepted(Encoding::default())
+        );
+        peer
+    }
+
+    fn names(clients: &RwLock<Vec<Arc<RwLock<Client>>>>) -> Vec<String> {
+        let clients = clients.read().unwrap();
+        clients
+            .iter()
+            .map(|c| c.read().unwrap().name.clone())
+            .collect()
+    }
+
+    /// Runs a server listening on two sockets with `run` and checks that clients connecting to
+    /// either end up in the same pool.
+    fn multiple_listeners(run: fn(&mut Server) -> std::io::Result<()>) {
+        let localhost = "127.0.0.1:0".parse().unwrap();
+        let mut server = Server::new(vec![localhost, localhost], true);
+        let clients = Arc::clone(&server.clients);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || run(&mut server));
+        let addrs = listening_on(&stop);
+        assert_eq!(addrs.len(), 2);
+        assert_ne!(addrs[0], addrs[1]);
+
+        let _first = connect(addrs[0], "first");
+        let _second = connect(addrs[1], "second");
+        // The handshake completes before the client is added
+        for _ in 0..100 {
+            if names(&clients).len() == 2 {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(names(&clients), ["first", "second"]);
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn multiple_listeners_threaded_test() {
+        multiple_listeners(Server::start);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn multiple_listeners_event_loop_test() {
+        multiple_listeners(event_loop::run);
+    }
+
+    #[test]
+    fn max_clients_test() {
+        let mut server =
+            Server::new(vec!["127.0.0.1:0".parse().unwrap()], true).with_max_clients(Some(2));
+        let clients = Arc::clone(&server.clients);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || server.start());
+        let addrs = listening_on(&stop);
+
+        let mut first = connect(addrs[0], "first");
+        let mut second = connect(addrs[0], "second");
+        for _ in 0..100 {
+            if names(&clients).len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut third = TcpStream::connect(addrs[0]).unwrap();
+        send_session(&mut third, &SessionInfo::new("third")).unwrap();
+        let SessionReply::Refused(Some(reason)) = receive_session_reply(&mut third).unwrap() else {
+            panic!("Expected a refusal with a reason");
+        };
+        assert!(reason.contains("full"), "{reason}");
+        // Closed without a single package
+        assert_eq!(third.read(&mut [0u8; 1]).unwrap(), 0);
+        assert_eq!(names(&clients), ["first", "second"]);
+
+        // The others are still served
+        let package = Package::text("Hello".to_string());
+        send_package(&package, &mut first, &None, Encoding::default()).unwrap();
+        let received = std::iter::repeat_with(|| {
+            receive_package(&mut second, &None, Encoding::default()).unwrap()
+        })
+        .find(|received| matches!(received, Package::Item { .. }));
+        assert_eq!(received, Some(package));
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn silent_connection_test() {
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true)
+            .with_idle_timeout(Duration::from_millis(300));
+        let clients = Arc::clone(&server.clients);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || server.start());
+        let addrs = listening_on(&stop);
+
+        // Connects without ever sending a session, then one that garbles it
+        let mut silent = TcpStream::connect(addrs[0]).unwrap();
+        let mut garbled = TcpStream::connect(addrs[0]).unwrap();
+        write_frame(&mut garbled, &[0xff; 16]).unwrap();
+        // Served once both were dropped, and the server is still running
+        let _laptop = connect(addrs[0], "laptop");
+        for _ in 0..100 {
+            if names(&clients).len() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(names(&clients), ["laptop"]);
+        assert_eq!(silent.read(&mut [0u8; 1]).unwrap(), 0);
+        assert_eq!(garbled.read(&mut [0u8; 1]).unwrap(), 0);
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    /// Runs a server with `run` and checks that a client sending its session a byte at a time,
+    /// each within the read timeout, can't hold up the handshakes of others.
+    fn dripping_handshake(run: fn(&mut Server) -> std::io::Result<()>) {
+        use std::time::Instant;
+
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true)
+            .with_idle_timeout(Duration::from_millis(300));
+        let clients = Arc::clone(&server.clients);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || run(&mut server));
+        let addrs = listening_on(&stop);
+
+        let mut dripping = TcpStream::connect(addrs[0]).unwrap();
+        // Announces a session of 60000 bytes, then sends it slowly
+        dripping.write_all(&[0xe0, 0xd4, 0x03]).unwrap();
+        let dripper = thread::spawn(move || {
+            let started = Instant::now();
+            while dripping.write_all(&[0]).is_ok() && started.elapsed() < Duration::from_secs(5) {
+                thread::sleep(Duration::from_millis(100));
+            }
+            started.elapsed()
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        let joining = Instant::now();
+        let _laptop = connect(addrs[0], "laptop");
+        for _ in 0..100 {
+            if names(&clients).len() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(names(&clients), ["laptop"]);
+        assert!(joining.elapsed() < Duration::from_secs(2));
+        assert!(dripper.join().unwrap() < Duration::from_secs(2));
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn dripping_handshake_threaded_test() {
+        dripping_handshake(Server::start);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dripping_handshake_event_loop_test() {
+        dripping_handshake(event_loop::run);
+    }
+
+    /// Connects to `addr` and resets the connection right away, like a client crashing.
+    #[cfg(unix)]
+    fn reset(addr: SocketAddr) {
+        use std::os::unix::io::AsRawFd;
+
+        let peer = TcpStream::connect(addr).unwrap();
+        // Closing with a zero linger time sends a reset instead of a FIN
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        let set = unsafe {
+            libc::setsockopt(
+                peer.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_LINGER,
+                &linger as *const libc::linger as *const libc::c_void,
+                std::mem::size_of::<libc::linger>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(set, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reset_connection_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut server = Server::new(vec![listener.local_addr().unwrap()], true);
+        reset(listener.local_addr().unwrap());
+        thread::sleep(Duration::from_millis(50));
+
+        let (stream, _) = listener.accept().unwrap();
+        assert!(server.try_accept(stream.try_clone().unwrap()).is_err());
+        assert!(server.accept(stream).is_none());
+        assert!(server.clients.read().unwrap().is_empty());
+    }
+
+    /// Runs a server with `run` and checks that a connection reset before its handshake doesn't
+    /// stop it from accepting the next one.
+    #[cfg(unix)]
+    fn survives_reset_connection(run: fn(&mut Server) -> std::io::Result<()>) {
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true);
+        let clients = Arc::clone(&server.clients);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || run(&mut server));
+        let addrs = listening_on(&stop);
+
+        reset(addrs[0]);
+        thread::sleep(Duration::from_millis(50));
+        let _laptop = connect(addrs[0], "laptop");
+        for _ in 0..100 {
+            if names(&clients).len() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(names(&clients), ["laptop"]);
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn survives_reset_connection_threaded_test() {
+        survives_reset_connection(Server::start);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn survives_reset_connection_event_loop_test() {
+        survives_reset_connection(event_loop::run);
+    }
+
+    #[test]
+    fn unbindable_address_test() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::new(
+            vec![taken.local_addr().unwrap(), "127.0.0.1:0".parse().unwrap()],
+            true,
+        );
+        assert_eq!(server.bind().unwrap().len(), 1);
+
+        let server = Server::new(vec![taken.local_addr().unwrap()], true);
+        assert!(server.bind().is_err());
+    }
+
+    /// Runs a server with `run` and checks that raising its stop flag disconnects the client
+    /// and makes `run` return.
+    fn stop_server(run: fn(&mut Server) -> std::io::Result<()>) {
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || run(&mut server));
+        let addrs = listening_on(&stop);
+
+        let mut peer = connect(addrs[0], "laptop");
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+        // Whatever was broadcast before, the connection ends rather than hanging
+        peer.read_to_end(&mut Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn stop_threaded_test() {
+        stop_server(Server::start);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stop_event_loop_test() {
+        stop_server(event_loop::run);
+    }
+
+    /// Runs a server with `run` and checks that a client sending nothing for longer than the
+    /// idle timeout is disconnected, while one sending heartbeats stays.
+    fn idle_client(run: fn(&mut Server) -> std::io::Result<()>) {
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true)
+            .with_idle_timeout(Duration::from_millis(300));
+        let clients = Arc::clone(&server.clients);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || run(&mut server));
+        let addrs = listening_on(&stop);
+
+        let mut silent = connect(addrs[0], "silent");
+        let mut alive = connect(addrs[0], "alive");
+        for _ in 0..10 {
+            send_package(&Package::Heartbeat, &mut alive, &None, Encoding::default()).unwrap();
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(names(&clients), ["alive"]);
+        // Whatever was broadcast before, the server closed the connection
+        silent.read_to_end(&mut Vec::new()).unwrap();
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn idle_client_threaded_test() {
+        idle_client(Server::start);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn idle_client_event_loop_test() {
+        idle_client(event_loop::run);
+    }
+
+    /// Connects to `addr` as `name` with the handshake the client binary runs, encrypting when
+    /// `encrypted`.
+    fn join(addr: SocketAddr, name: &str, encrypted: bool) -> Connection {
+        let mut session = SessionInfo::new(name);
+        session.use_encryption = encrypted;
+        let mut connection = Connection::new(TcpStream::connect(addr).unwrap()).unwrap();
+        connection.handshake(&session, None).unwrap();
+        connection
+    }
+
+    /// The next clip `connection` receives, skipping presence updates and heartbeats.
+    fn next_clip(connection: &mut Connection) -> Package {
+        std::iter::repeat_with(|| connection.recv().unwrap())
+            .find(|package| matches!(package, Package::Item { .. } | Package::Clear { .. }))
+            .unwrap()
+    }
+
+    /// Runs a server with `run` and checks that a clip sent by one client reaches every other
+    /// one through the broadcaster, whether they encrypt or not. Packages are sent directly, so
+    /// no clipboard is needed.
+    fn clip_reaches_other_clients(run: fn(&mut Server) -> std::io::Result<()>) {
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true);
+        let clients = Arc::clone(&server.clients);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || run(&mut server));
+        let addrs = listening_on(&stop);
+
+        let mut laptop = join(addrs[0], "laptop", true);
+        let mut desktop = join(addrs[0], "desktop", true);
+        let mut phone = join(addrs[0], "phone", false);
+        for _ in 0..100 {
+            if names(&clients).len() == 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(names(&clients), ["laptop", "desktop", "phone"]);
+
+        let clip = Package::text("Hello".to_string());
+        laptop.send(&clip).unwrap();
+        assert_eq!(next_clip(&mut desktop), clip);
+        assert_eq!(next_clip(&mut phone), clip);
+
+        // A newer clip from another client replaces it everywhere but on its sender
+        let reply = Package::text("World".to_string());
+        phone.send(&reply).unwrap();
+        assert_eq!(next_clip(&mut laptop), reply);
+        assert_eq!(next_clip(&mut desktop), reply);
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    /// Runs a server with `run` and checks that the same content copied on two devices is only
+    /// broadcast once, even though the second copy is newer.
+    fn same_content_is_broadcast_once(run: fn(&mut Server) -> std::io::Result<()>) {
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true);
+        let clients = Arc::clone(&server.clients);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || run(&mut server));
+        let addrs = listening_on(&stop);
+
+        let mut laptop = join(addrs[0], "laptop", false);
+        let mut desktop = join(addrs[0], "desktop", false);
+        let mut phone = join(addrs[0], "phone", false);
+        for _ in 0..100 {
+            if names(&clients).len() == 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let clip = Package::text("Hello".to_string());
+        laptop.send(&clip).unwrap();
+        assert_eq!(next_clip(&mut desktop), clip);
+        assert_eq!(next_clip(&mut phone), clip);
+        let copy = Package::text("Hello".to_string());
+        assert!(copy > clip);
+        desktop.send(&copy).unwrap();
+
+        // Both only get the next clip, not the copy in between
+        let next = Package::text("World".to_string());
+        desktop.send(&next).unwrap();
+        assert_eq!(next_clip(&mut laptop), next);
+        assert_eq!(next_clip(&mut phone), next);
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn same_content_is_broadcast_once_threaded_test() {
+        same_content_is_broadcast_once(Server::start);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn same_content_is_broadcast_once_event_loop_test() {
+        same_content_is_broadcast_once(event_loop::run);
+    }
+
+    #[test]
+    fn clip_reaches_other_clients_threaded_test() {
+        clip_reaches_other_clients(Server::start);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn clip_reaches_other_clients_event_loop_test() {
+        clip_reaches_other_clients(event_loop::run);
+    }
+
+    /// Runs a server with `run` and checks that a client connecting after a copy is sent that
+    /// clip right away.
+    fn late_client_receives_current_clip(run: fn(&mut Server) -> std::io::Result<()>) {
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true);
+        let current = Arc::clone(&server.package);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || run(&mut server));
+        let addrs = listening_on(&stop);
+
+        let mut laptop = join(addrs[0], "laptop", false);
+        let clip = Package::text("Hello".to_string());
+        laptop.send(&clip).unwrap();
+        for _ in 0..100 {
+            if *current.read().unwrap() == clip {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut desktop = join(addrs[0], "desktop", true);
+        assert_eq!(next_clip(&mut desktop), clip);
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn late_client_receives_current_clip_threaded_test() {
+        late_client_receives_current_clip(Server::start);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn late_client_receives_current_clip_event_loop_test() {
+        late_client_receives_current_clip(event_loop::run);
+    }
+
+    /// Runs a server with `run` and checks that clearing the clipboard on one client reaches the
+    /// other one, while the history keeps the clip copied before.
+    fn clear_reaches_other_clients(run: fn(&mut Server) -> std::io::Result<()>) {
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true);
+        let clients = Arc::clone(&server.clients);
+        let history = Arc::clone(&server.history);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || run(&mut server));
+        let addrs = listening_on(&stop);
+
+        let mut laptop = join(addrs[0], "laptop", true);
+        let mut desktop = join(addrs[0], "desktop", false);
+        for _ in 0..100 {
+            if names(&clients).len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let clip = Package::text("Hello".to_string());
+        laptop.send(&clip).unwrap();
+        assert_eq!(next_clip(&mut desktop), clip);
+        let clear = Package::clear();
+        laptop.send(&clear).unwrap();
+        assert_eq!(next_clip(&mut desktop), clear);
+        assert_eq!(history.read().unwrap().get(0), Some(&clip));
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn clear_reaches_other_clients_threaded_test() {
+        clear_reaches_other_clients(Server::start);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn clear_reaches_other_clients_event_loop_test() {
+        clear_reaches_other_clients(event_loop::run);
+    }
+
+    /// Runs a server with `run` and checks that every item and clear a client sends is
+    /// acknowledged to it, older ones included, but to no one else.
+    fn sender_receives_ack(run: fn(&mut Server) -> std::io::Result<()>) {
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true);
+        let clients = Arc::clone(&server.clients);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || run(&mut server));
+        let addrs = listening_on(&stop);
+
+        let mut laptop = join(addrs[0], "laptop", true);
+        let mut desktop = join(addrs[0], "desktop", false);
+        for _ in 0..100 {
+            if names(&clients).len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let next_ack = |connection: &mut Connection| {
+            std::iter::repeat_with(|| connection.recv().unwrap())
+                .find(|package| matches!(package, Package::Ack { .. } | Package::Item { .. }))
+                .unwrap()
+        };
+
+        let old = Package::text("Replayed".to_string());
+        let clip = Package::text("Hello".to_string());
+        let clear = Package::clear();
+        for package in [&clip, &clear, &old] {
+            laptop.send(package).unwrap();
+            assert_eq!(Some(next_ack(&mut laptop)), package.ack());
+        }
+        assert_eq!(next_ack(&mut desktop), clip);
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn sender_receives_ack_threaded_test() {
+        sender_receives_ack(Server::start);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sender_receives_ack_event_loop_test() {
+        sender_receives_ack(event_loop::run);
+    }
+
+    /// Runs a server with `run` and checks that a client reconnecting after a clear isn't sent
+    /// the clear, which would wipe its clipboard.
+    fn reconnecting_client_not_cleared(run: fn(&mut Server) -> std::io::Result<()>) {
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true);
+        let current = Arc::clone(&server.package);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || run(&mut server));
+        let addrs = listening_on(&stop);
+
+        let mut laptop = join(addrs[0], "laptop", false);
+        let desktop = join(addrs[0], "desktop", true);
+        laptop.send(&Package::text("Hello".to_string())).unwrap();
+        laptop.send(&Package::clear()).unwrap();
+        for _ in 0..100 {
+            if matches!(*current.read().unwrap(), Package::Clear { .. }) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(desktop);
+
+        let mut desktop = join(addrs[0], "desktop", true);
+        let clip = Package::text("World".to_string());
+        laptop.send(&clip).unwrap();
+        assert_eq!(next_clip(&mut desktop), clip);
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn reconnecting_client_not_cleared_threaded_test() {
+        reconnecting_client_not_cleared(Server::start);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reconnecting_client_not_cleared_event_loop_test() {
+        reconnecting_client_not_cleared(event_loop::run);
+    }
+
+    /// Runs a server with `run` and checks that a client that lost the connection partway
+    /// through the current clip, a large file, gets only the rest of it after reconnecting.
+    fn interrupted_file_resumes(run: fn(&mut Server) -> std::io::Result<()>) {
+        use clipper::chunk::{resume_requests, CHUNK_LEN};
+
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true);
+        let file = Package::from(clipper::item::TransferableItem::file(
+            "big.bin",
+            vec![7; 3 * CHUNK_LEN],
+        ));
+        *server.package.write().unwrap() = file.clone();
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || run(&mut server));
+        let addrs = listening_on(&stop);
+        let dir = std::env::temp_dir().join(format!("clipper-resume-{}", addrs[0].port()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut chunks = Reassembler::default().with_part_dir(&dir);
+        let next_chunk = |connection: &mut Connection| {
+            std::iter::repeat_with(|| connection.recv().unwrap())
+                .find(|package| matches!(package, Package::FileChunk { .. }))
+                .unwrap()
+        };
+        let mut laptop = join(addrs[0], "laptop", true);
+        laptop.send(&Package::Heartbeat).unwrap();
+        for _ in 0..2 {
+            assert_eq!(chunks.push(next_chunk(&mut laptop)), None);
+        }
+        drop(laptop);
+
+        let mut laptop = join(addrs[0], "laptop", true);
+        for request in resume_requests(&dir).unwrap() {
+            laptop.send(&request).unwrap();
+        }
+        laptop.send(&Package::Heartbeat).unwrap();
+        let chunk = next_chunk(&mut laptop);
+        assert!(matches!(chunk, Package::FileChunk { seq: 2, .. }));
+        assert_eq!(chunks.push(chunk), None);
+        assert_eq!(chunks.push(next_chunk(&mut laptop)), Some(file));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn interrupted_file_resumes_threaded_test() {
+        interrupted_file_resumes(Server::start);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn interrupted_file_resumes_event_loop_test() {
+        interrupted_file_resumes(event_loop::run);
+    }
+
+    #[test]
+    fn history_request_test() {
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || server.start());
+        let addrs = listening_on(&stop);
+
+        let mut laptop = join(addrs[0], "laptop", false);
+        let clips = ["first", "second"].map(|text| Package::text(text.to_string()));
+        for clip in &clips {
+            laptop.send(clip).unwrap();
+        }
+        for (index, expected) in [(1, Some(&clips[0])), (0, Some(&clips[1])), (2, None)] {
+            laptop.send(&Package::HistoryRequest { index }).unwrap();
+            let entry = std::iter::repeat_with(|| laptop.recv().unwrap())
+                .find(|package| matches!(package, Package::HistoryEntry { .. }))
+                .unwrap();
+            assert_eq!(
+                entry,
+                Package::HistoryEntry {
+                    index,
+                    package: expected.cloned().map(Box::new),
+                }
+            );
+        }
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    /// Runs a server with `run` and checks that a client stalling halfway through a frame is
+    /// dropped once the idle timeout passes, instead of tying up its reader.
+    fn stalled_mid_frame(run: fn(&mut Server) -> std::io::Result<()>) {
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true)
+            .with_idle_timeout(Duration::from_millis(300));
+        let clients = Arc::clone(&server.clients);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || run(&mut server));
+        let addrs = listening_on(&stop);
+
+        let mut stalled = connect(addrs[0], "stalled");
+        let mut frame = Vec::new();
+        write_frame(&mut frame, &[0; 64]).unwrap();
+        stalled.write_all(&frame[..frame.len() / 2]).unwrap();
+        for _ in 0..100 {
+            if names(&clients).is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(names(&clients).is_empty());
+        stalled.read_to_end(&mut Vec::new()).unwrap();
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    /// Runs a server with `run` and checks that a client announcing a frame over the limit is
+    /// disconnected right away, before the frame is read.
+    fn oversized_frame(run: fn(&mut Server) -> std::io::Result<()>) {
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true);
+        let clients = Arc::clone(&server.clients);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || run(&mut server));
+        let addrs = listening_on(&stop);
+
+        let mut oversized = connect(addrs[0], "oversized");
+        for _ in 0..100 {
+            if names(&clients).len() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Length prefix of MAX_PACKAGE_LEN + 1, then the start of the frame
+        let mut prefix = Vec::new();
+        let mut len = MAX_PACKAGE_LEN + 1;
+        while len >= 0x80 {
+            prefix.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        prefix.push(len as u8);
+        oversized.write_all(&prefix).unwrap();
+        oversized.write_all(&[0; 64]).unwrap();
+        for _ in 0..100 {
+            if names(&clients).is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(names(&clients).is_empty());
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn oversized_frame_threaded_test() {
+        oversized_frame(Server::start);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn oversized_frame_event_loop_test() {
+        oversized_frame(event_loop::run);
+    }
+
+    #[test]
+    fn stalled_mid_frame_threaded_test() {
+        stalled_mid_frame(Server::start);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stalled_mid_frame_event_loop_test() {
+        stalled_mid_frame(event_loop::run);
+    }
+
+    #[test]
+    fn stalled_reader_test() {
+        let mut server = Server::new(vec!["127.0.0.1:0".parse().unwrap()], true)
+            .with_write_timeout(Duration::from_millis(200));
+        let clients = Arc::clone(&server.clients);
+        let stop = server.stop.clone();
+        let running = thread::spawn(move || server.start());
+        let addrs = listening_on(&stop);
+
+        // Never reads, so the socket buffers fill up and writes to it stop making progress
+        let _stalled = connect(addrs[0], "stalled");
+        let mut sender = connect(addrs[0], "sender");
+        for ind in 0..32 {
+            if names(&clients) == ["sender"] {
+                break;
+            }
+            let clip = Package::text(format!("{ind}").repeat(4 * 1024 * 1024));
+            send_package(&clip, &mut sender, &None, Encoding::default()).unwrap();
+        }
+        for _ in 0..100 {
+            if names(&clients) == ["sender"] {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(names(&clients), ["sender"]);
+
+        stop.raise();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn broadcast_removes_disconnected_clients_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::new(vec![listener.local_addr().unwrap()], true);
+        let mut peers = Vec::new();
+        for (name, alive) in [("first", false), ("second", true), ("third", false)] {
+            let (client, peer) = client(&listener, name, alive);
+            server.clients.write().unwrap().push(client);
+            peers.push(peer);
+        }
+
+        *server.package.write().unwrap() = Package::text("Hello".to_string());
+        server.broadcaster.boardcast("laptop");
+
+        let names: Vec<_> = server
+            .clients
+            .read()
+            .unwrap()
+            .iter()
+            .map(|client| client.read().unwrap().name.clone())
+            .collect();
+        assert_eq!(names, ["second"]);
+    }
+
+    #[test]
+    fn presence_removes_disconnected_clients_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::new(vec![listener.local_addr().unwrap()], true);
+        let mut peers = Vec::new();
+        for (name, alive) in [("first", true), ("second", false)] {
+            let (client, peer) = client(&listener, name, alive);
+            server.clients.write().unwrap().push(client);
+            peers.push(peer);
+        }
+
+        server.broadcaster.broadcast_presence();
+
+        assert_eq!(names(&server.clients), ["first"]);
+        let mut reader = BufReader::new(Stream::from(peers.remove(0)));
+        let mut presence = || receive_package(&mut reader, &None, Encoding::default()).unwrap();
+        assert_eq!(
+            presence(),
+            Package::Presence {
+                peers: vec!["first".to_string(), "second".to_string()]
+            }
+        );
+        // Told again once the second was found gone
+        assert_eq!(
+            presence(),
+            Package::Presence {
+                peers: vec!["first".to_string()]
+            }
+        );
+    }
+
+    #[test]
+    fn broadcast_does_not_lock_clients_while_sending_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::new(vec![listener.local_addr().unwrap()], true);
+        let (stalled, mut peer) = client(&listener, "stalled", true);
+        server.clients.write().unwrap().push(stalled);
+        // Incompressible and far larger than the socket buffers, so the send stalls
+        let mut seed = 1u32;
+        let text = (0..64 << 20)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                char::from(b'!' + (seed >> 16) as u8 % 94)
+            })
+            .collect();
+        *server.package.write().unwrap() = Package::text(text);
+
+        thread::scope(|scope| {
+            scope.spawn(|| server.broadcaster.boardcast("laptop"));
+            peer.read_exact(&mut [0]).unwrap();
+
+            let (locked, done) = mpsc::channel();
+            let clients = &server.clients;
+            scope.spawn(move || {
+                drop(clients.write().unwrap());
+                locked.send(()).unwrap();
+            });
+            let result = done.recv_timeout(Duration::from_secs(5));
+            drop(peer);
+            assert!(result.is_ok(), "clients stayed locked during the send");
+        });
+        assert!(server.clients.read().unwrap().is_empty());
+    }
 }
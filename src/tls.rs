@@ -0,0 +1,299 @@
+//! Optional TLS transport. The server presents a certificate, which clients verify against the
+//! public web roots or against a certificate they were given, so a self-signed one can be pinned.
+//!
+//! Packages are framed exactly as over plain TCP, only the bytes travel inside TLS records.
+
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+
+pub use rustls::pki_types::ServerName;
+
+/// Bytes read from the socket at once, enough for a full record
+const READ_CHUNK: usize = 18 * 1024;
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Every certificate in the PEM file at `path`, in order.
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "No certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+/// The first private key in the PEM file at `path`.
+fn load_private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| invalid_data(format!("No private key in {}", path.display())))
+}
+
+/// Server side of the TLS transport.
+#[derive(Clone)]
+pub struct Acceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl Acceptor {
+    /// Presents the certificate chain in the PEM file `cert`, leaf first, signed with the key in
+    /// the PEM file `key`.
+    pub fn from_pem_files(cert: &Path, key: &Path) -> io::Result<Self> {
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_no_client_auth()
+            .with_single_cert(load_certs(cert)?, load_private_key(key)?)
+            .map_err(invalid_data)?;
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Runs the handshake with a client that just connected.
+    pub fn accept(&self, tcp: TcpStream) -> io::Result<TlsStream> {
+        let mut session = ServerConnection::new(Arc::clone(&self.config)).map_err(invalid_data)?;
+        let mut tcp = tcp;
+        while session.is_handshaking() {
+            session.complete_io(&mut tcp)?;
+        }
+        Ok(TlsStream::new(session.into(), tcp))
+    }
+}
+
+/// Client side of the TLS transport, for one server.
+#[derive(Clone)]
+pub struct Connector {
+    config: Arc<ClientConfig>,
+    /// What the server's certificate must be issued for
+    name: ServerName<'static>,
+}
+
+impl Connector {
+    /// Verifies the server is `name`, a DNS name or an IP address, with a certificate issued by
+    /// one of the public roots, or by one in the PEM file `pinned` instead when given. A
+    /// self-signed certificate is trusted by pinning itself.
+    pub fn new(name: ServerName<'static>, pinned: Option<&Path>) -> io::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        match pinned {
+            Some(path) => {
+                for cert in load_certs(path)? {
+                    roots.add(cert).map_err(invalid_data)?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self {
+            config: Arc::new(config),
+            name,
+        })
+    }
+
+    /// Runs the handshake over a fresh connection to the server.
+    pub fn connect(&self, tcp: TcpStream) -> io::Result<TlsStream> {
+        let mut session = ClientConnection::new(Arc::clone(&self.config), self.name.clone())
+            .map_err(invalid_data)?;
+        let mut tcp = tcp;
+        while session.is_handshaking() {
+            session.complete_io(&mut tcp)?;
+        }
+        Ok(TlsStream::new(session.into(), tcp))
+    }
+}
+
+/// An established TLS connection.
+///
+/// Like a `TcpStream` it can be cloned, so one thread reads while another writes: the clones
+/// share the TLS state, which is only locked while records are decrypted or sent.
+pub struct TlsStream {
+    session: Arc<Mutex<rustls::Connection>>,
+    tcp: TcpStream,
+    /// Records read from the socket that rustls hasn't taken yet
+    records: Vec<u8>,
+}
+
+impl TlsStream {
+    fn new(session: rustls::Connection, tcp: TcpStream) -> Self {
+        Self {
+            session: Arc::new(Mutex::new(session)),
+            tcp,
+            records: Vec::new(),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            session: Arc::clone(&self.session),
+            tcp: self.tcp.try_clone()?,
+            records: Vec::new(),
+        })
+    }
+
+    /// The socket underneath, e.g. to shut it down.
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.tcp
+    }
+
+    /// Sends every record rustls has queued, including replies it owes the peer.
+    fn send_records(session: &mut rustls::Connection, mut tcp: &TcpStream) -> io::Result<()> {
+        while session.wants_write() {
+            session.write_tls(&mut tcp)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut session = self.session.lock().unwrap();
+                match session.reader().read(buf) {
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+                if !self.records.is_empty() {
+                    let mut records = &self.records[..];
+                    session.read_tls(&mut records)?;
+                    // Once the peer said it is done rustls takes nothing more
+                    let taken = match self.records.len() - records.len() {
+                        0 => self.records.len(),
+                        taken => taken,
+                    };
+                    self.records.drain(..taken);
+                    session.process_new_packets().map_err(invalid_data)?;
+                    TlsStream::send_records(&mut session, &self.tcp)?;
+                    continue;
+                }
+            }
+            // Waits without holding the lock, so the other half can keep writing
+            let mut chunk = [0u8; READ_CHUNK];
+            let read = (&self.tcp).read(&mut chunk)?;
+            if read == 0 {
+                // The next read tells whether the peer closed the connection cleanly
+                self.session.lock().unwrap().read_tls(&mut io::empty())?;
+                continue;
+            }
+            self.records.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let written = session.writer().write(buf)?;
+        TlsStream::send_records(&mut session, &self.tcp)?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        session.writer().flush()?;
+        TlsStream::send_records(&mut session, &self.tcp)?;
+        (&self.tcp).flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{receive_package, send_package, Connection, Encoding, Package};
+    use std::net::{IpAddr, TcpListener};
+    use std::path::PathBuf;
+
+    /// Writes a self-signed certificate for 127.0.0.1 and its key to a fresh directory, returned
+    /// along with both files.
+    fn self_signed(name: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("clipper-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        std::fs::write(&cert, certified.cert.pem()).unwrap();
+        std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+        (dir, cert, key)
+    }
+
+    fn localhost() -> ServerName<'static> {
+        ServerName::from("127.0.0.1".parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn tls_round_trip_test() {
+        let (dir, cert, key) = self_signed("tls-round-trip");
+        let acceptor = Acceptor::from_pem_files(&cert, &key).unwrap();
+        let connector = Connector::new(localhost(), Some(&cert)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Echoes one package back, reading and writing from separate halves like the server
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let stream = acceptor.accept(stream).unwrap();
+            let (mut reader, mut writer) = Connection::new(stream).unwrap().split();
+            let package = receive_package(&mut reader, &None, Encoding::default()).unwrap();
+            send_package(&package, &mut writer, &None, Encoding::default()).unwrap();
+        });
+
+        let stream = connector
+            .connect(TcpStream::connect(addr).unwrap())
+            .unwrap();
+        let mut client = Connection::new(stream).unwrap();
+        // Spans several records
+        let package = Package::text("clip".repeat(25_000));
+        send_package(&package, &mut client, &None, Encoding::default()).unwrap();
+        assert_eq!(
+            receive_package(&mut client, &None, Encoding::default()).unwrap(),
+            package
+        );
+
+        server.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn untrusted_certificate_test() {
+        let (dir, cert, key) = self_signed("tls-untrusted");
+        let (other_dir, other_cert, _) = self_signed("tls-untrusted-other");
+        let acceptor = Acceptor::from_pem_files(&cert, &key).unwrap();
+        let connector = Connector::new(localhost(), Some(&other_cert)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            acceptor.accept(stream).is_err()
+        });
+
+        assert!(connector
+            .connect(TcpStream::connect(addr).unwrap())
+            .is_err());
+        assert!(server.join().unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&other_dir).unwrap();
+    }
+}
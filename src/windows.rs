@@ -1,20 +1,188 @@
-use arboard::{Clipboard};
+use arboard::{Clipboard, ImageData};
 
-pub fn read_text() -> Result<String, Error>{
-    let mut clipboard = Clipboard::new().unwrap();
-    match clipboard.get_text() {
-        Err(_) => Err(Error::Unsupported),
-        Ok(text) => Ok(text)
+/// Reads the clipboard through `read`, retrying while another process holds it open. Opening
+/// always succeeds, the clipboard is only taken by the read itself.
+fn read_with<T>(read: impl FnMut() -> Result<T, arboard::Error>) -> Result<T, Error> {
+    crate::clipboard::retry_occupied(read).map_err(|err| match err {
+        arboard::Error::ClipboardOccupied => Error::Busy,
+        arboard::Error::ContentNotAvailable | arboard::Error::ConversionFailure => {
+            Error::Unsupported
+        }
+        err => Error::Unavailable(err.to_string()),
+    })
+}
+
+pub fn read_text() -> Result<String, Error> {
+    read_with(|| Clipboard::new()?.get_text())
+}
+
+/// Bitmap on the clipboard, converted to RGBA by arboard.
+pub fn read_image() -> Result<ImageData<'static>, Error> {
+    read_with(|| Clipboard::new()?.get_image())
+}
+
+/// Writes an RGBA bitmap, retrying while another process holds the clipboard open.
+pub fn write_image(image: ImageData) -> Result<(), Error> {
+    let len = image
+        .width
+        .checked_mul(image.height)
+        .and_then(|pixels| pixels.checked_mul(4));
+    if len != Some(image.bytes.len()) {
+        return Err(Error::Write(format!(
+            "{} bytes don't make a {}x{} RGBA image",
+            image.bytes.len(),
+            image.width,
+            image.height
+        )));
     }
+    crate::clipboard::retry_occupied(|| Clipboard::new()?.set_image(image.clone())).map_err(
+        |err| match err {
+            arboard::Error::ClipboardOccupied => Error::Busy,
+            err => Error::Write(err.to_string()),
+        },
+    )
+}
+
+/// Empties the clipboard, retrying while another process holds it open.
+pub fn clear() -> Result<(), Error> {
+    crate::clipboard::retry_occupied(|| Clipboard::new()?.clear()).map_err(|err| match err {
+        arboard::Error::ClipboardOccupied => Error::Busy,
+        err => Error::Write(err.to_string()),
+    })
 }
 
-pub fn write_text(text: String) {
-    let mut clipboard = Clipboard::new().unwrap();
-    clipboard.set_text(text).unwrap();
+/// The first file or folder copied in Explorer, if the clipboard holds a file drop. `Err` with
+/// the reason when `filter` rejects it, which is told from its metadata before reading anything.
+pub fn read_file(
+    filter: &crate::item::ContentFilter,
+) -> Option<Result<crate::item::TransferableItem, String>> {
+    use crate::item::TransferableItem;
+    use clipboard_win::{formats, get_clipboard};
+
+    let paths: Vec<String> = get_clipboard(formats::FileList).ok()?;
+    let path = paths.into_iter().next()?;
+    if let Some(reason) = filter.rejects_path(std::path::Path::new(&path)) {
+        return Some(Err(reason));
+    }
+    let item = if std::path::Path::new(&path).is_dir() {
+        TransferableItem::read_folder(&path)
+    } else {
+        TransferableItem::read_file(&path)
+    };
+    match item {
+        Ok(item) => Some(Ok(item)),
+        Err(err) => {
+            log::debug!("Unable to read {path}: {err}");
+            None
+        }
+    }
+}
+
+/// Counter Windows increments on every clipboard change, so polling it tells whether the
+/// content has to be read again. Like `changeCount` on macOS.
+pub fn get_sequence_number() -> isize {
+    use windows_sys::Win32::System::DataExchange::GetClipboardSequenceNumber;
+
+    // Safety: takes no arguments and only reads a counter
+    unsafe { GetClipboardSequenceNumber() as isize }
+}
 
+/// Writes `text`, retrying while another process holds the clipboard open.
+pub fn write_text(text: String) -> Result<(), Error> {
+    crate::clipboard::retry_occupied(|| Clipboard::new()?.set_text(text.as_str())).map_err(
+        |err| match err {
+            arboard::Error::ClipboardOccupied => Error::Busy,
+            err => Error::Write(err.to_string()),
+        },
+    )
+}
+
+/// Name of the executable owning the foreground window, i.e. the application the user most
+/// likely copied from.
+pub fn foreground_app() -> Option<String> {
+    use windows_sys::Win32::Foundation::{CloseHandle, MAX_PATH};
+    use windows_sys::Win32::System::Threading::{
+        OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32,
+        PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+    use windows_sys::Win32::UI::WindowsAndMessaging::{
+        GetForegroundWindow, GetWindowThreadProcessId,
+    };
+
+    // Safety: every handle is checked before use and the process handle is closed before
+    // returning; the buffer length passed matches the buffer
+    let path = unsafe {
+        let window = GetForegroundWindow();
+        if window.is_null() {
+            return None;
+        }
+        let mut pid = 0;
+        GetWindowThreadProcessId(window, &mut pid);
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if process.is_null() {
+            return None;
+        }
+        let mut buffer = [0u16; MAX_PATH as usize];
+        let mut len = buffer.len() as u32;
+        let ok =
+            QueryFullProcessImageNameW(process, PROCESS_NAME_WIN32, buffer.as_mut_ptr(), &mut len);
+        CloseHandle(process);
+        if ok == 0 {
+            return None;
+        }
+        String::from_utf16_lossy(&buffer[..len as usize])
+    };
+    std::path::Path::new(&path)
+        .file_stem()
+        .map(|name| name.to_string_lossy().into_owned())
 }
 
 #[derive(Hash, Debug)]
 pub enum Error {
     Unsupported,
-}
\ No newline at end of file
+    /// Another process kept the clipboard open through every retry
+    Busy,
+    /// The clipboard couldn't be opened for another reason
+    Unavailable(String),
+    Write(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unsupported => write!(f, "Unsupported clipboard content"),
+            Self::Busy => write!(f, "Clipboard is held open by another process"),
+            Self::Unavailable(err) => write!(f, "Unable to open the clipboard: {err}"),
+            Self::Write(err) => write!(f, "Unable to write to the clipboard: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn busy_clipboard_test() {
+        let mut attempts = 0;
+        let result = read_with(|| {
+            attempts += 1;
+            Err::<(), _>(arboard::Error::ClipboardOccupied)
+        });
+        assert!(matches!(result, Err(Error::Busy)));
+        assert!(attempts > 1);
+        let result = read_with(|| Err::<(), _>(arboard::Error::ContentNotAvailable));
+        assert!(matches!(result, Err(Error::Unsupported)));
+        let result = read_with(|| Err::<(), _>(arboard::Error::ClipboardNotSupported));
+        assert!(matches!(result, Err(Error::Unavailable(_))));
+    }
+
+    #[test]
+    fn sequence_number_advances_test() {
+        let before = get_sequence_number();
+        write_text("Hello".to_string()).unwrap();
+        assert_ne!(get_sequence_number(), before);
+    }
+}